@@ -0,0 +1,311 @@
+use crate::ast::{Expr, FunctionArgs};
+use crate::parser::{Affinity, Data};
+
+use anyhow::bail;
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+
+// A list of values that can be compared, for sets and maps keyed by values.
+// Values are compared in SQLite's sort order, so 1 and 1.0 are equal.
+#[derive(Debug, Clone)]
+pub struct Key(pub Vec<Data>);
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Key {}
+
+impl PartialOrd for Key {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Key {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0
+            .iter()
+            .zip(&other.0)
+            .map(|(a, b)| a.compare(b))
+            .find(|c| *c != Ordering::Equal)
+            .unwrap_or_else(|| self.0.len().cmp(&other.0.len()))
+    }
+}
+
+// The names of the aggregate functions
+pub const AGGREGATES: [&str; 7] = ["avg", "count", "group_concat", "max", "min", "sum", "total"];
+
+// Returns true if a function call with the given name and number of
+// arguments is an aggregate. min and max with several arguments are scalar.
+pub fn is_aggregate(name: &str, args: &FunctionArgs) -> bool {
+    let argc = match args {
+        FunctionArgs::Star => return name.eq_ignore_ascii_case("count"),
+        FunctionArgs::List(args) => args.len(),
+    };
+    match name.to_ascii_lowercase().as_str() {
+        "count" => argc <= 1,
+        "sum" | "total" | "avg" | "min" | "max" => argc == 1,
+        "group_concat" => argc == 1 || argc == 2,
+        _ => false,
+    }
+}
+
+// The running state of one aggregate function
+#[derive(Debug, Clone)]
+enum State {
+    Count(i64),
+    // Integers are summed exactly until a real shows up
+    Sum {
+        integer: i64,
+        real: f64,
+        is_real: bool,
+        count: i64,
+    },
+    Extreme {
+        value: Option<Data>,
+        ordering: Ordering,
+    },
+    GroupConcat(Option<String>),
+}
+
+// Accumulates the values of one aggregate call over the rows of a group
+#[derive(Debug, Clone)]
+pub struct Accumulator {
+    name: String,
+    state: State,
+    // The values seen so far, for aggregates with DISTINCT
+    seen: Option<BTreeSet<Key>>,
+}
+
+impl Accumulator {
+    pub fn new(name: &str, distinct: bool) -> Self {
+        let name = name.to_ascii_lowercase();
+        let state = match name.as_str() {
+            "count" => State::Count(0),
+            "min" | "max" => State::Extreme {
+                value: None,
+                ordering: if name == "min" {
+                    Ordering::Less
+                } else {
+                    Ordering::Greater
+                },
+            },
+            "group_concat" => State::GroupConcat(None),
+            _ => State::Sum {
+                integer: 0,
+                real: 0.0,
+                is_real: false,
+                count: 0,
+            },
+        };
+        Self {
+            name,
+            state,
+            seen: distinct.then(BTreeSet::new),
+        }
+    }
+
+    // Adds a row's arguments. count(*) gets no arguments. Returns true if the
+    // value became the new minimum or maximum.
+    pub fn step(&mut self, args: &[Data]) -> Result<bool, anyhow::Error> {
+        let Some(value) = args.first() else {
+            if let State::Count(n) = &mut self.state {
+                *n += 1;
+            }
+            return Ok(false);
+        };
+        // Aggregates skip NULLs, and DISTINCT skips values seen before
+        if *value == Data::Null {
+            return Ok(false);
+        }
+        if let Some(seen) = &mut self.seen {
+            if !seen.insert(Key(vec![value.clone()])) {
+                return Ok(false);
+            }
+        }
+        match &mut self.state {
+            State::Count(n) => *n += 1,
+            State::Sum {
+                integer,
+                real,
+                is_real,
+                count,
+            } => {
+                *count += 1;
+                match value.clone().apply_affinity(Affinity::Numeric) {
+                    Data::Integer(i) if !*is_real => match integer.checked_add(i) {
+                        Some(sum) => *integer = sum,
+                        None if self.name == "sum" => bail!("integer overflow"),
+                        None => {
+                            *is_real = true;
+                            *real = *integer as f64 + i as f64;
+                        }
+                    },
+                    Data::Integer(i) => *real += i as f64,
+                    Data::Float(x) => {
+                        if !*is_real {
+                            *is_real = true;
+                            *real = *integer as f64;
+                        }
+                        *real += x;
+                    }
+                    // Text that isn't a number counts as 0.0
+                    _ => {
+                        if !*is_real {
+                            *is_real = true;
+                            *real = *integer as f64;
+                        }
+                    }
+                }
+            }
+            State::Extreme {
+                value: best,
+                ordering,
+            } => {
+                let better = best.as_ref().is_none_or(|b| value.compare(b) == *ordering);
+                if better {
+                    *best = Some(value.clone());
+                }
+                return Ok(better);
+            }
+            State::GroupConcat(res) => {
+                let text = text_of(value);
+                match res {
+                    None => *res = Some(text),
+                    Some(res) => {
+                        let separator = match args.get(1) {
+                            Some(Data::Null) => String::new(),
+                            Some(separator) => text_of(separator),
+                            None => ",".to_string(),
+                        };
+                        res.push_str(&separator);
+                        res.push_str(&text);
+                    }
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    // The value of the aggregate over the rows added so far. Over no rows,
+    // count and total are zero and the others are NULL.
+    pub fn finish(&self) -> Data {
+        match &self.state {
+            State::Count(n) => Data::Integer(*n),
+            State::Sum {
+                integer,
+                real,
+                is_real,
+                count,
+            } => match self.name.as_str() {
+                "total" if *is_real => Data::Float(*real),
+                "total" => Data::Float(*integer as f64),
+                _ if *count == 0 => Data::Null,
+                "avg" if *is_real => Data::Float(*real / *count as f64),
+                "avg" => Data::Float(*integer as f64 / *count as f64),
+                _ if *is_real => Data::Float(*real),
+                _ => Data::Integer(*integer),
+            },
+            State::Extreme { value, .. } => value.clone().unwrap_or(Data::Null),
+            State::GroupConcat(res) => res.clone().map_or(Data::Null, Data::Text),
+        }
+    }
+}
+
+// Converts a value to text for group_concat. Blobs are read as UTF-8.
+fn text_of(value: &Data) -> String {
+    match value {
+        Data::Blob(b) => String::from_utf8_lossy(b).into_owned(),
+        value => value.to_string(),
+    }
+}
+
+// Collects the distinct aggregate calls in an expression, without looking
+// inside them
+pub fn collect_aggregates<'a>(expr: &'a Expr, res: &mut Vec<&'a Expr>) {
+    match expr {
+        Expr::Function { name, args, .. } if is_aggregate(name, args) => {
+            if !res.contains(&expr) {
+                res.push(expr);
+            }
+        }
+        expr => {
+            for child in expr.children() {
+                collect_aggregates(child, res);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(name: &str, distinct: bool, values: &[Data]) -> Data {
+        let mut acc = Accumulator::new(name, distinct);
+        for value in values {
+            acc.step(std::slice::from_ref(value)).unwrap();
+        }
+        acc.finish()
+    }
+
+    #[test]
+    fn test_empty_input() {
+        assert_eq!(run("count", false, &[]), Data::Integer(0));
+        assert_eq!(run("total", false, &[]), Data::Float(0.0));
+        for name in ["sum", "avg", "min", "max", "group_concat"] {
+            assert_eq!(run(name, false, &[Data::Null]), Data::Null, "{name}");
+        }
+    }
+
+    #[test]
+    fn test_aggregates() {
+        let values = [
+            Data::Integer(3),
+            Data::Null,
+            Data::Integer(1),
+            Data::Integer(3),
+            Data::Text("2".to_string()),
+        ];
+        assert_eq!(run("count", false, &values), Data::Integer(4));
+        assert_eq!(run("count", true, &values), Data::Integer(3));
+        assert_eq!(run("sum", false, &values), Data::Integer(9));
+        assert_eq!(run("sum", true, &values), Data::Integer(6));
+        assert_eq!(run("total", false, &values), Data::Float(9.0));
+        assert_eq!(run("avg", false, &values), Data::Float(2.25));
+        assert_eq!(run("min", false, &values), Data::Integer(1));
+        assert_eq!(run("max", false, &values), Data::Text("2".to_string()));
+        assert_eq!(
+            run("group_concat", false, &values),
+            Data::Text("3,1,3,2".to_string())
+        );
+        assert_eq!(
+            run("sum", false, &[Data::Integer(1), Data::Float(0.5)]),
+            Data::Float(1.5)
+        );
+    }
+
+    #[test]
+    fn test_sum_overflow() {
+        let mut sum = Accumulator::new("sum", false);
+        sum.step(&[Data::Integer(i64::MAX)]).unwrap();
+        assert!(sum.step(&[Data::Integer(1)]).is_err());
+        let mut total = Accumulator::new("total", false);
+        total.step(&[Data::Integer(i64::MAX)]).unwrap();
+        total.step(&[Data::Integer(1)]).unwrap();
+        assert_eq!(total.finish(), Data::Float(9223372036854775808.0));
+    }
+
+    #[test]
+    fn test_group_concat_separator() {
+        let mut acc = Accumulator::new("group_concat", false);
+        for value in ["a", "b", "c"] {
+            let args = [Data::Text(value.to_string()), Data::Text("; ".to_string())];
+            acc.step(&args).unwrap();
+        }
+        assert_eq!(acc.finish(), Data::Text("a; b; c".to_string()));
+    }
+}
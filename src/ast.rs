@@ -0,0 +1,577 @@
+use crate::parser::{Affinity, Data};
+
+use std::rc::Rc;
+
+// Represents a parsed SQL statement
+#[derive(Debug, Clone, PartialEq)]
+pub enum Statement {
+    Select(Select),
+}
+
+// Represents a SELECT statement
+#[derive(Debug, Clone, PartialEq)]
+pub struct Select {
+    // The tables of a WITH clause, which the SELECT and the queries nested in
+    // it can read. They are shared so that they keep their address while the
+    // statement runs.
+    pub with: Option<Rc<Vec<Cte>>>,
+    // Set by SELECT DISTINCT, which leaves out repeated result rows
+    pub distinct: bool,
+    pub columns: Vec<ResultColumn>,
+    pub from: Option<TableRef>,
+    // The tables joined to the first one, in order
+    pub joins: Vec<Join>,
+    pub where_: Option<Expr>,
+    pub group_by: Vec<Expr>,
+    pub having: Option<Expr>,
+    // The windows of the WINDOW clause, by name
+    pub windows: Vec<(String, Window)>,
+    // The SELECTs combined with this one, in order. The ORDER BY, LIMIT and
+    // OFFSET of a compound SELECT apply to the combined rows, and the
+    // SELECTs combined with it have none of their own.
+    pub compound: Vec<(CompoundOp, Select)>,
+    pub order_by: Vec<OrderingTerm>,
+    pub limit: Option<Expr>,
+    pub offset: Option<Expr>,
+}
+
+// Represents a table defined by a WITH clause, as in
+// name(columns) AS (select). A SELECT combined with the first one by UNION or
+// UNION ALL may read the table itself, which makes the table recursive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cte {
+    pub name: String,
+    // The names of the columns, if they aren't those of the SELECT's result
+    pub columns: Vec<String>,
+    pub select: Select,
+}
+
+// Represents the operator that combines the rows of a SELECT with those of
+// the SELECTs before it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompoundOp {
+    Union,
+    UnionAll,
+    Intersect,
+    Except,
+}
+
+impl CompoundOp {
+    pub fn name(self) -> &'static str {
+        match self {
+            CompoundOp::Union => "UNION",
+            CompoundOp::UnionAll => "UNION ALL",
+            CompoundOp::Intersect => "INTERSECT",
+            CompoundOp::Except => "EXCEPT",
+        }
+    }
+}
+
+// Represents where NULLs go in an ORDER BY term
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NullsOrder {
+    First,
+    Last,
+}
+
+// Represents one key of an ORDER BY clause
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderingTerm {
+    pub expr: Expr,
+    pub descending: bool,
+    // Without NULLS FIRST or LAST, NULLs sort as the smallest values
+    pub nulls: Option<NullsOrder>,
+}
+
+// Represents one entry of the result column list of a SELECT
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResultColumn {
+    // * or table.*, which expand to every column of the table
+    Star(Option<String>),
+    Expr { expr: Expr, alias: Option<String> },
+}
+
+// Represents the window of a window function call, or one defined by a
+// WINDOW clause
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Window {
+    // The named window this one is based on, as in OVER name or
+    // OVER (name ORDER BY ...)
+    pub base: Option<String>,
+    pub partition_by: Vec<Expr>,
+    pub order_by: Vec<OrderingTerm>,
+    pub frame: Option<Frame>,
+}
+
+// Represents the frame of a window: the rows around the current row that
+// aggregates and functions like first_value see
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub units: FrameUnits,
+    pub start: FrameBound,
+    pub end: FrameBound,
+}
+
+// Represents what the offsets of a frame count: rows, groups of rows with
+// equal ORDER BY values, or the difference from the current row's ORDER BY
+// value
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameUnits {
+    Rows,
+    Range,
+    Groups,
+}
+
+// Represents one end of a frame
+#[derive(Debug, Clone, PartialEq)]
+pub enum FrameBound {
+    UnboundedPreceding,
+    Preceding(Expr),
+    CurrentRow,
+    Following(Expr),
+    UnboundedFollowing,
+}
+
+// Represents a table in a FROM clause
+#[derive(Debug, Clone, PartialEq)]
+pub enum TableRef {
+    Table {
+        name: String,
+        alias: Option<String>,
+    },
+    // A parenthesized SELECT, whose rows are read like those of a table
+    Subquery {
+        select: Box<Select>,
+        alias: Option<String>,
+    },
+}
+
+impl TableRef {
+    // The name columns of the table are qualified with. A subquery without
+    // an alias has none.
+    pub fn scope_name(&self) -> Option<&str> {
+        match self {
+            TableRef::Table { name, alias } => Some(alias.as_deref().unwrap_or(name)),
+            TableRef::Subquery { alias, .. } => alias.as_deref(),
+        }
+    }
+}
+
+// Represents the kind of a join. Comma and CROSS joins are inner joins.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JoinKind {
+    Inner,
+    Left,
+}
+
+// Represents how the rows of a joined table are matched
+#[derive(Debug, Clone, PartialEq)]
+pub enum JoinConstraint {
+    On(Expr),
+    Using(Vec<String>),
+    // USING the columns that both sides have
+    Natural,
+}
+
+// Represents a table joined to the tables before it in a FROM clause
+#[derive(Debug, Clone, PartialEq)]
+pub struct Join {
+    pub kind: JoinKind,
+    pub table: TableRef,
+    pub constraint: Option<JoinConstraint>,
+}
+
+// Represents a reference to a column, optionally qualified by its table
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnRef {
+    pub table: Option<String>,
+    pub name: String,
+}
+
+// Represents the arguments of a function call. Only some functions, like
+// count, accept * in place of their arguments.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FunctionArgs {
+    Star,
+    List(Vec<Expr>),
+}
+
+// Represents a binary operator
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    // IS and IS NOT, which treat NULL as a value
+    Is,
+    IsNot,
+    And,
+    Or,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    // ||, which concatenates values as text
+    Concat,
+}
+
+// Represents a unary operator
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOp {
+    Not,
+    Negate,
+    // Unary +, which leaves its operand unchanged but takes away its affinity
+    Plus,
+}
+
+// Represents a pattern matching operator
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LikeOp {
+    Like,
+    Glob,
+    Regexp,
+}
+
+// Represents an expression
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Literal(Data),
+    Column(ColumnRef),
+    // A column of the `table`th table of a FROM clause, which the parser
+    // never produces. The equalities that USING and NATURAL imply refer to
+    // their tables this way, since a table may have no name, or share its
+    // name with another.
+    TableColumn {
+        table: usize,
+        name: String,
+    },
+    Function {
+        name: String,
+        // Set for aggregate calls like count(DISTINCT x)
+        distinct: bool,
+        args: FunctionArgs,
+    },
+    // name(args) OVER window, which is computed over the rows of a window
+    // after the rows are filtered and grouped
+    Window {
+        name: String,
+        args: FunctionArgs,
+        window: Box<Window>,
+    },
+    Binary {
+        op: BinaryOp,
+        left: Box<Expr>,
+        right: Box<Expr>,
+    },
+    Unary {
+        op: UnaryOp,
+        expr: Box<Expr>,
+    },
+    // expr [NOT] LIKE pattern [ESCAPE escape], and likewise GLOB and REGEXP
+    Like {
+        op: LikeOp,
+        negated: bool,
+        expr: Box<Expr>,
+        pattern: Box<Expr>,
+        escape: Option<Box<Expr>>,
+    },
+    // expr [NOT] IN (list)
+    InList {
+        negated: bool,
+        expr: Box<Expr>,
+        list: Vec<Expr>,
+    },
+    // expr [NOT] BETWEEN low AND high
+    Between {
+        negated: bool,
+        expr: Box<Expr>,
+        low: Box<Expr>,
+        high: Box<Expr>,
+    },
+    // expr [NOT] IN (SELECT ...)
+    InSelect {
+        negated: bool,
+        expr: Box<Expr>,
+        select: Box<Select>,
+    },
+    // EXISTS (SELECT ...), which is true if the subquery has a row
+    Exists(Box<Select>),
+    // (SELECT ...), the first value of the subquery's first row
+    Subquery(Box<Select>),
+    // CASE [operand] WHEN ... THEN ... [ELSE ...] END. With an operand, the
+    // WHEN values are compared with it, and otherwise they are conditions.
+    Case {
+        operand: Option<Box<Expr>>,
+        whens: Vec<(Expr, Expr)>,
+        otherwise: Option<Box<Expr>>,
+    },
+    // CAST(expr AS type), which converts a value to the type's affinity
+    Cast {
+        expr: Box<Expr>,
+        type_name: String,
+    },
+}
+
+impl Expr {
+    pub fn binary(op: BinaryOp, left: Expr, right: Expr) -> Expr {
+        Expr::Binary {
+            op,
+            left: Box::new(left),
+            right: Box::new(right),
+        }
+    }
+
+    // The expressions directly inside this one. The expressions of a
+    // subquery belong to the subquery, so they aren't included.
+    pub fn children(&self) -> Vec<&Expr> {
+        match self {
+            Expr::Literal(_)
+            | Expr::Column(_)
+            | Expr::TableColumn { .. }
+            | Expr::Exists(_)
+            | Expr::Subquery(_) => Vec::new(),
+            Expr::Function { args, .. } => match args {
+                FunctionArgs::Star => Vec::new(),
+                FunctionArgs::List(args) => args.iter().collect(),
+            },
+            Expr::Window { args, window, .. } => {
+                let args = match args {
+                    FunctionArgs::Star => &[][..],
+                    FunctionArgs::List(args) => args,
+                };
+                let terms = window.order_by.iter().map(|term| &term.expr);
+                args.iter()
+                    .chain(&window.partition_by)
+                    .chain(terms)
+                    .collect()
+            }
+            Expr::Binary { left, right, .. } => vec![left, right],
+            Expr::Unary { expr, .. } => vec![expr],
+            Expr::Like {
+                expr,
+                pattern,
+                escape,
+                ..
+            } => [Some(expr), Some(pattern), escape.as_ref()]
+                .into_iter()
+                .flatten()
+                .map(AsRef::as_ref)
+                .collect(),
+            Expr::InList { expr, list, .. } => std::iter::once(expr.as_ref()).chain(list).collect(),
+            Expr::Between {
+                expr, low, high, ..
+            } => vec![expr, low, high],
+            Expr::InSelect { expr, .. } | Expr::Cast { expr, .. } => vec![expr],
+            Expr::Case {
+                operand,
+                whens,
+                otherwise,
+            } => operand
+                .iter()
+                .map(AsRef::as_ref)
+                .chain(whens.iter().flat_map(|(when, then)| [when, then]))
+                .chain(otherwise.iter().map(AsRef::as_ref))
+                .collect(),
+        }
+    }
+
+    // The expressions directly inside this one, in the same order as
+    // children, so that they can be rewritten
+    pub fn children_mut(&mut self) -> Vec<&mut Expr> {
+        match self {
+            Expr::Literal(_)
+            | Expr::Column(_)
+            | Expr::TableColumn { .. }
+            | Expr::Exists(_)
+            | Expr::Subquery(_) => Vec::new(),
+            Expr::Function { args, .. } => match args {
+                FunctionArgs::Star => Vec::new(),
+                FunctionArgs::List(args) => args.iter_mut().collect(),
+            },
+            Expr::Window { args, window, .. } => {
+                let args = match args {
+                    FunctionArgs::Star => &mut [][..],
+                    FunctionArgs::List(args) => args,
+                };
+                let window = window.as_mut();
+                let terms = window.order_by.iter_mut().map(|term| &mut term.expr);
+                args.iter_mut()
+                    .chain(&mut window.partition_by)
+                    .chain(terms)
+                    .collect()
+            }
+            Expr::Binary { left, right, .. } => vec![left, right],
+            Expr::Unary { expr, .. } => vec![expr],
+            Expr::Like {
+                expr,
+                pattern,
+                escape,
+                ..
+            } => [Some(expr), Some(pattern), escape.as_mut()]
+                .into_iter()
+                .flatten()
+                .map(AsMut::as_mut)
+                .collect(),
+            Expr::InList { expr, list, .. } => std::iter::once(expr.as_mut()).chain(list).collect(),
+            Expr::Between {
+                expr, low, high, ..
+            } => vec![expr, low, high],
+            Expr::InSelect { expr, .. } | Expr::Cast { expr, .. } => vec![expr],
+            Expr::Case {
+                operand,
+                whens,
+                otherwise,
+            } => operand
+                .iter_mut()
+                .map(AsMut::as_mut)
+                .chain(whens.iter_mut().flat_map(|(when, then)| [when, then]))
+                .chain(otherwise.iter_mut().map(AsMut::as_mut))
+                .collect(),
+        }
+    }
+}
+
+// Represents a CREATE TABLE statement, as stored in the schema table
+#[derive(Debug, Clone, PartialEq)]
+pub struct CreateTable {
+    pub name: String,
+    pub columns: Vec<ColumnDef>,
+    pub constraints: Vec<TableConstraint>,
+    // A WITHOUT ROWID table is stored as an index on its primary key
+    pub without_rowid: bool,
+    // A STRICT table only allows values of its columns' declared types
+    pub strict: bool,
+}
+
+// Represents a CREATE INDEX statement, as stored in the schema table
+#[derive(Debug, Clone, PartialEq)]
+pub struct CreateIndex {
+    pub name: String,
+    pub table: String,
+    pub unique: bool,
+    pub columns: Vec<IndexedColumn>,
+    // A partial index only has keys for the rows where this is true
+    pub where_: Option<Expr>,
+}
+
+// A column of an index, which may also be an expression of the table's columns
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexedColumn {
+    pub expr: Expr,
+    pub collation: Option<String>,
+    pub descending: bool,
+}
+
+// Represents a column definition
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnDef {
+    pub name: String,
+    // The type as declared, like VARCHAR(255), which may be empty
+    pub declared_type: String,
+    pub affinity: Affinity,
+    pub constraints: Vec<ColumnConstraint>,
+    pub ipk: bool, // is an integer primary key
+}
+
+impl ColumnDef {
+    // A column with no declared type or constraints, like the columns of a
+    // subquery
+    pub fn new(name: String, affinity: Affinity) -> ColumnDef {
+        ColumnDef {
+            name,
+            declared_type: affinity.declared_type().to_string(),
+            affinity,
+            constraints: Vec::new(),
+            ipk: false,
+        }
+    }
+
+    // Converts a value read from a record to the column's type. Reals with
+    // integer values may be stored as integers to save space, so columns
+    // with REAL affinity turn integers back into reals.
+    pub fn stored_value(&self, value: Data) -> Data {
+        match value {
+            Data::Integer(i) if self.affinity == Affinity::Real => Data::Float(i as f64),
+            value => value,
+        }
+    }
+
+    // The expression of a VIRTUAL generated column, which is computed when a
+    // row is read instead of being stored in the record
+    pub fn virtual_expr(&self) -> Option<&Expr> {
+        self.constraints.iter().find_map(|c| match c {
+            ColumnConstraint::Generated {
+                expr,
+                stored: false,
+            } => Some(expr),
+            _ => None,
+        })
+    }
+
+    // The name of the column's collation, if it declares one
+    pub fn collation(&self) -> Option<&str> {
+        self.constraints.iter().find_map(|c| match c {
+            ColumnConstraint::Collate(name) => Some(name.as_str()),
+            _ => None,
+        })
+    }
+
+    // The value of the column in rows written before ALTER TABLE added it,
+    // which is its default. The default of an added column is a literal.
+    pub fn missing_value(&self) -> Data {
+        let default = self.constraints.iter().find_map(|c| match c {
+            ColumnConstraint::Default(Expr::Literal(value)) => Some(value),
+            _ => None,
+        });
+        match default {
+            Some(value) => value.clone().apply_affinity(self.affinity),
+            None => Data::Null,
+        }
+    }
+}
+
+// A constraint in a column definition. Constraint names and conflict
+// clauses are left out.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ColumnConstraint {
+    PrimaryKey {
+        descending: bool,
+        autoincrement: bool,
+    },
+    NotNull,
+    Unique,
+    Check(Expr),
+    Default(Expr),
+    Collate(String),
+    References(ForeignKey),
+    // [GENERATED ALWAYS] AS (expr) [STORED | VIRTUAL], a column computed
+    // from the others. Virtual columns aren't stored in the records.
+    Generated {
+        expr: Expr,
+        stored: bool,
+    },
+}
+
+// A constraint on the columns of a table, after its column definitions
+#[derive(Debug, Clone, PartialEq)]
+pub enum TableConstraint {
+    PrimaryKey(Vec<String>),
+    Unique(Vec<String>),
+    Check(Expr),
+    ForeignKey {
+        columns: Vec<String>,
+        references: ForeignKey,
+    },
+}
+
+// The table a foreign key refers to, and its columns. Without columns, the
+// key refers to the table's primary key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForeignKey {
+    pub table: String,
+    pub columns: Vec<String>,
+}
@@ -0,0 +1,139 @@
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+
+// The default cache holds 2000 KiB of pages, like SQLite's default cache_size
+pub const DEFAULT_CACHE_BYTES: usize = 2000 * 1024;
+
+// How large a page cache may grow, either as a number of pages or as a
+// number of bytes of page data
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CacheSize {
+    Pages(usize),
+    Bytes(usize),
+}
+
+impl CacheSize {
+    // The number of pages of the given size that fit in the cache
+    pub fn pages(&self, page_size: usize) -> usize {
+        match self {
+            CacheSize::Pages(n) => *n,
+            CacheSize::Bytes(n) => n / page_size,
+        }
+    }
+}
+
+impl Default for CacheSize {
+    fn default() -> Self {
+        CacheSize::Bytes(DEFAULT_CACHE_BYTES)
+    }
+}
+
+// Counts of cache lookups that did and didn't find their page
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+// A bounded cache of raw pages, keyed by page number. When it is full, the
+// least recently used page is evicted.
+#[derive(Debug)]
+pub struct PageCache {
+    capacity: usize,
+    // Each cached page, together with the tick at which it was last used
+    pages: HashMap<u64, (Rc<[u8]>, u64)>,
+    // Page numbers ordered by when they were last used
+    recency: BTreeMap<u64, u64>,
+    tick: u64,
+    stats: CacheStats,
+}
+
+impl PageCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            pages: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    // Look up a page, marking it as the most recently used
+    pub fn get(&mut self, page_number: u64) -> Option<Rc<[u8]>> {
+        self.tick += 1;
+        match self.pages.get_mut(&page_number) {
+            Some((page, last_used)) => {
+                self.recency.remove(last_used);
+                self.recency.insert(self.tick, page_number);
+                *last_used = self.tick;
+                self.stats.hits += 1;
+                Some(page.clone())
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    // Add a page to the cache, evicting the least recently used page if the
+    // cache is full
+    pub fn insert(&mut self, page_number: u64, page: Rc<[u8]>) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.tick += 1;
+        if let Some((_, last_used)) = self.pages.insert(page_number, (page, self.tick)) {
+            self.recency.remove(&last_used);
+        }
+        self.recency.insert(self.tick, page_number);
+        while self.pages.len() > self.capacity {
+            if let Some((_, oldest)) = self.recency.pop_first() {
+                self.pages.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(byte: u8) -> Rc<[u8]> {
+        Rc::from(vec![byte; 4])
+    }
+
+    #[test]
+    fn test_hits_and_misses() {
+        let mut cache = PageCache::new(2);
+        assert_eq!(cache.get(1), None);
+        cache.insert(1, page(1));
+        assert_eq!(cache.get(1), Some(page(1)));
+        assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 1 });
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let mut cache = PageCache::new(2);
+        cache.insert(1, page(1));
+        cache.insert(2, page(2));
+        // Using page 1 makes page 2 the least recently used
+        cache.get(1);
+        cache.insert(3, page(3));
+        assert_eq!(cache.pages.len(), 2);
+        assert_eq!(cache.get(2), None);
+        assert_eq!(cache.get(1), Some(page(1)));
+        assert_eq!(cache.get(3), Some(page(3)));
+    }
+
+    #[test]
+    fn test_cache_size_in_bytes() {
+        assert_eq!(CacheSize::Bytes(1 << 20).pages(4096), 256);
+        assert_eq!(CacheSize::Pages(10).pages(4096), 10);
+    }
+}
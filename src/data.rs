@@ -1,12 +1,22 @@
+use crate::ast::{ColumnDef, ColumnRef, Expr, IndexedColumn};
+use crate::cache::{CacheSize, CacheStats};
+use crate::executor;
+use crate::journal::HotJournal;
 use crate::parser;
-use crate::parser::{Data, Page, PageValue};
+use crate::parser::{Data, Header, Page, PageLayout, PageSource, PageValue, TextEncoding};
+use crate::sql_parser;
+use crate::storage::{Backend, PageBytes, Storage};
+use crate::wal::Wal;
 
 use anyhow::{anyhow, bail};
-use regex::Regex;
-use std::cell::RefCell;
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
+use std::io::Read;
+use std::ops::Bound;
+
+// The file format version in the header that marks a database in WAL mode
+const WAL_FILE_FORMAT_VERSION: u8 = 2;
 
 const ROOT_PAGE_INDEX: usize = 3;
 const CREATE_TABLE_INDEX: usize = 4;
@@ -59,241 +69,556 @@ pub fn get_create_table<'a>(
     get_schema_value_by_index(table_name, schema, CREATE_TABLE_INDEX)
 }
 
-// Given the root page of a table, return the page numbers of all its pages
-pub fn get_pages(root_index: usize, db: &Database) -> Result<Vec<usize>, anyhow::Error> {
-    let (_, root) = parser::parse_page(&db.read_page_at(root_index as u64)?, false)
-        .map_err(|e| anyhow!("{e}"))?;
-    // If the root page is a leaf page, it's the only page for the table.
-    if root.header.page_type == parser::PageType::LeafTable {
-        Ok(vec![root_index])
-    } else if root.header.page_type == parser::PageType::InteriorTable {
-        // If the root page is an interior page, it contains the page numbers
-        // of the child pages.
-        let mut child_pages = Vec::new();
-        child_pages.push(root.header.right_most_pointer.ok_or(anyhow!(
-            "Root page is interior but has no right most pointer"
-        ))? as usize);
-        for value in root.values {
-            if let parser::PageValue::InteriorTable {
-                left_child_page,
-                rowid: _,
-            } = value
-            {
-                child_pages.push(left_child_page as usize);
+// Walks a table B-tree in rowid order, yielding one row at a time. Only the
+// pages on the path from the root to the current leaf are held in memory.
+pub struct TableCursor<'a> {
+    db: &'a Database,
+    // The page to start from, until the cursor has descended into it
+    root_page: Option<u64>,
+    // The interior pages above the current leaf, each with the index of the
+    // next child to descend into
+    stack: Vec<(Page, usize)>,
+    // The rows of the current leaf that haven't been returned yet
+    rows: std::vec::IntoIter<PageValue>,
+}
+
+impl<'a> TableCursor<'a> {
+    pub fn new(db: &'a Database, root_page: u64) -> Self {
+        Self {
+            db,
+            root_page: Some(root_page),
+            stack: Vec::new(),
+            rows: Vec::new().into_iter(),
+        }
+    }
+
+    // Given an interior page, return the page number of its i-th child. The
+    // right-most pointer comes after the left children of all the cells.
+    fn child(page: &Page, i: usize) -> Option<u64> {
+        match page.values.get(i) {
+            Some(PageValue::InteriorTable {
+                left_child_page, ..
+            }) => Some(*left_child_page as u64),
+            Some(_) => None,
+            None if i == page.values.len() => page.header.right_most_pointer.map(|p| p as u64),
+            None => None,
+        }
+    }
+
+    // Descend from a page to its left-most leaf, pushing the interior pages
+    // on the way onto the stack
+    fn descend(&mut self, mut page_number: u64) -> Result<(), anyhow::Error> {
+        loop {
+            let page = self.db.parse_page_at(page_number)?;
+            match page.header.page_type {
+                parser::PageType::LeafTable => {
+                    self.rows = page.values.into_iter();
+                    return Ok(());
+                }
+                parser::PageType::InteriorTable => {
+                    page_number = Self::child(&page, 0)
+                        .ok_or(anyhow!("Interior page {page_number} has no children"))?;
+                    self.stack.push((page, 1));
+                }
+                _ => bail!("Page {page_number} is not a table page"),
             }
         }
-        let pages = child_pages
-            .iter()
-            .map(|p| get_pages(*p, db))
-            .collect::<Result<Vec<_>, _>>()?
-            .concat();
-        Ok(pages)
-    } else {
-        Err(anyhow!("Root page is not a table"))
     }
-}
 
-// Given a page and the columns of a table, return the rows of the table.
-pub fn get_rows<'a>(
-    page: &'a Page,
-    columns: &'a [parser::ColumnDef],
-    where_: Option<parser::WhereClause>,
-) -> Result<Vec<HashMap<String, parser::Data>>, anyhow::Error> {
-    let mut rows = Vec::<HashMap<String, parser::Data>>::new();
-    for val in &page.values {
-        if let parser::PageValue::LeafTable {
-            payload: vec,
-            rowid,
-        } = val
-        {
-            let mut map = HashMap::new();
-            for (i, col) in columns.iter().enumerate() {
-                // If the column is the integer primary key, then it must be null,
-                // and we substitute the row id.
-                if col.ipk {
-                    map.insert(col.name.clone(), Data::Integer(*rowid));
-                } else {
-                    map.insert(col.name.clone(), vec[i].clone());
+    // Move to the next leaf. Returns false when there are no more leaves.
+    fn next_leaf(&mut self) -> Result<bool, anyhow::Error> {
+        if let Some(root_page) = self.root_page.take() {
+            self.descend(root_page)?;
+            return Ok(true);
+        }
+        while let Some((page, i)) = self.stack.last_mut() {
+            let child = Self::child(page, *i);
+            *i += 1;
+            match child {
+                Some(child) => {
+                    self.descend(child)?;
+                    return Ok(true);
+                }
+                None => {
+                    self.stack.pop();
                 }
             }
-            rows.push(map);
         }
+        Ok(false)
     }
-    let rows = if let Some(where_) = where_ {
-        rows.into_iter()
-            .filter(|row| {
-                let where_value = row.get(&where_.column);
-                if let Some(where_value) = where_value {
-                    where_value == &Data::Text(where_.value.clone())
-                } else {
-                    false
+}
+
+impl Iterator for TableCursor<'_> {
+    type Item = Result<PageValue, anyhow::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(row) = self.rows.next() {
+                return Some(Ok(row));
+            }
+            match self.next_leaf() {
+                Ok(true) => continue,
+                Ok(false) => return None,
+                // Stop after an error rather than skipping the broken subtree
+                Err(e) => {
+                    self.stack.clear();
+                    return Some(Err(e));
                 }
-            })
-            .collect::<Vec<_>>()
-    } else {
-        rows
-    };
-    Ok(rows)
+            }
+        }
+    }
 }
 
-// Use an index to find the rowids of rows that match a value
-pub fn search_index(
-    root_index: usize,
-    value: Data,
-    db: &Database,
-) -> Result<Vec<i64>, anyhow::Error> {
-    let (_, root) = parser::parse_page(&db.read_page_at(root_index as u64)?, false)
-        .map_err(|e| anyhow!("{e}"))?;
-    let m = root.values.partition_point(|v| {
-        if let PageValue::InteriorIndex { payload, .. } | PageValue::LeafIndex { payload } = v {
-            payload[0] < value
-        } else {
-            panic!("Not an index page")
+// Given a table row and the columns of its table, return a map of column
+// names to values.
+pub fn get_row(
+    row: &PageValue,
+    columns: &[ColumnDef],
+) -> Result<HashMap<String, Data>, anyhow::Error> {
+    let mut map = HashMap::new();
+    if let PageValue::LeafTable { payload, rowid } = row {
+        // A column named rowid hides the rowid
+        map.insert("rowid".to_string(), Data::Integer(*rowid));
+        // Virtual columns aren't in the record, so the values of the columns
+        // after them come earlier
+        let mut values = payload.iter().cloned();
+        let mut virtual_columns = Vec::new();
+        for col in columns {
+            if let Some(expr) = col.virtual_expr() {
+                virtual_columns.push((col, expr));
+                continue;
+            }
+            let value = values.next();
+            // If the column is the integer primary key, then it must be null,
+            // and we substitute the row id.
+            let value = if col.ipk {
+                Data::Integer(*rowid)
+            } else {
+                match value {
+                    Some(value) => col.stored_value(value),
+                    // Rows written before a column was added don't store it
+                    None => col.missing_value(),
+                }
+            };
+            map.insert(col.name.clone(), value);
         }
-    });
-    let n = root.values.partition_point(|v| {
-        if let PageValue::InteriorIndex { payload, .. } | PageValue::LeafIndex { payload } = v {
-            payload[0] <= value
-        } else {
-            panic!("Not an index page")
+        if !virtual_columns.is_empty() {
+            compute_virtual_columns(&mut map, columns, virtual_columns)?;
         }
-    });
-    let mut results = Vec::new();
-    for i in m..=(n.min(root.values.len() - 1)) {
-        match &root.values[i] {
-            PageValue::InteriorIndex {
-                left_child_page,
-                payload,
-            } => {
-                if let Data::Integer(k) = payload[1] {
-                    results.push(k);
+    }
+    Ok(map)
+}
+
+// Computes the virtual columns of a row from its other columns. A virtual
+// column may use others, so each is computed once the ones it uses are.
+fn compute_virtual_columns(
+    row: &mut HashMap<String, Data>,
+    columns: &[ColumnDef],
+    mut pending: Vec<(&ColumnDef, &Expr)>,
+) -> Result<(), anyhow::Error> {
+    let scope = executor::Scope::new(columns);
+    while !pending.is_empty() {
+        let uses_pending = |expr: &Expr| {
+            let mut exprs = vec![expr];
+            while let Some(expr) = exprs.pop() {
+                if let Expr::Column(column) = expr {
+                    if pending
+                        .iter()
+                        .any(|(col, _)| col.name.eq_ignore_ascii_case(&column.name))
+                    {
+                        return true;
+                    }
+                }
+                exprs.extend(expr.children());
+            }
+            false
+        };
+        let Some(ready) = pending.iter().position(|(_, expr)| !uses_pending(expr)) else {
+            bail!("generated column loop on \"{}\"", pending[0].0.name);
+        };
+        let (column, expr) = pending.remove(ready);
+        let env = executor::Env::new(&scope, std::slice::from_ref(row));
+        let value = executor::eval(expr, &env)?.apply_affinity(column.affinity);
+        row.insert(column.name.clone(), value);
+    }
+    Ok(())
+}
+
+// Given a cursor over a table and the columns of the table, lazily return
+// the rows of the table that match the WHERE clause.
+#[allow(dead_code)]
+pub fn get_rows<'a>(
+    rows: impl Iterator<Item = Result<PageValue, anyhow::Error>> + 'a,
+    columns: Vec<ColumnDef>,
+    where_: Option<&'a Expr>,
+) -> impl Iterator<Item = Result<HashMap<String, Data>, anyhow::Error>> + 'a {
+    let scope = executor::Scope::new(&columns);
+    let rows = rows.map(move |row| get_row(&row?, &columns));
+    executor::filter(rows, scope, where_)
+}
+
+// Compare the leading columns of an index key with a bound, which may have
+// fewer columns than the key. Text is ordered by its bytes in the database's
+// encoding, as it is in the index.
+fn compare_prefix(key: &[Data], bound: &[Data], encoding: TextEncoding) -> Ordering {
+    key.iter()
+        .zip(bound)
+        .map(|(a, b)| a.compare_encoded(b, encoding))
+        .find(|c| *c != Ordering::Equal)
+        .unwrap_or(Ordering::Equal)
+}
+
+// Walks an index B-tree in key order, starting from the first key within a
+// lower bound and stopping at the first key past an upper bound. Keys are
+// index records: the indexed columns followed by the rowid. Bounds apply to
+// the leading columns of the keys, so a bound with fewer columns than the
+// index matches every key with that prefix.
+pub struct IndexCursor<'a> {
+    db: &'a Database,
+    // The interior pages above the current leaf, each with the index of the
+    // cell to return once the subtree to its left is done
+    stack: Vec<(Page, usize)>,
+    // The current leaf, with the index of the next cell to return
+    leaf: Option<(Page, usize)>,
+    upper: Bound<Vec<Data>>,
+    done: bool,
+}
+
+impl<'a> IndexCursor<'a> {
+    // Create a cursor over the keys between two bounds
+    pub fn new(
+        db: &'a Database,
+        root_page: u64,
+        lower: Bound<Vec<Data>>,
+        upper: Bound<Vec<Data>>,
+    ) -> Result<Self, anyhow::Error> {
+        let mut cursor = Self {
+            db,
+            stack: Vec::new(),
+            leaf: None,
+            upper,
+            done: false,
+        };
+        cursor.seek(root_page, &lower)?;
+        Ok(cursor)
+    }
+
+    // Given an interior page, return the page number of its i-th child. The
+    // right-most pointer comes after the left children of all the cells.
+    fn child(page: &Page, i: usize) -> Result<u64, anyhow::Error> {
+        match page.values.get(i) {
+            Some(PageValue::InteriorIndex {
+                left_child_page, ..
+            }) => Ok(*left_child_page as u64),
+            None if i == page.values.len() => page
+                .header
+                .right_most_pointer
+                .map(|p| p as u64)
+                .ok_or(anyhow!("Interior index page has no right-most pointer")),
+            _ => bail!("Not an interior index page"),
+        }
+    }
+
+    // Descend from a page to the first key that is not below the lower bound,
+    // pushing the interior pages on the way onto the stack
+    fn seek(
+        &mut self,
+        mut page_number: u64,
+        lower: &Bound<Vec<Data>>,
+    ) -> Result<(), anyhow::Error> {
+        loop {
+            let page = self.db.parse_page_at(page_number)?;
+            let page_type = page.header.page_type;
+            if !matches!(
+                page_type,
+                parser::PageType::InteriorIndex | parser::PageType::LeafIndex
+            ) {
+                bail!("Page {page_number} is not an index page");
+            }
+            let encoding = self.db.text_encoding();
+            let i = page.values.partition_point(|v| {
+                let key = v.get_payload().map_or(&[][..], |p| &p[..]);
+                match lower {
+                    Bound::Included(bound) => {
+                        compare_prefix(key, bound, encoding) == Ordering::Less
+                    }
+                    Bound::Excluded(bound) => {
+                        compare_prefix(key, bound, encoding) != Ordering::Greater
+                    }
+                    Bound::Unbounded => false,
                 }
-                results.extend(search_index(*left_child_page as usize, value.clone(), db)?);
+            });
+            if page_type == parser::PageType::LeafIndex {
+                self.leaf = Some((page, i));
+                return Ok(());
             }
-            PageValue::LeafIndex { payload } => {
-                if let Data::Integer(k) = payload[1] {
-                    // if i == m {
-                    results.push(k);
-                    // }
+            // Keys in the subtree to the left of cell i are not above it, so
+            // the first key within the bound may be in that subtree
+            page_number = Self::child(&page, i)?;
+            self.stack.push((page, i));
+        }
+    }
+
+    // Return the next key in order, whether or not it is within the upper bound
+    fn next_key(&mut self) -> Result<Option<Vec<Data>>, anyhow::Error> {
+        loop {
+            if let Some((leaf, i)) = &mut self.leaf {
+                if let Some(value) = leaf.values.get(*i) {
+                    *i += 1;
+                    return Ok(value.get_payload().cloned());
                 }
+                self.leaf = None;
+            }
+            // The current subtree is done, so return the cell to its right in
+            // the parent page, then move on to the subtree to the right of that
+            let Some((page, i)) = self.stack.last_mut() else {
+                return Ok(None);
+            };
+            if *i < page.values.len() {
+                let key = page.values[*i].get_payload().cloned();
+                *i += 1;
+                let child = Self::child(page, *i)?;
+                self.seek(child, &Bound::Unbounded)?;
+                return Ok(key);
             }
-            _ => panic!("Not an index page"),
+            self.stack.pop();
         }
     }
-    if m == root.values.len() {
-        if let Some(right_most_pointer) = root.header.right_most_pointer {
-            results.extend(search_index(right_most_pointer as usize, value, db)?);
-        } else if let Some(vec) = root.values[m - 1].get_payload() {
-            if let Data::Integer(k) = vec[1] {
-                results.push(k);
+}
+
+impl Iterator for IndexCursor<'_> {
+    type Item = Result<Vec<Data>, anyhow::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let key = match self.next_key() {
+            Ok(Some(key)) => key,
+            Ok(None) => {
+                self.done = true;
+                return None;
+            }
+            Err(e) => {
+                self.done = true;
+                return Some(Err(e));
             }
+        };
+        let encoding = self.db.text_encoding();
+        let past_upper = match &self.upper {
+            Bound::Included(bound) => compare_prefix(&key, bound, encoding) == Ordering::Greater,
+            Bound::Excluded(bound) => compare_prefix(&key, bound, encoding) != Ordering::Less,
+            Bound::Unbounded => false,
+        };
+        if past_upper {
+            self.done = true;
+            return None;
         }
+        Some(Ok(key))
     }
-    Ok(results)
 }
 
-// Given a database and a root page, us a binary search to find a given rowid
+// Use an index to find the rowids of rows that match a value
+pub fn search_index(
+    root_index: usize,
+    value: Data,
+    db: &Database,
+) -> Result<Vec<i64>, anyhow::Error> {
+    let key = vec![value];
+    IndexCursor::new(
+        db,
+        root_index as u64,
+        Bound::Included(key.clone()),
+        Bound::Included(key),
+    )?
+    .map(|key| match key?.last() {
+        Some(Data::Integer(rowid)) => Ok(*rowid),
+        _ => bail!("Index entry has no rowid"),
+    })
+    .collect()
+}
+
+// Given a database and a root page, use a binary search to find a given rowid
 pub fn search_by_rowid(
     db: &Database,
     root_page_number: u64,
     rowid_to_find: i64,
 ) -> Result<PageValue, anyhow::Error> {
-    let root = db.read_page_at(root_page_number)?;
-    let (_, root) = parser::parse_page(&root, false).map_err(|e| anyhow!("{e}"))?;
-    let i = root.values.binary_search_by_key(&rowid_to_find, |v| {
-        if let PageValue::LeafTable { rowid, .. } | PageValue::InteriorTable { rowid, .. } = v {
-            *rowid
-        } else {
-            panic!("Not a table age")
+    find_rowid(db, root_page_number, rowid_to_find)?.ok_or(anyhow!("Rowid not in table"))
+}
+
+// Like search_by_rowid, but returns None if the table has no such row
+pub fn find_rowid(
+    db: &Database,
+    root_page_number: u64,
+    rowid_to_find: i64,
+) -> Result<Option<PageValue>, anyhow::Error> {
+    let root = db.parse_page_at(root_page_number)?;
+    match root.header.page_type {
+        // If the page is a table leaf, the row is either on it or not in the table
+        parser::PageType::LeafTable => Ok(root
+            .values
+            .into_iter()
+            .find(|v| matches!(v, PageValue::LeafTable { rowid, .. } if *rowid == rowid_to_find))),
+        // Each cell of an interior page holds the largest rowid in the subtree
+        // to its left. Rowids larger than every cell are in the right-most subtree.
+        parser::PageType::InteriorTable => {
+            let i = root.values.partition_point(
+                |v| matches!(v, PageValue::InteriorTable { rowid, .. } if *rowid < rowid_to_find),
+            );
+            let child = match root.values.get(i) {
+                Some(PageValue::InteriorTable {
+                    left_child_page, ..
+                }) => *left_child_page,
+                Some(_) => bail!("Not a table page"),
+                None => root
+                    .header
+                    .right_most_pointer
+                    .ok_or(anyhow!("Interior page has no right-most pointer"))?,
+            };
+            find_rowid(db, child as u64, rowid_to_find)
         }
-    });
-    if let Ok(i) = i {
-        // If the page is a table leaf and the rowid is present, return the data
-        if let PageValue::LeafTable { .. } = &root.values[i] {
-            Ok(root.values[i].clone())
-        // If the page is a table interior and the rowid is present, that means
-        // the rowid is not in the table.
-        } else if let PageValue::InteriorTable { .. } = &root.values[i] {
-            bail!("Rowid not in table");
         // This function should not be called on index pages
-        } else {
-            bail!("Not a table page")
-        }
-        // When the rowid is not present, the binary search returns the index
-        // where the rowid should be inserted.
-    } else if let Err(i) = i {
-        // If the insertion index is equal to the length of the page, then the
-        // rowid is greater than all the rowids in the page. Then the rightmost
-        // pointer is followed.
-        if i == root.values.len() {
-            if let Some(right_most_pointer) = root.header.right_most_pointer {
-                return search_by_rowid(db, right_most_pointer as u64, rowid_to_find);
-            }
-        }
-        if let PageValue::InteriorTable {
-            left_child_page,
-            rowid: _,
-        } = &root.values[i]
-        {
-            return search_by_rowid(db, *left_child_page as u64, rowid_to_find);
-        } else {
-            bail!("Not a table page")
-        }
-    } else {
-        bail!("Not a table page")
+        _ => bail!("Not a table page"),
     }
 }
 
+// Options that control how a database file is opened
+#[derive(Debug, Clone, Default)]
+pub struct DatabaseOptions {
+    pub cache_size: CacheSize,
+    pub backend: Backend,
+    // Whether to play back a hot rollback journal, instead of refusing to
+    // open a database that a crashed writer left inconsistent
+    pub recover_journal: bool,
+}
+
 // Represents a database file. The schema_page field stores the schema page for reference.
+// Pages are read through the storage backend chosen when the database was opened,
+// unless the database is in WAL mode and the log holds a newer version of the page.
 pub struct Database {
-    filename: String,
+    storage: Storage,
+    wal: Option<Wal>,
+    header: Header,
     page_size: u64,
+    layout: PageLayout,
+    text_encoding: TextEncoding,
     schema_page: Vec<PageValue>,
 }
 
+// The database reads overflow pages for the cell parsers
+impl PageSource for Database {
+    fn layout(&self) -> PageLayout {
+        self.layout
+    }
+
+    fn text_encoding(&self) -> TextEncoding {
+        self.text_encoding
+    }
+
+    fn overflow_page(&self, page_number: u32) -> Result<PageBytes, anyhow::Error> {
+        self.read_page_at(page_number as u64)
+    }
+}
+
 impl Database {
     // Create a new database instance from a filename
+    #[allow(dead_code)]
     pub fn new(filename: &str) -> Result<Self, anyhow::Error> {
+        Self::open(filename, DatabaseOptions::default())
+    }
+
+    // Create a new database instance from a filename, with the given options
+    pub fn open(filename: &str, options: DatabaseOptions) -> Result<Self, anyhow::Error> {
+        if let Some(journal) = HotJournal::find(filename)? {
+            if !options.recover_journal {
+                bail!(
+                    "{filename} has a hot journal left by an interrupted write, \
+                     so it may be inconsistent. Run .rollback to restore it."
+                );
+            }
+            journal.rollback(filename)?;
+        }
         let mut raw_header = [0; 100];
         let mut file = File::open(filename)?;
         file.read_exact(&mut raw_header)?;
         // Read the header to get the page size, then read the schema page
         let (_, header) = parser::parse_header(&raw_header).map_err(|e| anyhow::anyhow!("{e}"))?;
-        let mut buf = vec![0; header.page_size as usize];
-        file.rewind()?;
-        file.read_exact(&mut buf)?;
-        let (_, schema_page) =
-            parser::parse_page(&buf, true).map_err(|e| anyhow::anyhow!("{e}"))?;
-        Ok(Self {
-            filename: filename.to_string(),
-            page_size: header.page_size as u64,
-            schema_page: schema_page.values,
-        })
+        let page_size = header.page_size as u64;
+        if !page_size.is_power_of_two() || !(512..=65536).contains(&page_size) {
+            bail!("Invalid page size {page_size}");
+        }
+        let layout = PageLayout::from_header(&header);
+        // The format requires at least 480 usable bytes per page
+        if layout.usable_size < 480 {
+            bail!("Usable page size {} is too small", layout.usable_size);
+        }
+        let cache_pages = options.cache_size.pages(page_size as usize);
+        let wal = if header.file_format_read_version == WAL_FILE_FORMAT_VERSION {
+            Wal::open(filename, page_size as usize, cache_pages)?
+        } else {
+            None
+        };
+        let text_encoding = TextEncoding::from_header(header.text_encoding)
+            .ok_or(anyhow!("Unknown text encoding {}", header.text_encoding))?;
+        let mut db = Self {
+            storage: Storage::open(file, options.backend, cache_pages)?,
+            wal,
+            header,
+            page_size,
+            layout,
+            text_encoding,
+            schema_page: Vec::new(),
+        };
+        db.schema_page = TableCursor::new(&db, 1).collect::<Result<Vec<_>, _>>()?;
+        Ok(db)
+    }
+
+    // Get the header of the database file, as it was when the database was opened
+    pub fn header(&self) -> &Header {
+        &self.header
     }
 
-    // Read the page at the current cursor position
-    fn read_page(&self, file: &mut File) -> Result<Vec<u8>, anyhow::Error> {
-        let mut buf = vec![0; self.page_size as usize];
-        file.read_exact(&mut buf)?;
-        Ok(buf)
+    // Get the size of each page in bytes
+    pub fn page_size(&self) -> u64 {
+        self.page_size
     }
 
-    // Read a page at a given page number
-    // TODO The performance of this function could probably be improved
-    pub fn read_page_at(&self, page_number: u64) -> Result<Vec<u8>, anyhow::Error> {
-        let mut file = File::open(&self.filename)?;
-        // Page numbers start at 1
-        file.seek(SeekFrom::Start((page_number - 1) * self.page_size))?;
-        self.read_page(&mut file)
+    // Get the number of bytes of each page available to the B-tree. The rest
+    // of the page is reserved, e.g. for checksums or encryption.
+    #[allow(dead_code)]
+    pub fn usable_size(&self) -> usize {
+        self.layout.usable_size
     }
 
-    // Read multiple pages at given page numbers
-    fn _read_pages(&self, page_numbers: &[u64]) -> Result<Vec<Vec<u8>>, anyhow::Error> {
-        let mut pages = Vec::new();
-        for page_number in page_numbers {
-            pages.push(self.read_page_at(*page_number)?);
+    // Read a page at a given page number, from the cache or the mapping if possible.
+    // In WAL mode, the latest committed version of the page may be in the log.
+    pub fn read_page_at(&self, page_number: u64) -> Result<PageBytes, anyhow::Error> {
+        if let Some(wal) = &self.wal {
+            if let Some(page) = wal.read_page(page_number)? {
+                return Ok(page);
+            }
         }
-        Ok(pages)
+        self.storage.read_page(page_number, self.page_size as usize)
+    }
+
+    // Get the number of page reads that were and weren't served from the cache
+    pub fn cache_stats(&self) -> CacheStats {
+        self.storage.cache_stats()
+    }
+
+    // Read and parse the page at a given page number, following overflow
+    // pages for any cells that don't fit on it
+    pub fn parse_page_at(&self, page_number: u64) -> Result<Page, anyhow::Error> {
+        let buf = self.read_page_at(page_number)?;
+        let (_, page) =
+            parser::parse_page(&buf, page_number == 1, self).map_err(parser::page_error)?;
+        Ok(page)
+    }
+
+    // Get the rows of the schema table
+    pub fn schema(&self) -> &[PageValue] {
+        &self.schema_page
     }
 
     // Get the root page of a table given its name
@@ -309,40 +634,51 @@ impl Database {
         get_create_table(table_name, &self.schema_page)
     }
 
-    // Get the root page of an index given its table and column names
-    pub fn find_index_root(&self, column: &str, table: &str) -> Option<usize> {
-        // TODO: This is a hack. We should parse the create table statement.
-        let column_regex = Regex::new(&format!("(?i)on {table}\\s*\\({column}\\)")).unwrap();
-        let index = self.schema_page.iter().find(|elem| {
-            if let PageValue::LeafTable { payload: vec, .. } = elem {
-                if let Data::Text(s) = &vec[0] {
-                    if s == "index" {
-                        if let Data::Text(sql) = &vec[4] {
-                            column_regex.is_match(sql)
-                        } else {
-                            false
-                        }
-                    } else {
-                        false
-                    }
-                } else {
-                    false
-                }
-            } else {
-                false
-            }
-        });
-        index.map(|idx| {
-            if let PageValue::LeafTable { payload: vec, .. } = idx {
-                if let Data::Integer(n) = &vec[3] {
-                    *n as usize
-                } else {
-                    panic!("Index is not an integer")
+    // Get the root page of an index that can find the rows of a table by the
+    // value of a column. The column must lead the index as it is, with the
+    // BINARY collation and in ascending order, and the index must have a key
+    // for every row, so partial indexes aren't used. An index without a
+    // COLLATE clause has the collation of the column, which isn't checked
+    // here.
+    pub fn find_index_root(
+        &self,
+        column: &str,
+        table: &str,
+    ) -> Result<Option<usize>, anyhow::Error> {
+        for value in &self.schema_page {
+            let PageValue::LeafTable { payload, .. } = value else {
+                bail!("Not a data page");
+            };
+            let is_index = matches!(&payload[0], Data::Text(t) if t == "index");
+            let on_table = matches!(&payload[2], Data::Text(t) if t.eq_ignore_ascii_case(table));
+            // The indexes SQLite makes for UNIQUE and PRIMARY KEY constraints
+            // have no SQL
+            let (true, true, Data::Text(sql)) = (is_index, on_table, &payload[4]) else {
+                continue;
+            };
+            let index = sql_parser::parse_create_index(sql)?;
+            let usable = match index.columns.first() {
+                Some(IndexedColumn {
+                    expr: Expr::Column(ColumnRef { table: None, name }),
+                    collation,
+                    descending: false,
+                }) => {
+                    name.eq_ignore_ascii_case(column)
+                        && collation
+                            .as_ref()
+                            .is_none_or(|c| c.eq_ignore_ascii_case("binary"))
                 }
-            } else {
-                panic!("Index is not a data page")
+                _ => false,
+            };
+            if !usable || index.where_.is_some() {
+                continue;
             }
-        })
+            return match &payload[3] {
+                Data::Integer(n) => Ok(Some(*n as usize)),
+                _ => bail!("Root page of index {} is not an integer", index.name),
+            };
+        }
+        Ok(None)
     }
 
     // Find the rows of a table that match a value using an index
@@ -352,42 +688,114 @@ impl Database {
         table: &str,
         value: Data,
     ) -> Result<Vec<PageValue>, anyhow::Error> {
-        if let Some(index_root) = self.find_index_root(column, table) {
+        if let Some(index_root) = self.find_index_root(column, table)? {
             let indices = search_index(index_root, value, self)?;
-            let mut results = Vec::new();
-            for row in indices {
-                if let Some(Data::Integer(n)) = self.get_root_page(table)? {
-                    results.push(search_by_rowid(self, n as u64, row)?);
-                }
-            }
-            Ok(results)
+            let Some(Data::Integer(table_root)) = self.get_root_page(table)? else {
+                return Ok(Vec::new());
+            };
+            indices
+                .into_iter()
+                .map(|row| search_by_rowid(self, table_root as u64, row))
+                .collect()
         } else {
             bail!("No index found for {column} in table {table}")
         }
     }
 
     // Given a row and a table name, return a map of column names to values
+    #[allow(dead_code)]
     pub fn match_row_with_column_names(
         &self,
         row: &PageValue,
         table_name: &str,
     ) -> Result<HashMap<String, Data>, anyhow::Error> {
-        let mut map = HashMap::new();
-        let create_table = self.get_create_table(table_name)?;
-        if let Some(Data::Text(sql)) = create_table {
-            let (_, columns) = parser::parse_create_table(&sql).map_err(|e| anyhow!("{e}"))?;
-            if let PageValue::LeafTable { payload, rowid } = row {
-                map.insert("rowid".to_string(), Data::Integer(*rowid));
-                for (i, col) in columns.iter().enumerate() {
-                    // If the column is an integer primary key, insert rowid for value instead
-                    if col.ipk {
-                        map.insert(col.name.clone(), Data::Integer(*rowid));
-                    } else {
-                        map.insert(col.name.clone(), payload[i].clone());
-                    }
-                }
-            }
+        match self.get_create_table(table_name)? {
+            Some(Data::Text(sql)) => get_row(row, &sql_parser::parse_create_table(&sql)?.columns),
+            _ => Ok(HashMap::new()),
         }
-        Ok(map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::Statement;
+    use crate::sql_parser::parse_statement;
+
+    #[test]
+    fn test_table_cursor() {
+        let db = Database::new("sample.db").unwrap();
+        let root = match db.get_root_page("oranges").unwrap() {
+            Some(Data::Integer(n)) => n as u64,
+            _ => panic!("Missing table oranges"),
+        };
+        let rowids = TableCursor::new(&db, root)
+            .map(|row| match row.unwrap() {
+                PageValue::LeafTable { rowid, .. } => rowid,
+                _ => panic!("Not a table row"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(rowids, vec![1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn test_get_row() {
+        let columns = sql_parser::parse_create_table(
+            "create table t(id integer primary key, a, b as (a + c), c real, d int default '7')",
+        )
+        .unwrap()
+        .columns;
+        // The record of a row written before d was added, with no value for
+        // the virtual column b
+        let row = PageValue::LeafTable {
+            payload: vec![Data::Null, Data::Integer(1), Data::Integer(2)],
+            rowid: 5,
+        };
+        let row = get_row(&row, &columns).unwrap();
+        assert_eq!(row.get("id"), Some(&Data::Integer(5)));
+        assert_eq!(row.get("b"), Some(&Data::Float(3.0)));
+        assert_eq!(row.get("c"), Some(&Data::Float(2.0)));
+        assert_eq!(row.get("d"), Some(&Data::Integer(7)));
+    }
+
+    #[test]
+    fn test_utf16_index_order() {
+        // The index on t(a) is sorted by the UTF-16le bytes of its keys, where
+        // "Ā1" comes before "A26" and "中文" before "é"
+        let db = Database::open("utf16le.db", DatabaseOptions::default()).unwrap();
+        let find = |value: &str| {
+            db.find_by_index("a", "t", Data::Text(value.to_string()))
+                .unwrap()
+                .iter()
+                .map(|row| match row {
+                    PageValue::LeafTable { rowid, .. } => *rowid,
+                    _ => panic!("Not a table row"),
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(find("C2"), vec![2, 68]);
+        assert_eq!(find("Ā1"), vec![61]);
+        assert_eq!(find("中文"), vec![64]);
+        assert_eq!(find("é"), vec![65]);
+        assert_eq!(find("missing"), Vec::<i64>::new());
+    }
+
+    #[test]
+    fn test_get_rows_filters_lazily() {
+        let db = Database::new("sample.db").unwrap();
+        let columns = sql_parser::parse_create_table(
+            "create table apples (id integer primary key autoincrement, name text, color text)",
+        )
+        .unwrap()
+        .columns;
+        let where_ = match parse_statement("select id from apples where color = 'Red'").unwrap() {
+            Statement::Select(select) => select.where_,
+        };
+        let rows = get_rows(TableCursor::new(&db, 2), columns, where_.as_ref())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].get("id"), Some(&Data::Integer(2)));
+        assert_eq!(rows[0].get("name"), Some(&Data::Text("Fuji".to_string())));
     }
 }
@@ -0,0 +1,2876 @@
+use crate::aggregate::{self, Accumulator, Key};
+use crate::ast::{
+    BinaryOp, ColumnConstraint, ColumnDef, ColumnRef, CompoundOp, Cte, Expr, FunctionArgs,
+    JoinConstraint, LikeOp, NullsOrder, OrderingTerm, ResultColumn, Select, Statement, TableRef,
+    UnaryOp,
+};
+use crate::data::Database;
+use crate::functions;
+use crate::join::{self, JoinedRows, Table};
+use crate::parser::{parse_numeric_prefix, Affinity, Collation, Data};
+use crate::pattern;
+use crate::window;
+
+use anyhow::{anyhow, bail};
+use std::cell::{Cell, OnceCell, RefCell};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::iter;
+use std::rc::Rc;
+
+// A row of named values, as produced by data::get_rows
+pub type Row = HashMap<String, Data>;
+
+// The rows of the tables of a FROM clause that are combined into one row
+// of the join, in the order of the tables
+pub type JoinedRow = Vec<Row>;
+
+// A row of a SELECT before its result columns are evaluated: a joined row,
+// or the row a group takes its bare columns from, along with the values of
+// the aggregate and window function calls
+pub type SourceRow<'a> = (JoinedRow, Vec<(&'a Expr, Data)>);
+
+// The columns of one table of a FROM clause, with their affinities
+#[derive(Debug, Clone, Default)]
+struct TableScope {
+    // The table's alias, or its name. A subquery without an alias has none,
+    // so its columns can't be qualified.
+    name: Option<String>,
+    // The declared columns in order, which * expands to
+    columns: Vec<String>,
+    affinities: HashMap<String, Affinity>,
+    // The columns with a collation other than BINARY
+    collations: HashMap<String, Collation>,
+    // Columns that USING or NATURAL merged with a column of an earlier table,
+    // which unqualified names and * leave out
+    merged: Vec<String>,
+}
+
+impl TableScope {
+    fn is_named(&self, name: &str) -> bool {
+        self.name
+            .as_deref()
+            .is_some_and(|n| n.eq_ignore_ascii_case(name))
+    }
+
+    fn collation(&self, name: &str) -> Collation {
+        self.collations.get(name).copied().unwrap_or_default()
+    }
+
+    // Finds the name a column is stored under. Column names are matched
+    // regardless of case.
+    fn resolve(&self, name: &str) -> Option<&str> {
+        match self.affinities.get_key_value(name) {
+            Some((name, _)) => Some(name),
+            None => self
+                .affinities
+                .keys()
+                .find(|n| n.eq_ignore_ascii_case(name))
+                .map(String::as_str),
+        }
+    }
+}
+
+// The tables and columns that expressions can refer to
+#[derive(Debug, Clone, Default)]
+pub struct Scope {
+    tables: Vec<TableScope>,
+}
+
+impl Scope {
+    // A scope with a single unnamed table
+    pub fn new(columns: &[ColumnDef]) -> Self {
+        let mut scope = Self::default();
+        scope.add_table(None, columns, true);
+        scope
+    }
+
+    // Adds a table to the scope. Tables of the database have a rowid, which
+    // the rows of a subquery don't.
+    pub fn add_table(&mut self, name: Option<&str>, columns: &[ColumnDef], rowid: bool) {
+        let mut affinities = columns
+            .iter()
+            .map(|c| (c.name.clone(), c.affinity))
+            .collect::<HashMap<_, _>>();
+        if rowid {
+            affinities
+                .entry("rowid".to_string())
+                .or_insert(Affinity::Integer);
+        }
+        let collations = columns
+            .iter()
+            .filter_map(|c| {
+                let collation = c.collation().and_then(Collation::from_name)?;
+                Some((c.name.clone(), collation))
+            })
+            .collect();
+        self.tables.push(TableScope {
+            name: name.map(str::to_string),
+            columns: columns.iter().map(|c| c.name.clone()).collect(),
+            affinities,
+            collations,
+            merged: Vec::new(),
+        });
+    }
+
+    // Marks a column of the last table as merged with a column of an earlier
+    // table by USING or NATURAL
+    pub fn merge(&mut self, name: &str) {
+        if let Some(table) = self.tables.last_mut() {
+            if let Some(name) = table.resolve(name).map(str::to_string) {
+                table.merged.push(name);
+            }
+        }
+    }
+
+    fn has_table(&self, name: &str) -> bool {
+        self.tables.iter().any(|t| t.is_named(name))
+    }
+
+    // Checks that the columns * or table.* expands to can be told apart,
+    // which they can't if two tables have the same name and column
+    fn check_star(&self, qualifier: Option<&str>) -> Result<(), anyhow::Error> {
+        for (i, table) in self.tables.iter().enumerate() {
+            let Some(name) = &table.name else {
+                continue;
+            };
+            if qualifier.is_some_and(|q| !name.eq_ignore_ascii_case(q)) {
+                continue;
+            }
+            let others = self
+                .tables
+                .iter()
+                .enumerate()
+                .filter(|&(j, other)| j != i && other.is_named(name));
+            for (_, other) in others {
+                if let Some(column) = table.columns.iter().find(|c| other.resolve(c).is_some()) {
+                    bail!("ambiguous column name: {name}.{column}");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Finds the table a column belongs to, and the name the column is
+    // stored under in that table's rows, or None if no table has it
+    fn find(&self, column: &ColumnRef) -> Result<Option<(usize, &str)>, anyhow::Error> {
+        let mut found = None;
+        for (i, table) in self.tables.iter().enumerate() {
+            let visible = match &column.table {
+                Some(name) => table.is_named(name),
+                None => !table
+                    .merged
+                    .iter()
+                    .any(|m| m.eq_ignore_ascii_case(&column.name)),
+            };
+            let Some(name) = table.resolve(&column.name).filter(|_| visible) else {
+                continue;
+            };
+            if found.is_some() {
+                bail!("ambiguous column name: {}", column_name(column));
+            }
+            found = Some((i, name));
+        }
+        Ok(found)
+    }
+
+    // Like find, but a column that no table has is an error
+    pub fn resolve(&self, column: &ColumnRef) -> Result<(usize, &str), anyhow::Error> {
+        self.find(column)?
+            .ok_or_else(|| anyhow!("no such column: {}", column_name(column)))
+    }
+
+    // Returns the last table, in FROM clause order, that an expression
+    // refers to, or None if it refers to no columns. Columns of the queries
+    // this one is nested in don't count, since they are the same for every row.
+    pub fn last_table(&self, expr: &Expr) -> Result<Option<usize>, anyhow::Error> {
+        let mut last = match expr {
+            Expr::Column(column) => self.find(column)?.map(|(table, _)| table),
+            Expr::TableColumn { table, .. } => Some(*table),
+            // A subquery may refer to any of the tables
+            Expr::InSelect { .. } | Expr::Exists(_) | Expr::Subquery(_) => {
+                self.tables.len().checked_sub(1)
+            }
+            _ => None,
+        };
+        for child in expr.children() {
+            last = last.max(self.last_table(child)?);
+        }
+        Ok(last)
+    }
+}
+
+// Converts the operands of a comparison to a common affinity, given the
+// affinities of the expressions they came from. Numeric columns convert the
+// other operand to a number if it isn't numeric, and text columns convert
+// operands without an affinity to text.
+fn coerce(affinities: (Affinity, Affinity), l: Data, r: Data) -> (Data, Data) {
+    let (la, ra) = affinities;
+    if la.is_numeric() && !ra.is_numeric() {
+        (l, r.apply_affinity(Affinity::Numeric))
+    } else if ra.is_numeric() && !la.is_numeric() {
+        (l.apply_affinity(Affinity::Numeric), r)
+    } else if la == Affinity::Text && ra == Affinity::Blob {
+        (l, r.apply_affinity(Affinity::Text))
+    } else if ra == Affinity::Text && la == Affinity::Blob {
+        (l.apply_affinity(Affinity::Text), r)
+    } else {
+        (l, r)
+    }
+}
+
+// The rows produced by a statement
+pub type Rows<'a> = Box<dyn Iterator<Item = Result<Vec<Data>, anyhow::Error>> + 'a>;
+
+// Returns true if a value counts as true in a WHERE clause. NULL does not,
+// and text and blobs do if the number they start with isn't zero.
+pub fn is_true(value: &Data) -> bool {
+    match value {
+        Data::Integer(n) => *n != 0,
+        Data::Float(x) => *x != 0.0,
+        Data::Null => false,
+        value => is_true(&to_number(value.clone())),
+    }
+}
+
+// Returns the truth value of a value, or None if it is NULL
+fn truth(value: &Data) -> Option<bool> {
+    match value {
+        Data::Null => None,
+        value => Some(is_true(value)),
+    }
+}
+
+fn boolean(value: bool) -> Data {
+    Data::Integer(value as i64)
+}
+
+// What is kept of a subquery between the rows it is evaluated for. The
+// result of a subquery that doesn't refer to the rows of the queries it's
+// nested in is the same for every row. Other subqueries have to be run again
+// for each row, but their tables only have to be opened once, unless the rows
+// of a table depend on the row too.
+#[derive(Clone)]
+enum Prepared {
+    Result(Rc<SubqueryResult>),
+    Correlated(Option<Rc<Plan>>),
+}
+
+// The subqueries of a statement, keyed by their address
+type Subqueries = RefCell<HashMap<*const Select, Prepared>>;
+
+// The rows of a subquery, along with the affinities of its columns
+struct SubqueryResult {
+    affinities: Vec<Affinity>,
+    rows: Vec<Vec<Data>>,
+    // The values of the first column, converted for comparison with values
+    // of an affinity under a collation and sorted, so that IN can search them
+    sorted: OnceCell<((Affinity, Collation), Vec<Data>)>,
+}
+
+impl SubqueryResult {
+    fn new(affinities: Vec<Affinity>, rows: Vec<Vec<Data>>) -> Self {
+        Self {
+            affinities,
+            rows,
+            sorted: OnceCell::new(),
+        }
+    }
+
+    // Returns whether a value of an affinity is in the first column, as for
+    // expr IN (SELECT ...)
+    fn contains(
+        &self,
+        affinity: Affinity,
+        collation: Collation,
+        value: Data,
+        negated: bool,
+    ) -> Data {
+        let affinities = (affinity, self.affinities[0]);
+        let (sorted_as, sorted) = self.sorted.get_or_init(|| {
+            let mut sorted = self
+                .rows
+                .iter()
+                .map(|row| {
+                    coerce(affinities, Data::Null, row[0].clone())
+                        .1
+                        .collated(collation)
+                })
+                .collect::<Vec<_>>();
+            sorted.sort_by(Data::compare);
+            ((affinity, collation), sorted)
+        });
+        // The values were converted for another IN
+        if *sorted_as != (affinity, collation) {
+            let items = self.rows.iter().map(|row| (affinities.1, row[0].clone()));
+            return is_in(affinity, collation, value, items, negated);
+        }
+        // NULLs sort first, and make the result NULL if nothing matches
+        let has_null = sorted.first() == Some(&Data::Null);
+        let value = coerce(affinities, value, Data::Null).0.collated(collation);
+        match value {
+            Data::Null if sorted.is_empty() => boolean(negated),
+            Data::Null => Data::Null,
+            value if sorted.binary_search_by(|v| v.compare(&value)).is_ok() => boolean(!negated),
+            _ if has_null => Data::Null,
+            _ => boolean(negated),
+        }
+    }
+}
+
+// The rows of a table defined by a WITH clause. Those of a recursive table
+// are worked out as they are read.
+#[derive(Clone)]
+pub enum CteRows {
+    Rows(Rc<Vec<Row>>),
+    Recursive(Rc<Recursion>),
+}
+
+// The columns and rows of a table defined by a WITH clause
+pub type CteTable = (Vec<ColumnDef>, CteRows);
+
+// A recursive WITH table. Its first rows go into a queue. Each row taken
+// from the queue is added to the table, and the recursive SELECTs are run on
+// it alone, adding the rows they return to the queue. Rows are only taken
+// when a query reads past the rows worked out so far, so a query that stops
+// reading stops the recursion too.
+pub struct Recursion {
+    cte: *const Cte,
+    name: String,
+    // The first SELECT that reads the table
+    first: usize,
+    columns: Vec<ColumnDef>,
+    // The table the recursive SELECTs were prepared with, which is swapped
+    // for the row they are run on
+    placeholder: Rc<Vec<Row>>,
+    recursive: Vec<(Vec<Table>, Scope)>,
+    sort_columns: Vec<usize>,
+    // With UNION, rows that were queued before are left out
+    distinct: bool,
+    state: RefCell<RecursionState>,
+}
+
+struct RecursionState {
+    rows: Vec<Row>,
+    queue: VecDeque<Vec<Data>>,
+    seen: BTreeSet<Key>,
+    // The rows still to be left out for OFFSET, and the most rows for LIMIT
+    offset: i64,
+    limit: usize,
+}
+
+impl RecursionState {
+    fn push(&mut self, values: Vec<Data>, distinct: bool) {
+        if !distinct || self.seen.insert(Key(values.clone())) {
+            self.queue.push_back(values);
+        }
+    }
+}
+
+impl Recursion {
+    // Returns the `i`th row of the table, or None if it has fewer rows,
+    // working out rows until it has it. The context is that of a query that
+    // reads the table.
+    pub fn row(&self, i: usize, context: &Context) -> Result<Option<Row>, anyhow::Error> {
+        let Some((clause, cte)) = context.find_cte(|cte| std::ptr::eq(cte, self.cte)) else {
+            bail!("no such table: {}", self.name);
+        };
+        let Ok(mut state) = self.state.try_borrow_mut() else {
+            bail!("circular reference: {}", self.name);
+        };
+        let context = context.defining(clause).with_clause(&cte.select);
+        while state.rows.len() <= i && self.step(&mut state, &cte.select, &context)? {}
+        Ok(state.rows.get(i).cloned())
+    }
+
+    // Takes the next row from the queue, and queues the rows the recursive
+    // SELECTs return for it. With ORDER BY, the smallest row is taken first
+    // rather than the oldest. Returns false once the table has all its rows.
+    fn step(
+        &self,
+        state: &mut RecursionState,
+        select: &Select,
+        context: &Context,
+    ) -> Result<bool, anyhow::Error> {
+        if state.rows.len() >= state.limit {
+            return Ok(false);
+        }
+        let next = if select.order_by.is_empty() {
+            state.queue.pop_front()
+        } else {
+            let keys = |row: &[Data]| {
+                self.sort_columns
+                    .iter()
+                    .map(|&column| row[column].clone())
+                    .collect::<Vec<_>>()
+            };
+            // The first of the smallest rows, so that equal rows are taken
+            // in the order they were queued
+            let smallest = state
+                .queue
+                .iter()
+                .enumerate()
+                .min_by(|(_, a), (_, b)| compare_keys(&select.order_by, &keys(a), &keys(b)))
+                .map(|(i, _)| i);
+            smallest.and_then(|i| state.queue.remove(i))
+        };
+        let Some(values) = next else {
+            return Ok(false);
+        };
+        let current = Rc::new(vec![named_row(&self.columns, values)]);
+        if state.offset > 0 {
+            state.offset -= 1;
+        } else {
+            state.rows.push(current[0].clone());
+        }
+        let cores = select.compound[self.first - 1..]
+            .iter()
+            .map(|(_, core)| core);
+        for (core, (tables, scope)) in cores.zip(&self.recursive) {
+            let tables = tables
+                .iter()
+                .map(|table| table.with_rows(&self.placeholder, &current))
+                .collect();
+            for values in run_core(core, tables, scope.clone(), context.clone())? {
+                state.push(values?, self.distinct);
+            }
+        }
+        Ok(true)
+    }
+}
+
+// The table of a recursive WITH table that its recursive SELECTs read while
+// they are prepared, which is swapped for each row of the table as it's run
+struct Working {
+    cte: *const Cte,
+    columns: Vec<ColumnDef>,
+    rows: Rc<Vec<Row>>,
+    // How many times the table has been opened
+    reads: Cell<usize>,
+}
+
+// The tables of a WITH clause, with the row of the query it was nested in
+type WithClause<'a> = (Rc<Vec<Cte>>, Option<&'a Env<'a>>);
+
+// What a query runs in: the database, and for a subquery, the row of the
+// query it's nested in
+#[derive(Clone)]
+pub struct Context<'a> {
+    pub db: &'a Database,
+    pub outer: Option<&'a Env<'a>>,
+    // The WITH clauses of the queries this one is part of, innermost last
+    with: Rc<Vec<WithClause<'a>>>,
+    working: Option<Rc<Working>>,
+    // Shared by every query of a statement
+    subqueries: Rc<Subqueries>,
+    // The WITH tables that don't depend on the row of an outer query, keyed
+    // by their address. A table that is still being worked out is None.
+    ctes: Rc<RefCell<HashMap<*const Cte, Option<CteTable>>>>,
+}
+
+impl<'a> Context<'a> {
+    pub fn new(db: &'a Database) -> Self {
+        Self {
+            db,
+            outer: None,
+            with: Rc::default(),
+            working: None,
+            subqueries: Rc::default(),
+            ctes: Rc::default(),
+        }
+    }
+
+    // The context of a subquery evaluated for a row of this query
+    fn nested(&self, env: &'a Env<'a>) -> Self {
+        Self {
+            outer: Some(env),
+            ..self.clone()
+        }
+    }
+
+    // The context of a SELECT, which can read the tables of its WITH clause
+    fn with_clause(&self, select: &Select) -> Self {
+        let Some(ctes) = &select.with else {
+            return self.clone();
+        };
+        let mut with = self.with.as_ref().clone();
+        with.push((ctes.clone(), self.outer));
+        Self {
+            with: Rc::new(with),
+            ..self.clone()
+        }
+    }
+
+    // The context a subquery is prepared in to find out whether it refers
+    // to the rows of the queries it's nested in. The WITH tables that do
+    // are left out too.
+    fn uncorrelated(&self) -> Self {
+        let with = self.with.iter().take_while(|(_, outer)| outer.is_none());
+        Self {
+            outer: None,
+            with: Rc::new(with.cloned().collect()),
+            working: None,
+            ..self.clone()
+        }
+    }
+
+    // Finds the innermost table of the WITH clauses in scope that matches,
+    // along with the position of its clause
+    fn find_cte(&self, matches: impl Fn(&Cte) -> bool) -> Option<(usize, &Cte)> {
+        self.with
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(i, (ctes, _))| Some((i, ctes.iter().find(|cte| matches(cte))?)))
+    }
+
+    // The context the tables of the `i`th WITH clause are worked out in. They
+    // can read each other, and those of the clauses it's nested in.
+    fn defining(&self, i: usize) -> Self {
+        Self {
+            outer: self.with[i].1,
+            with: Rc::new(self.with[..=i].to_vec()),
+            working: None,
+            ..self.clone()
+        }
+    }
+
+    // Finds the table a WITH clause defines with a name, working out its
+    // rows the first time it's read. Returns None if no WITH clause in
+    // scope has the name.
+    pub fn cte(&self, name: &str) -> Result<Option<CteTable>, anyhow::Error> {
+        let Some((i, cte)) = self.find_cte(|cte| cte.name.eq_ignore_ascii_case(name)) else {
+            return Ok(None);
+        };
+        let key = cte as *const Cte;
+        if let Some(working) = self.working.as_ref().filter(|w| w.cte == key) {
+            working.reads.set(working.reads.get() + 1);
+            let rows = CteRows::Rows(working.rows.clone());
+            return Ok(Some((working.columns.clone(), rows)));
+        }
+        match self.ctes.borrow().get(&key) {
+            Some(Some(table)) => return Ok(Some(table.clone())),
+            Some(None) => bail!("circular reference: {}", cte.name),
+            None => {}
+        }
+        self.ctes.borrow_mut().insert(key, None);
+        let table = cte_table(cte, &self.defining(i));
+        let fixed = self.with[..=i].iter().all(|(_, outer)| outer.is_none());
+        match &table {
+            Ok(table) if fixed => self.ctes.borrow_mut().insert(key, Some(table.clone())),
+            _ => self.ctes.borrow_mut().remove(&key),
+        };
+        table.map(Some)
+    }
+}
+
+// What an expression is evaluated against: a row of each table in scope,
+// and after grouping, the values of the group's aggregate calls
+#[derive(Clone, Copy)]
+pub struct Env<'a> {
+    pub scope: &'a Scope,
+    // A missing row, as in a LEFT join without a match, is all NULL
+    pub rows: &'a [Row],
+    pub aggregates: &'a [(&'a Expr, Data)],
+    // The query the expression belongs to, which subqueries need
+    pub context: Option<&'a Context<'a>>,
+}
+
+impl<'a> Env<'a> {
+    pub fn new(scope: &'a Scope, rows: &'a [Row]) -> Self {
+        Self {
+            scope,
+            rows,
+            aggregates: &[],
+            context: None,
+        }
+    }
+
+    pub fn with_context(self, context: &'a Context<'a>) -> Self {
+        Self {
+            context: Some(context),
+            ..self
+        }
+    }
+
+    // The row of the query this one is nested in
+    fn outer(&self) -> Option<&'a Env<'a>> {
+        self.context.and_then(|context| context.outer)
+    }
+
+    // Looks a column up in the row, or else in the rows of the queries this
+    // one is nested in
+    fn column(&self, column: &ColumnRef) -> Result<Data, anyhow::Error> {
+        match (self.scope.find(column)?, self.outer()) {
+            (Some((table, name)), _) => Ok(self
+                .rows
+                .get(table)
+                .and_then(|row| row.get(name))
+                .cloned()
+                .unwrap_or(Data::Null)),
+            (None, Some(outer)) => outer.column(column),
+            (None, None) => bail!("no such column: {}", column_name(column)),
+        }
+    }
+
+    // The affinity of an expression. Only columns and casts have one, so the
+    // values of other expressions are compared as they are.
+    fn affinity(&self, expr: &Expr) -> Affinity {
+        let column = match expr {
+            Expr::Column(column) => column,
+            Expr::TableColumn { table, name } => return self.scope.tables[*table].affinities[name],
+            Expr::Cast { type_name, .. } => return cast_affinity(type_name),
+            _ => return Affinity::Blob,
+        };
+        match (self.scope.find(column), self.outer()) {
+            (Ok(Some((table, name))), _) => self.scope.tables[table].affinities[name],
+            (Ok(None), Some(outer)) => outer.affinity(expr),
+            _ => Affinity::Blob,
+        }
+    }
+
+    // The collation of an expression, which only columns have
+    fn collation(&self, expr: &Expr) -> Option<Collation> {
+        match expr {
+            Expr::Column(column) => match (self.scope.find(column), self.outer()) {
+                (Ok(Some((table, name))), _) => Some(self.scope.tables[table].collation(name)),
+                (Ok(None), Some(outer)) => outer.collation(expr),
+                _ => None,
+            },
+            Expr::TableColumn { table, name } => Some(self.scope.tables[*table].collation(name)),
+            _ => None,
+        }
+    }
+
+    // The collation two operands are compared with: that of the left one if
+    // it's a column, or else that of the right one
+    fn comparison_collation(&self, left: &Expr, right: &Expr) -> Collation {
+        self.collation(left)
+            .or_else(|| self.collation(right))
+            .unwrap_or_default()
+    }
+
+    // Returns true if an index on a column can find the rows where the
+    // column equals another expression. It can't if the comparison would
+    // convert the column's values rather than the other operand's, or
+    // compare them with a collation other than BINARY, which is the order
+    // of the indexes that are used.
+    pub fn can_look_up(&self, column: &Expr, other: &Expr) -> bool {
+        let (ca, oa) = (self.affinity(column), self.affinity(other));
+        let converts_column =
+            (oa.is_numeric() && !ca.is_numeric()) || (ca == Affinity::Blob && oa == Affinity::Text);
+        let binary = [column, other]
+            .iter()
+            .all(|expr| self.collation(expr).unwrap_or_default() == Collation::Binary);
+        !converts_column && binary
+    }
+
+    // Converts a value compared with a column the way the comparison would,
+    // so that it can be looked up in the column's index
+    pub fn lookup_value(&self, column: &Expr, other: &Expr, value: Data) -> Data {
+        let affinities = (self.affinity(column), self.affinity(other));
+        coerce(affinities, Data::Null, value).1
+    }
+
+    // Checks that the columns, functions and subqueries an expression uses
+    // exist
+    fn check(&self, expr: &Expr) -> Result<(), anyhow::Error> {
+        match expr {
+            Expr::Column(column) if self.scope.find(column)?.is_none() => match self.outer() {
+                Some(outer) => outer.check(expr)?,
+                None => bail!("no such column: {}", column_name(column)),
+            },
+            Expr::Function { name, args, .. } => functions::check(name, args)?,
+            Expr::Window { name, args, .. } => window::check(name, args)?,
+            Expr::Exists(select) => {
+                self.prepare_subquery(select)?;
+            }
+            Expr::InSelect { select, .. } | Expr::Subquery(select) => {
+                let plan = self.prepare_subquery(select)?;
+                let count = result_columns(select, &plan.scope).len();
+                if count != 1 {
+                    bail!("sub-select returns {count} columns - expected 1");
+                }
+            }
+            _ => {}
+        }
+        expr.children()
+            .into_iter()
+            .try_for_each(|child| self.check(child))
+    }
+
+    fn prepare_subquery(&self, select: &Select) -> Result<Plan, anyhow::Error> {
+        let context = self
+            .context
+            .ok_or_else(|| anyhow!("subqueries need a database"))?;
+        prepare(select, &context.nested(self))
+    }
+
+    // Runs a subquery for the row, unless its result doesn't depend on it
+    fn subquery(&self, select: &Select) -> Result<Rc<SubqueryResult>, anyhow::Error> {
+        let context = self
+            .context
+            .ok_or_else(|| anyhow!("subqueries need a database"))?;
+        let key = select as *const Select;
+        let prepared = context.subqueries.borrow().get(&key).cloned();
+        let nested = context.nested(self);
+        let plan = match &prepared {
+            Some(Prepared::Result(result)) => return Ok(result.clone()),
+            Some(Prepared::Correlated(Some(plan))) => plan.as_ref().clone(),
+            Some(Prepared::Correlated(None)) | None => prepare(select, &nested)?,
+        };
+        let affinities = result_columns(select, &plan.scope)
+            .into_iter()
+            .map(|c| c.affinity)
+            .collect();
+        let rows = run(select, plan.clone(), nested)?.collect::<Result<_, _>>()?;
+        let result = Rc::new(SubqueryResult::new(affinities, rows));
+        if prepared.is_none() {
+            // It refers to no outer rows if it can be prepared without them
+            let prepared = match prepare(select, &context.uncorrelated()) {
+                Ok(_) => Prepared::Result(result.clone()),
+                Err(_) if plan.is_reusable() => Prepared::Correlated(Some(Rc::new(plan))),
+                Err(_) => Prepared::Correlated(None),
+            };
+            context.subqueries.borrow_mut().insert(key, prepared);
+        }
+        Ok(result)
+    }
+}
+
+// Evaluates an expression against a row
+pub fn eval(expr: &Expr, env: &Env) -> Result<Data, anyhow::Error> {
+    match expr {
+        Expr::Literal(value) => Ok(value.clone()),
+        Expr::Column(column) => env.column(column),
+        Expr::TableColumn { table, name } => Ok(env
+            .rows
+            .get(*table)
+            .and_then(|row| row.get(name))
+            .cloned()
+            .unwrap_or(Data::Null)),
+        Expr::Function { name, args, .. } if aggregate::is_aggregate(name, args) => env
+            .aggregates
+            .iter()
+            .find(|(call, _)| *call == expr)
+            .map(|(_, value)| value.clone())
+            .ok_or_else(|| anyhow!("misuse of aggregate function {name}()")),
+        Expr::Function {
+            name,
+            args: FunctionArgs::List(args),
+            ..
+        } => {
+            let args = args
+                .iter()
+                .map(|arg| eval(arg, env))
+                .collect::<Result<Vec<_>, _>>()?;
+            functions::call(name, &args)
+        }
+        // A scalar function called with * has no arguments
+        Expr::Function { name, .. } => functions::call(name, &[]),
+        // Window functions are computed before the expressions that use them
+        Expr::Window { name, .. } => env
+            .aggregates
+            .iter()
+            .find(|(call, _)| *call == expr)
+            .map(|(_, value)| value.clone())
+            .ok_or_else(|| anyhow!("misuse of window function {name}()")),
+        Expr::Unary {
+            op: UnaryOp::Not,
+            expr,
+        } => Ok(match eval(expr, env)? {
+            Data::Null => Data::Null,
+            value => boolean(!is_true(&value)),
+        }),
+        Expr::Unary {
+            op: UnaryOp::Negate,
+            expr,
+        } => Ok(negate(eval(expr, env)?)),
+        Expr::Unary {
+            op: UnaryOp::Plus,
+            expr,
+        } => eval(expr, env),
+        Expr::Binary {
+            op: op @ (BinaryOp::And | BinaryOp::Or),
+            left,
+            right,
+        } => {
+            // Three-valued logic: NULL is an unknown truth value, so it only
+            // decides the result when the other side doesn't
+            let decisive = *op == BinaryOp::Or;
+            let left = truth(&eval(left, env)?);
+            if left == Some(decisive) {
+                return Ok(boolean(decisive));
+            }
+            let right = truth(&eval(right, env)?);
+            Ok(match (left, right) {
+                (_, Some(r)) if r == decisive => boolean(decisive),
+                (Some(_), Some(_)) => boolean(!decisive),
+                _ => Data::Null,
+            })
+        }
+        Expr::Binary {
+            op: BinaryOp::Concat,
+            left,
+            right,
+        } => Ok(match (eval(left, env)?, eval(right, env)?) {
+            (Data::Null, _) | (_, Data::Null) => Data::Null,
+            (l, r) => Data::Text(text_of(&l) + &text_of(&r)),
+        }),
+        Expr::Binary {
+            op: op @ (BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem),
+            left,
+            right,
+        } => Ok(arithmetic(*op, eval(left, env)?, eval(right, env)?)),
+        Expr::Binary { op, left, right } => {
+            let l = eval(left, env)?;
+            let r = eval(right, env)?;
+            let affinities = (env.affinity(left), env.affinity(right));
+            let collation = env.comparison_collation(left, right);
+            Ok(compare(*op, affinities, collation, l, r))
+        }
+        Expr::Like {
+            op,
+            negated,
+            expr,
+            pattern,
+            escape,
+        } => {
+            let value = eval(expr, env)?;
+            let pattern = eval(pattern, env)?;
+            let escape = escape.as_ref().map(|e| eval(e, env)).transpose()?;
+            if value == Data::Null || pattern == Data::Null || escape == Some(Data::Null) {
+                return Ok(Data::Null);
+            }
+            let escape = match escape.map(|e| text_of(&e)) {
+                Some(e) if e.chars().count() != 1 => {
+                    bail!("ESCAPE expression must be a single character")
+                }
+                e => e.and_then(|e| e.chars().next()),
+            };
+            let (value, pattern) = (text_of(&value), text_of(&pattern));
+            let matched = match op {
+                LikeOp::Like => pattern::like(&pattern, &value, escape),
+                LikeOp::Glob => pattern::glob(&pattern, &value),
+                LikeOp::Regexp => pattern::regexp(&pattern, &value)?,
+            };
+            Ok(boolean(matched != *negated))
+        }
+        Expr::InList {
+            negated,
+            expr,
+            list,
+        } => {
+            let value = eval(expr, env)?;
+            let mut items = Vec::new();
+            for item in list {
+                items.push((env.affinity(item), eval(item, env)?));
+            }
+            let collation = env.collation(expr).unwrap_or_default();
+            Ok(is_in(env.affinity(expr), collation, value, items, *negated))
+        }
+        Expr::InSelect {
+            negated,
+            expr,
+            select,
+        } => {
+            let value = eval(expr, env)?;
+            let collation = env.collation(expr).unwrap_or_default();
+            Ok(env
+                .subquery(select)?
+                .contains(env.affinity(expr), collation, value, *negated))
+        }
+        Expr::Exists(select) => Ok(boolean(!env.subquery(select)?.rows.is_empty())),
+        Expr::Subquery(select) => Ok(env
+            .subquery(select)?
+            .rows
+            .first()
+            .map_or(Data::Null, |row| row[0].clone())),
+        Expr::Between {
+            negated,
+            expr,
+            low,
+            high,
+        } => {
+            let value = eval(expr, env)?;
+            let l = eval(low, env)?;
+            let h = eval(high, env)?;
+            let affinity = env.affinity(expr);
+            let above = truth(&compare(
+                BinaryOp::Ge,
+                (affinity, env.affinity(low)),
+                env.comparison_collation(expr, low),
+                value.clone(),
+                l,
+            ));
+            let below = truth(&compare(
+                BinaryOp::Le,
+                (affinity, env.affinity(high)),
+                env.comparison_collation(expr, high),
+                value,
+                h,
+            ));
+            Ok(match (above, below) {
+                (Some(false), _) | (_, Some(false)) => boolean(*negated),
+                (Some(true), Some(true)) => boolean(!negated),
+                _ => Data::Null,
+            })
+        }
+        Expr::Case {
+            operand,
+            whens,
+            otherwise,
+        } => {
+            let operand = match operand {
+                Some(operand) => Some((operand, env.affinity(operand), eval(operand, env)?)),
+                None => None,
+            };
+            // The first WHEN that is true, or equal to the operand, decides
+            // the result. NULL matches nothing.
+            for (when, then) in whens {
+                let value = eval(when, env)?;
+                let matched = match &operand {
+                    Some((expr, affinity, operand)) => compare(
+                        BinaryOp::Eq,
+                        (*affinity, env.affinity(when)),
+                        env.comparison_collation(expr, when),
+                        operand.clone(),
+                        value,
+                    ),
+                    None => value,
+                };
+                if is_true(&matched) {
+                    return eval(then, env);
+                }
+            }
+            match otherwise {
+                Some(otherwise) => eval(otherwise, env),
+                None => Ok(Data::Null),
+            }
+        }
+        Expr::Cast { expr, type_name } => Ok(eval(expr, env)?.cast(cast_affinity(type_name))),
+    }
+}
+
+// The affinity a CAST converts to. Unlike a column with no declared type, a
+// CAST with no type name converts to a number.
+fn cast_affinity(type_name: &str) -> Affinity {
+    if type_name.is_empty() {
+        Affinity::Numeric
+    } else {
+        Affinity::from_declared_type(type_name)
+    }
+}
+
+// Compares two values with a comparison operator, after converting them
+// according to the affinities of the expressions they came from. Text is
+// compared with a collation.
+fn compare(
+    op: BinaryOp,
+    affinities: (Affinity, Affinity),
+    collation: Collation,
+    left: Data,
+    right: Data,
+) -> Data {
+    match (op, &left, &right) {
+        (BinaryOp::Is, Data::Null, _) | (BinaryOp::Is, _, Data::Null) => {
+            return boolean(left == right);
+        }
+        (BinaryOp::IsNot, Data::Null, _) | (BinaryOp::IsNot, _, Data::Null) => {
+            return boolean(left != right);
+        }
+        (_, Data::Null, _) | (_, _, Data::Null) => return Data::Null,
+        _ => {}
+    }
+    let (left, right) = coerce(affinities, left, right);
+    let ordering = left.collated(collation).compare(&right.collated(collation));
+    boolean(match op {
+        BinaryOp::Eq | BinaryOp::Is => ordering == Ordering::Equal,
+        BinaryOp::Ne | BinaryOp::IsNot => ordering != Ordering::Equal,
+        BinaryOp::Lt => ordering == Ordering::Less,
+        BinaryOp::Le => ordering != Ordering::Greater,
+        BinaryOp::Gt => ordering == Ordering::Greater,
+        BinaryOp::Ge => ordering != Ordering::Less,
+        _ => unreachable!(),
+    })
+}
+
+// Returns whether a value is equal to one of a list of values, each with the
+// affinity of the expression it came from. The result is NULL if nothing
+// matched but something might have.
+fn is_in(
+    affinity: Affinity,
+    collation: Collation,
+    value: Data,
+    items: impl IntoIterator<Item = (Affinity, Data)>,
+    negated: bool,
+) -> Data {
+    let mut unknown = false;
+    for (item_affinity, item) in items {
+        let affinities = (affinity, item_affinity);
+        match compare(BinaryOp::Eq, affinities, collation, value.clone(), item) {
+            Data::Null => unknown = true,
+            v if is_true(&v) => return boolean(!negated),
+            _ => {}
+        }
+    }
+    if unknown {
+        Data::Null
+    } else {
+        boolean(negated)
+    }
+}
+
+// Converts a value to text for the string operators. Blobs are read as UTF-8.
+fn text_of(value: &Data) -> String {
+    match value {
+        Data::Blob(b) => String::from_utf8_lossy(b).into_owned(),
+        value => value.to_string(),
+    }
+}
+
+// Formats a column reference the way it was written
+fn column_name(column: &ColumnRef) -> String {
+    match &column.table {
+        Some(table) => format!("{table}.{}", column.name),
+        None => column.name.clone(),
+    }
+}
+
+// Converts a value to a number for arithmetic. Text and blobs are read up to
+// the end of their longest numeric prefix.
+fn to_number(value: Data) -> Data {
+    match value {
+        Data::Text(s) => parse_numeric_prefix(&s),
+        Data::Blob(b) => parse_numeric_prefix(&String::from_utf8_lossy(&b)),
+        value => value,
+    }
+}
+
+// Negates a value. Negating the smallest integer overflows to a real.
+fn negate(value: Data) -> Data {
+    match to_number(value) {
+        Data::Integer(i) => i
+            .checked_neg()
+            .map_or(Data::Float(-(i as f64)), Data::Integer),
+        Data::Float(x) => Data::Float(-x),
+        value => value,
+    }
+}
+
+// Applies an arithmetic operator. Integer results that overflow become
+// reals, and division or remainder by zero is NULL.
+fn arithmetic(op: BinaryOp, left: Data, right: Data) -> Data {
+    let (a, b) = match (to_number(left), to_number(right)) {
+        (Data::Null, _) | (_, Data::Null) => return Data::Null,
+        (Data::Integer(a), Data::Integer(b)) => {
+            let res = match op {
+                BinaryOp::Add => a.checked_add(b),
+                BinaryOp::Sub => a.checked_sub(b),
+                BinaryOp::Mul => a.checked_mul(b),
+                _ if b == 0 => return Data::Null,
+                BinaryOp::Div => a.checked_div(b),
+                _ => Some(a.wrapping_rem(b)),
+            };
+            match res {
+                Some(i) => return Data::Integer(i),
+                None => (a as f64, b as f64),
+            }
+        }
+        (a, b) => (as_real(&a), as_real(&b)),
+    };
+    let res = match op {
+        BinaryOp::Add => a + b,
+        BinaryOp::Sub => a - b,
+        BinaryOp::Mul => a * b,
+        _ if b == 0.0 => return Data::Null,
+        BinaryOp::Div => a / b,
+        // The remainder of reals is that of their integer parts
+        _ => match (a as i64, b as i64) {
+            (_, 0) => return Data::Null,
+            (a, b) => a.wrapping_rem(b) as f64,
+        },
+    };
+    if res.is_nan() {
+        Data::Null
+    } else {
+        Data::Float(res)
+    }
+}
+
+fn as_real(value: &Data) -> f64 {
+    match value {
+        Data::Integer(i) => *i as f64,
+        Data::Float(x) => *x,
+        _ => 0.0,
+    }
+}
+
+// Lazily drops the rows that don't satisfy a WHERE clause
+#[allow(dead_code)]
+pub fn filter<'a>(
+    rows: impl Iterator<Item = Result<Row, anyhow::Error>> + 'a,
+    scope: Scope,
+    where_: Option<&'a Expr>,
+) -> impl Iterator<Item = Result<Row, anyhow::Error>> + 'a {
+    rows.filter_map(move |row| {
+        let row = match row {
+            Ok(row) => row,
+            Err(e) => return Some(Err(e)),
+        };
+        let env = Env::new(&scope, std::slice::from_ref(&row));
+        match where_.map(|w| eval(w, &env)).transpose() {
+            Ok(Some(value)) if !is_true(&value) => None,
+            Ok(_) => Some(Ok(row)),
+            Err(e) => Some(Err(e)),
+        }
+    })
+}
+
+// Evaluates the result columns of a SELECT against a row
+fn project(columns: &[ResultColumn], env: &Env) -> Result<Vec<Data>, anyhow::Error> {
+    let mut values = Vec::new();
+    for column in columns {
+        match column {
+            ResultColumn::Star(qualifier) => {
+                for (i, table) in env.scope.tables.iter().enumerate() {
+                    let columns = table.columns.iter().filter(|name| match qualifier {
+                        Some(qualifier) => table.is_named(qualifier),
+                        None => !table.merged.contains(name),
+                    });
+                    let row = env.rows.get(i);
+                    values.extend(
+                        columns.map(|name| {
+                            row.and_then(|r| r.get(name)).cloned().unwrap_or(Data::Null)
+                        }),
+                    );
+                }
+            }
+            ResultColumn::Expr { expr, .. } => values.push(eval(expr, env)?),
+        }
+    }
+    Ok(values)
+}
+
+// The result columns of a SELECT as the columns of a table. A column keeps
+// its affinity and collation when a subquery passes it on. A result column
+// that isn't a column and has no alias is named after its position, and
+// repeated names are numbered, as in id:1.
+fn result_columns(select: &Select, scope: &Scope) -> Vec<ColumnDef> {
+    let mut columns = Vec::new();
+    for column in &select.columns {
+        match column {
+            ResultColumn::Star(qualifier) => {
+                for table in &scope.tables {
+                    let names = table.columns.iter().filter(|name| match qualifier {
+                        Some(qualifier) => table.is_named(qualifier),
+                        None => !table.merged.contains(name),
+                    });
+                    for name in names {
+                        let (affinity, collation) = (table.affinities[name], table.collation(name));
+                        columns.push(column_def(name.clone(), affinity, collation));
+                    }
+                }
+            }
+            ResultColumn::Expr { expr, alias } => {
+                let (affinity, collation) = match expr {
+                    Expr::Column(column) => match scope.find(column) {
+                        Ok(Some((table, name))) => {
+                            let table = &scope.tables[table];
+                            (table.affinities[name], table.collation(name))
+                        }
+                        _ => (Affinity::Blob, Collation::Binary),
+                    },
+                    Expr::Cast { type_name, .. } => (cast_affinity(type_name), Collation::Binary),
+                    _ => (Affinity::Blob, Collation::Binary),
+                };
+                let name = match (alias, expr) {
+                    (Some(alias), _) => alias.clone(),
+                    (None, Expr::Column(column)) => column.name.clone(),
+                    (None, _) => format!("column{}", columns.len() + 1),
+                };
+                columns.push(column_def(name, affinity, collation));
+            }
+        }
+    }
+    let mut named: Vec<ColumnDef> = Vec::new();
+    for column in columns {
+        let base = column.name.clone();
+        let mut name = base.clone();
+        let mut n = 1;
+        while named.iter().any(|c| c.name.eq_ignore_ascii_case(&name)) {
+            name = format!("{base}:{n}");
+            n += 1;
+        }
+        named.push(ColumnDef { name, ..column });
+    }
+    named
+}
+
+// Describes a result column as a column of a table, which keeps the
+// collation of the column it came from
+fn column_def(name: String, affinity: Affinity, collation: Collation) -> ColumnDef {
+    let mut column = ColumnDef::new(name, affinity);
+    if collation != Collation::Binary {
+        let name = collation.name().to_string();
+        column.constraints.push(ColumnConstraint::Collate(name));
+    }
+    column
+}
+
+// Names the values of a result row after the columns of a table
+fn named_row(columns: &[ColumnDef], values: Vec<Data>) -> Row {
+    columns.iter().map(|c| c.name.clone()).zip(values).collect()
+}
+
+// Runs a subquery in a FROM clause, returning the columns and rows of the
+// table it stands for
+pub fn derived_table(
+    select: &Select,
+    context: &Context,
+) -> Result<(Vec<ColumnDef>, Vec<Row>), anyhow::Error> {
+    let plan = prepare(select, context)?;
+    let columns = result_columns(select, &plan.scope);
+    let rows = run(select, plan, context.clone())?
+        .map(|values| Ok(named_row(&columns, values?)))
+        .collect::<Result<_, anyhow::Error>>()?;
+    Ok((columns, rows))
+}
+
+// The columns of a WITH table, which may be renamed by its column list
+fn cte_columns(cte: &Cte, columns: Vec<ColumnDef>) -> Result<Vec<ColumnDef>, anyhow::Error> {
+    if cte.columns.is_empty() {
+        return Ok(columns);
+    }
+    if cte.columns.len() != columns.len() {
+        bail!(
+            "table {} has {} values for {} columns",
+            cte.name,
+            columns.len(),
+            cte.columns.len()
+        );
+    }
+    let names = cte.columns.iter().cloned();
+    Ok(names
+        .zip(columns)
+        .map(|(name, column)| ColumnDef { name, ..column })
+        .collect())
+}
+
+// Counts the tables of the FROM clause of a SELECT with a name
+fn table_reads(select: &Select, name: &str) -> usize {
+    let joined = select.joins.iter().map(|join| &join.table);
+    select
+        .from
+        .iter()
+        .chain(joined)
+        .filter(|table| matches!(table, TableRef::Table { name: n, .. } if n.eq_ignore_ascii_case(name)))
+        .count()
+}
+
+// Works out the columns and rows of a WITH table
+fn cte_table(cte: &Cte, context: &Context) -> Result<CteTable, anyhow::Error> {
+    let select = &cte.select;
+    // The table is recursive if a SELECT combined with the first one by
+    // UNION or UNION ALL reads it, but the first one doesn't
+    let first = select
+        .compound
+        .iter()
+        .position(|(op, core)| {
+            matches!(op, CompoundOp::Union | CompoundOp::UnionAll)
+                && table_reads(core, &cte.name) > 0
+        })
+        .filter(|_| table_reads(select, &cte.name) == 0);
+    if let Some(first) = first {
+        return recursive_table(cte, first + 1, context);
+    }
+    let plan = prepare(select, context)?;
+    let columns = cte_columns(cte, result_columns(select, &plan.scope))?;
+    let rows = run(select, plan, context.clone())?
+        .map(|values| Ok(named_row(&columns, values?)))
+        .collect::<Result<_, anyhow::Error>>()?;
+    Ok((columns, CteRows::Rows(Rc::new(rows))))
+}
+
+// Prepares a recursive WITH table, working out its first rows from the
+// SELECTs before the `first` one that reads the table
+fn recursive_table(cte: &Cte, first: usize, context: &Context) -> Result<CteTable, anyhow::Error> {
+    let select = &cte.select;
+    let context = &context.with_clause(select);
+    let cores = iter::once(select)
+        .chain(select.compound.iter().map(|(_, core)| core))
+        .collect::<Vec<_>>();
+    let mut plans = cores[..first]
+        .iter()
+        .map(|core| prepare_core(core, context))
+        .collect::<Result<Vec<_>, _>>()?;
+    let columns = cte_columns(cte, result_columns(select, &plans[0].scope))?;
+    let placeholder = Rc::new(Vec::new());
+    let working = Rc::new(Working {
+        cte,
+        columns: columns.clone(),
+        rows: placeholder.clone(),
+        reads: Cell::new(0),
+    });
+    let recursive_context = Context {
+        working: Some(working.clone()),
+        ..context.clone()
+    };
+    for core in &cores[first..] {
+        match table_reads(core, &cte.name) {
+            0 => bail!("circular reference: {}", cte.name),
+            1 => {}
+            _ => bail!("multiple references to recursive table: {}", cte.name),
+        }
+        working.reads.set(0);
+        plans.push(prepare_core(core, &recursive_context)?);
+        if working.reads.get() > 1 {
+            bail!("multiple recursive references: {}", cte.name);
+        }
+        if !core.group_by.is_empty() || !aggregate_calls(core).is_empty() {
+            bail!("recursive aggregate queries not supported");
+        }
+    }
+    let plan = compound_plan(select, plans)?;
+
+    let limit = match &select.limit {
+        Some(limit) => usize::try_from(eval_limit(limit, context)?).unwrap_or(usize::MAX),
+        None => usize::MAX,
+    };
+    let offset = match &select.offset {
+        Some(offset) => eval_limit(offset, context)?.max(0),
+        None => 0,
+    };
+    let Plan {
+        tables,
+        scope,
+        compound,
+        sort_columns,
+    } = plan;
+    let mut members =
+        iter::once((tables, scope)).chain(compound.into_iter().map(|p| (p.tables, p.scope)));
+    let mut initial = Vec::new();
+    for (i, (tables, scope)) in members.by_ref().take(first).enumerate() {
+        let rows =
+            run_core(cores[i], tables, scope, context.clone())?.collect::<Result<Vec<_>, _>>()?;
+        initial = match i {
+            0 => rows,
+            _ => combine(select.compound[i - 1].0, initial, rows),
+        };
+    }
+    let recursive = members.collect();
+
+    let mut state = RecursionState {
+        rows: Vec::new(),
+        queue: VecDeque::new(),
+        seen: BTreeSet::new(),
+        offset,
+        limit,
+    };
+    let distinct = select.compound[first - 1].0 == CompoundOp::Union;
+    for values in initial {
+        state.push(values, distinct);
+    }
+    let recursion = Recursion {
+        cte,
+        name: cte.name.clone(),
+        first,
+        columns: columns.clone(),
+        placeholder,
+        recursive,
+        sort_columns,
+        distinct,
+        state: RefCell::new(state),
+    };
+    Ok((columns, CteRows::Recursive(Rc::new(recursion))))
+}
+
+// A SELECT that is ready to run: its tables, and the scope of their columns,
+// along with the plans of the SELECTs combined with it
+#[derive(Clone)]
+struct Plan {
+    tables: Vec<Table>,
+    scope: Scope,
+    compound: Vec<Plan>,
+    // The result columns that the ORDER BY terms of a compound SELECT sort by
+    sort_columns: Vec<usize>,
+}
+
+impl Plan {
+    // Returns true if the plan can be run again for another row of an outer
+    // query. The rows of subqueries and WITH tables may depend on the row.
+    fn is_reusable(&self) -> bool {
+        self.tables.iter().all(Table::is_stored) && self.compound.iter().all(Plan::is_reusable)
+    }
+}
+
+// Opens the tables of a SELECT and checks it, along with the SELECTs
+// combined with it
+fn prepare(select: &Select, context: &Context) -> Result<Plan, anyhow::Error> {
+    let context = &context.with_clause(select);
+    let cores = iter::once(select).chain(select.compound.iter().map(|(_, core)| core));
+    let plans = cores
+        .map(|core| prepare_core(core, context))
+        .collect::<Result<_, _>>()?;
+    compound_plan(select, plans)
+}
+
+// Opens the tables of a SELECT without the SELECTs combined with it, and
+// checks it
+fn prepare_core(select: &Select, context: &Context) -> Result<Plan, anyhow::Error> {
+    let (tables, scope) = match &select.from {
+        Some(_) => join::open(select, context)?,
+        None => (Vec::new(), Scope::default()),
+    };
+    check_select(select, &Env::new(&scope, &[]).with_context(context))?;
+    Ok(Plan {
+        tables,
+        scope,
+        compound: Vec::new(),
+        sort_columns: Vec::new(),
+    })
+}
+
+// Combines the plans of the SELECTs of a compound SELECT, in order, into
+// the plan of the first one, checking that they have as many columns
+fn compound_plan(select: &Select, plans: Vec<Plan>) -> Result<Plan, anyhow::Error> {
+    let mut plans = plans.into_iter();
+    let Some(mut plan) = plans.next() else {
+        bail!("no SELECT to prepare");
+    };
+    let count = result_columns(select, &plan.scope).len();
+    for ((op, core), core_plan) in select.compound.iter().zip(plans) {
+        if result_columns(core, &core_plan.scope).len() != count {
+            bail!(
+                "SELECTs to the left and right of {} do not have the same number of result columns",
+                op.name()
+            );
+        }
+        plan.compound.push(core_plan);
+    }
+    if !select.compound.is_empty() {
+        plan.sort_columns = sort_columns(select, &plan)?;
+    }
+    Ok(plan)
+}
+
+// Finds the result columns that the ORDER BY terms of a compound SELECT
+// refer to, by position, by name, or as the same expression as a result
+// column of one of the SELECTs
+fn sort_columns(select: &Select, plan: &Plan) -> Result<Vec<usize>, anyhow::Error> {
+    let cores = iter::once(select).chain(select.compound.iter().map(|(_, core)| core));
+    let scopes = iter::once(&plan.scope).chain(plan.compound.iter().map(|plan| &plan.scope));
+    let names = cores
+        .clone()
+        .zip(scopes)
+        .map(|(core, scope)| result_columns(core, scope))
+        .collect::<Vec<_>>();
+    let count = names[0].len();
+    let mut columns = Vec::new();
+    for (i, term) in select.order_by.iter().enumerate() {
+        let column = match &term.expr {
+            Expr::Literal(Data::Integer(n)) => match (*n as usize).checked_sub(1) {
+                Some(column) if *n > 0 && column < count => Some(column),
+                _ => bail!(
+                    "{} ORDER BY term out of range - should be between 1 and {count}",
+                    ordinal(i + 1)
+                ),
+            },
+            Expr::Column(column) => names.iter().find_map(|names| {
+                names
+                    .iter()
+                    .position(|c| c.name.eq_ignore_ascii_case(&column.name))
+            }),
+            expr => cores.clone().find_map(|core| {
+                let no_stars = core
+                    .columns
+                    .iter()
+                    .all(|c| matches!(c, ResultColumn::Expr { .. }));
+                core.columns
+                    .iter()
+                    .position(|c| matches!(c, ResultColumn::Expr { expr: e, .. } if e == expr))
+                    .filter(|_| no_stars)
+            }),
+        };
+        match column {
+            Some(column) => columns.push(column),
+            None => bail!(
+                "{} ORDER BY term does not match any column in the result set",
+                ordinal(i + 1)
+            ),
+        }
+    }
+    Ok(columns)
+}
+
+// Runs a prepared SELECT
+fn run<'a>(
+    select: &'a Select,
+    plan: Plan,
+    context: Context<'a>,
+) -> Result<Rows<'a>, anyhow::Error> {
+    let context = context.with_clause(select);
+    if !select.compound.is_empty() {
+        return compound(select, plan, context);
+    }
+    run_core(select, plan.tables, plan.scope, context)
+}
+
+// Runs a SELECT without the SELECTs combined with it
+fn run_core<'a>(
+    select: &'a Select,
+    tables: Vec<Table>,
+    scope: Scope,
+    context: Context<'a>,
+) -> Result<Rows<'a>, anyhow::Error> {
+    let where_ = select
+        .where_
+        .as_ref()
+        .map(|expr| resolve_aliases(&select.columns, &scope, expr));
+    // Without FROM, the result columns are evaluated once, on an empty row
+    if tables.is_empty() {
+        let env = Env::new(&scope, &[]).with_context(&context);
+        let keep = match &where_ {
+            Some(where_) => is_true(&eval(where_, &env)?),
+            None => true,
+        };
+        let rows = keep.then(|| Ok(JoinedRow::new()));
+        return finish(select, scope, context, true, Box::new(rows.into_iter()));
+    }
+    let in_rowid_order = is_rowid_order(select, &scope, &tables[0].columns);
+    let rows = join::read(&scope, tables, where_.as_ref(), context.clone())?;
+    finish(select, scope, context, in_rowid_order, rows)
+}
+
+// Runs a compound SELECT. The rows of each SELECT are combined with the rows
+// before them, from left to right. UNION, INTERSECT and EXCEPT leave out
+// repeated rows and return the rest in sorted order, while UNION ALL keeps
+// every row where it is. The combined rows are then sorted and limited.
+fn compound<'a>(
+    select: &'a Select,
+    plan: Plan,
+    context: Context<'a>,
+) -> Result<Rows<'a>, anyhow::Error> {
+    let mut rows = run_core(select, plan.tables, plan.scope, context.clone())?
+        .collect::<Result<Vec<_>, _>>()?;
+    for ((op, core), plan) in select.compound.iter().zip(plan.compound) {
+        let right = run(core, plan, context.clone())?.collect::<Result<Vec<_>, _>>()?;
+        rows = combine(*op, rows, right);
+    }
+    if !select.order_by.is_empty() {
+        let keys = |row: &[Data]| {
+            plan.sort_columns
+                .iter()
+                .map(|&column| row[column].clone())
+                .collect::<Vec<_>>()
+        };
+        rows.sort_by(|a, b| compare_keys(&select.order_by, &keys(a), &keys(b)));
+    }
+    limit_rows(select, &context, Box::new(rows.into_iter().map(Ok)))
+}
+
+// Combines the rows of a SELECT with the rows before them
+fn combine(op: CompoundOp, mut rows: Vec<Vec<Data>>, right: Vec<Vec<Data>>) -> Vec<Vec<Data>> {
+    match op {
+        CompoundOp::UnionAll => {
+            rows.extend(right);
+            rows
+        }
+        // Of rows that are equal but not the same, like 1 and 1.0, the last
+        // one read is kept
+        CompoundOp::Union => {
+            let mut set = BTreeSet::new();
+            for row in rows.into_iter().chain(right) {
+                set.replace(Key(row));
+            }
+            set.into_iter().map(|key| key.0).collect()
+        }
+        CompoundOp::Intersect | CompoundOp::Except => {
+            let right = right.into_iter().map(Key).collect::<BTreeSet<_>>();
+            let left = rows.into_iter().map(Key).collect::<BTreeSet<_>>();
+            let keep = op == CompoundOp::Intersect;
+            left.into_iter()
+                .filter(|key| right.contains(key) == keep)
+                .map(|key| key.0)
+                .collect()
+        }
+    }
+}
+
+// Checks the tables, columns and functions a SELECT refers to, so that
+// mistakes are reported even if there are no rows
+fn check_select(select: &Select, env: &Env) -> Result<(), anyhow::Error> {
+    for column in &select.columns {
+        match column {
+            ResultColumn::Star(None) if select.from.is_none() => bail!("no tables specified"),
+            ResultColumn::Star(Some(name)) if !env.scope.has_table(name) => {
+                bail!("no such table: {name}")
+            }
+            ResultColumn::Star(qualifier) => env.scope.check_star(qualifier.as_deref())?,
+            ResultColumn::Expr { expr, .. } => env.check(expr)?,
+        }
+    }
+    for join in &select.joins {
+        if let Some(JoinConstraint::On(on)) = &join.constraint {
+            env.check(on)?;
+        }
+    }
+    let where_ = select
+        .where_
+        .as_ref()
+        .map(|expr| resolve_aliases(&select.columns, env.scope, expr));
+    let having = select
+        .having
+        .as_ref()
+        .map(|expr| resolve_aliases(&select.columns, env.scope, expr));
+    if let Some(where_) = &where_ {
+        env.check(where_)?;
+        let mut calls = Vec::new();
+        aggregate::collect_aggregates(where_, &mut calls);
+        if let Some(Expr::Function { name, .. }) = calls.first() {
+            bail!("misuse of aggregate function {name}()");
+        }
+    }
+    if let Some(having) = &having {
+        env.check(having)?;
+    }
+    let terms = select.group_by.iter();
+    for expr in terms.chain(own_order_by(select).iter().map(|term| &term.expr)) {
+        env.check(resolve_alias(&select.columns, expr))?;
+    }
+    // Window functions are computed after the rows are filtered and grouped
+    let ons = select
+        .joins
+        .iter()
+        .filter_map(|join| match &join.constraint {
+            Some(JoinConstraint::On(on)) => Some(on),
+            _ => None,
+        });
+    let filters = ons.chain(&where_).chain(&select.group_by).chain(&having);
+    for expr in filters {
+        let mut calls = Vec::new();
+        window::collect_windows(expr, &mut calls);
+        if let Some(Expr::Window { name, .. }) = calls.first() {
+            bail!("misuse of window function {name}()");
+        }
+    }
+    for (_, window) in &select.windows {
+        let terms = window.order_by.iter().map(|term| &term.expr);
+        for expr in window.partition_by.iter().chain(terms) {
+            env.check(expr)?;
+        }
+    }
+    for call in window::window_calls(select) {
+        if let Expr::Window { window, .. } = call {
+            window::clauses(window, select)?;
+        }
+    }
+    Ok(())
+}
+
+// Returns true if the ORDER BY clause of a SELECT sorts by the ascending
+// rowid of the first table, which is the order rows are read in, so that
+// sorting can be skipped
+fn is_rowid_order(select: &Select, scope: &Scope, columns: &[ColumnDef]) -> bool {
+    let term = match own_order_by(select) {
+        [] => return true,
+        [term] if !term.descending => resolve_alias(&select.columns, &term.expr),
+        _ => return false,
+    };
+    match term {
+        Expr::Column(column) => match scope.resolve(column) {
+            Ok((0, name)) => {
+                columns.iter().any(|c| c.ipk && c.name == name)
+                    || (name == "rowid" && !columns.iter().any(|c| c.name == "rowid"))
+            }
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+// The ORDER BY terms that sort the rows of a SELECT. Those of a compound
+// SELECT sort the combined rows instead.
+pub fn own_order_by(select: &Select) -> &[OrderingTerm] {
+    if select.compound.is_empty() {
+        &select.order_by
+    } else {
+        &[]
+    }
+}
+
+// Orders two lists of sort keys by the terms of an ORDER BY clause
+pub fn compare_keys(order_by: &[OrderingTerm], a: &[Data], b: &[Data]) -> Ordering {
+    for ((term, a), b) in order_by.iter().zip(a).zip(b) {
+        let ordering = match (a, b, term.nulls) {
+            (Data::Null, Data::Null, _) => Ordering::Equal,
+            (Data::Null, _, Some(NullsOrder::Last)) => Ordering::Greater,
+            (_, Data::Null, Some(NullsOrder::Last)) => Ordering::Less,
+            (Data::Null, _, Some(NullsOrder::First)) => Ordering::Less,
+            (_, Data::Null, Some(NullsOrder::First)) => Ordering::Greater,
+            _ if term.descending => b.compare(a),
+            _ => a.compare(b),
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
+// Returns the English ordinal of a number, as in 1st or 22nd
+fn ordinal(n: usize) -> String {
+    let suffix = match (n % 10, n % 100) {
+        (_, 11..=13) => "th",
+        (1, _) => "st",
+        (2, _) => "nd",
+        (3, _) => "rd",
+        _ => "th",
+    };
+    format!("{n}{suffix}")
+}
+
+// Finds the expression of the result column with a term's name as its
+// alias, if the term is a bare name
+fn resolve_alias<'a>(columns: &'a [ResultColumn], term: &'a Expr) -> &'a Expr {
+    let Expr::Column(ColumnRef { table: None, name }) = term else {
+        return term;
+    };
+    columns
+        .iter()
+        .find_map(|column| match column {
+            ResultColumn::Expr {
+                expr,
+                alias: Some(alias),
+            } if alias.eq_ignore_ascii_case(name) => Some(expr),
+            _ => None,
+        })
+        .unwrap_or(term)
+}
+
+// Replaces the bare names in an expression that aren't columns of the FROM
+// tables with the expressions of the result columns they are aliases of, as
+// SQLite does in WHERE and HAVING. The names in those expressions aren't
+// aliases themselves.
+fn resolve_aliases(columns: &[ResultColumn], scope: &Scope, expr: &Expr) -> Expr {
+    let mut expr = expr.clone();
+    let mut pending = vec![&mut expr];
+    while let Some(expr) = pending.pop() {
+        if let Expr::Column(column) = expr {
+            if matches!(scope.find(column), Ok(None)) {
+                *expr = resolve_alias(columns, expr).clone();
+            }
+            continue;
+        }
+        pending.extend(expr.children_mut());
+    }
+    expr
+}
+
+// Computes the sort keys of a row. An integer literal term refers to a
+// result column by its position, a name can refer to one by its alias, and
+// any other term is evaluated on the row. A term that is a column sorts by
+// the column's collation.
+fn sort_keys(select: &Select, env: &Env, values: &[Data]) -> Result<Vec<Data>, anyhow::Error> {
+    own_order_by(select)
+        .iter()
+        .enumerate()
+        .map(|(i, term)| match &term.expr {
+            Expr::Literal(Data::Integer(n)) => match values.get((*n as usize).wrapping_sub(1)) {
+                Some(value) if *n > 0 => {
+                    // Positions only match the result columns if none is a *
+                    let column = select.columns.get(*n as usize - 1).filter(|_| {
+                        !select
+                            .columns
+                            .iter()
+                            .any(|c| matches!(c, ResultColumn::Star(_)))
+                    });
+                    let collation = match column {
+                        Some(ResultColumn::Expr { expr, .. }) => env.collation(expr),
+                        _ => None,
+                    };
+                    Ok(value.clone().collated(collation.unwrap_or_default()))
+                }
+                _ => bail!(
+                    "{} ORDER BY term out of range - should be between 1 and {}",
+                    ordinal(i + 1),
+                    values.len()
+                ),
+            },
+            expr => {
+                let expr = resolve_alias(&select.columns, expr);
+                let collation = env.collation(expr).unwrap_or_default();
+                Ok(eval(expr, env)?.collated(collation))
+            }
+        })
+        .collect()
+}
+
+// Evaluates a LIMIT or OFFSET expression, which must be an integer
+fn eval_limit(expr: &Expr, context: &Context) -> Result<i64, anyhow::Error> {
+    let scope = Scope::default();
+    let env = Env::new(&scope, &[]).with_context(context);
+    match eval(expr, &env)?.apply_affinity(Affinity::Integer) {
+        Data::Integer(n) => Ok(n),
+        _ => bail!("datatype mismatch"),
+    }
+}
+
+// Applies the LIMIT and OFFSET of a SELECT to its rows. A negative limit
+// means no limit. Rows are pulled lazily, so reaching the limit stops the scan.
+fn limit_rows<'a>(
+    select: &Select,
+    context: &Context,
+    rows: Rows<'a>,
+) -> Result<Rows<'a>, anyhow::Error> {
+    let Some(limit) = &select.limit else {
+        return Ok(rows);
+    };
+    let limit = usize::try_from(eval_limit(limit, context)?).unwrap_or(usize::MAX);
+    let mut offset = match &select.offset {
+        Some(offset) => eval_limit(offset, context)?.max(0),
+        None => 0,
+    };
+    // Errors aren't skipped, so that they aren't hidden by the offset
+    let rows = rows.filter(move |row| {
+        if row.is_ok() && offset > 0 {
+            offset -= 1;
+            return false;
+        }
+        true
+    });
+    Ok(Box::new(rows.take(limit)))
+}
+
+// Collects the aggregate calls in the result columns, HAVING and ORDER BY
+// of a SELECT
+fn aggregate_calls(select: &Select) -> Vec<&Expr> {
+    let mut calls = Vec::new();
+    for column in &select.columns {
+        if let ResultColumn::Expr { expr, .. } = column {
+            aggregate::collect_aggregates(expr, &mut calls);
+        }
+    }
+    if let Some(having) = &select.having {
+        aggregate::collect_aggregates(having, &mut calls);
+    }
+    for term in own_order_by(select) {
+        aggregate::collect_aggregates(&term.expr, &mut calls);
+    }
+    for (_, window) in &select.windows {
+        let terms = window.order_by.iter().map(|term| &term.expr);
+        for expr in window.partition_by.iter().chain(terms) {
+            aggregate::collect_aggregates(expr, &mut calls);
+        }
+    }
+    calls
+}
+
+// The result values of a row, along with its ORDER BY keys
+type SortedRow = (Vec<Data>, Vec<Data>);
+
+// The rows of one group, summarized by the accumulators of the aggregate
+// calls, and the row that bare columns take their values from
+struct Group {
+    row: Option<JoinedRow>,
+    accumulators: Vec<Accumulator>,
+}
+
+// Groups rows by the GROUP BY expressions and computes the aggregates of each
+// group, returning the groups that pass HAVING, in group order
+fn aggregate<'a>(
+    select: &'a Select,
+    scope: &Scope,
+    context: &Context,
+    rows: impl Iterator<Item = Result<JoinedRow, anyhow::Error>>,
+) -> Result<Vec<SourceRow<'a>>, anyhow::Error> {
+    let calls = aggregate_calls(select);
+    let new_group = || Group {
+        row: None,
+        accumulators: calls
+            .iter()
+            .map(|call| match call {
+                Expr::Function { name, distinct, .. } => Accumulator::new(name, *distinct),
+                _ => unreachable!(),
+            })
+            .collect(),
+    };
+    // With exactly one call to min or max, bare columns come from the row
+    // that holds the minimum or maximum. Otherwise they come from the last row.
+    let extremes = calls
+        .iter()
+        .enumerate()
+        .filter(|(_, call)| {
+            matches!(call, Expr::Function { name, .. }
+                if name.eq_ignore_ascii_case("min") || name.eq_ignore_ascii_case("max"))
+        })
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+    let extreme = match extremes[..] {
+        [i] => Some(i),
+        _ => None,
+    };
+    // A GROUP BY term that is an integer refers to a result column, and so
+    // does one that names a result column's alias rather than a column
+    let group_by = select
+        .group_by
+        .iter()
+        .map(|expr| match expr {
+            Expr::Column(column) if scope.resolve(column).is_err() => {
+                Ok(resolve_alias(&select.columns, expr))
+            }
+            Expr::Literal(Data::Integer(n)) => {
+                match select.columns.get((*n as usize).wrapping_sub(1)) {
+                    Some(ResultColumn::Expr { expr, .. }) if *n > 0 => Ok(expr),
+                    _ => bail!(
+                        "GROUP BY term out of range - should be between 1 and {}",
+                        select.columns.len()
+                    ),
+                }
+            }
+            expr => Ok(expr),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut groups = BTreeMap::new();
+    for row in rows {
+        let row = row?;
+        let env = Env::new(scope, &row).with_context(context);
+        let key = Key(group_by
+            .iter()
+            .map(|expr| Ok(eval(expr, &env)?.collated(env.collation(expr).unwrap_or_default())))
+            .collect::<Result<_, anyhow::Error>>()?);
+        let group = groups.entry(key).or_insert_with(new_group);
+        let mut take_row = group.row.is_none() || extreme.is_none();
+        for (i, (call, accumulator)) in calls.iter().zip(&mut group.accumulators).enumerate() {
+            let args = match call {
+                Expr::Function {
+                    args: FunctionArgs::List(args),
+                    ..
+                } => args
+                    .iter()
+                    .map(|arg| eval(arg, &env))
+                    .collect::<Result<Vec<_>, _>>()?,
+                _ => Vec::new(),
+            };
+            if accumulator.step(&args)? && extreme == Some(i) {
+                take_row = true;
+            }
+        }
+        if take_row {
+            group.row = Some(row);
+        }
+    }
+    // Without GROUP BY, there is one group even if there are no rows
+    if groups.is_empty() && select.group_by.is_empty() {
+        groups.insert(Key(Vec::new()), new_group());
+    }
+
+    let having = select
+        .having
+        .as_ref()
+        .map(|expr| resolve_aliases(&select.columns, scope, expr));
+    let mut res = Vec::new();
+    for group in groups.into_values() {
+        let aggregates = calls
+            .iter()
+            .zip(&group.accumulators)
+            .map(|(call, accumulator)| (*call, accumulator.finish()))
+            .collect::<Vec<_>>();
+        let row = group.row.unwrap_or_default();
+        if let Some(having) = &having {
+            let env = Env {
+                scope,
+                rows: &row,
+                aggregates: &aggregates,
+                context: Some(context),
+            };
+            if !is_true(&eval(having, &env)?) {
+                continue;
+            }
+        }
+        res.push((row, aggregates));
+    }
+    Ok(res)
+}
+
+// Turns the filtered rows of a SELECT into its result rows. Unless the rows
+// are already in order, they are all read and sorted before the first result.
+// The rows of a SELECT in a compound SELECT are neither sorted nor limited,
+// since that happens to the combined rows.
+fn finish<'a>(
+    select: &'a Select,
+    scope: Scope,
+    context: Context<'a>,
+    in_order: bool,
+    rows: JoinedRows<'a>,
+) -> Result<Rows<'a>, anyhow::Error> {
+    let grouped = !select.group_by.is_empty() || select.having.is_some();
+    let aggregated = grouped || !aggregate_calls(select).is_empty();
+    let order_by = own_order_by(select);
+    // The collations that DISTINCT compares the result columns with
+    let collations = select.distinct.then(|| {
+        result_columns(select, &scope)
+            .iter()
+            .map(|c| {
+                c.collation()
+                    .and_then(Collation::from_name)
+                    .unwrap_or_default()
+            })
+            .collect()
+    });
+    let rows: Rows<'a> = if aggregated || !window::window_calls(select).is_empty() {
+        let mut rows = if aggregated {
+            aggregate(select, &scope, &context, rows)?
+        } else {
+            rows.map(|row| Ok((row?, Vec::new())))
+                .collect::<Result<_, anyhow::Error>>()?
+        };
+        window::apply(select, &scope, &context, &mut rows)?;
+        let mut results = Vec::new();
+        for (row, aggregates) in &rows {
+            let env = Env {
+                scope: &scope,
+                rows: row,
+                aggregates,
+                context: Some(&context),
+            };
+            let values = project(&select.columns, &env)?;
+            results.push((sort_keys(select, &env, &values)?, values));
+        }
+        sorted(order_by, results)
+    } else if in_order {
+        let context = context.clone();
+        Box::new(rows.map(move |row| {
+            let row = row?;
+            let env = Env::new(&scope, &row).with_context(&context);
+            project(&select.columns, &env)
+        }))
+    } else {
+        let mut results = Vec::new();
+        for row in rows {
+            let row = row?;
+            let env = Env::new(&scope, &row).with_context(&context);
+            let values = project(&select.columns, &env)?;
+            results.push((sort_keys(select, &env, &values)?, values));
+        }
+        sorted(order_by, results)
+    };
+    let rows = match collations {
+        Some(collations) => distinct(rows, collations),
+        None => rows,
+    };
+    if select.compound.is_empty() {
+        limit_rows(select, &context, rows)
+    } else {
+        Ok(rows)
+    }
+}
+
+// Sorts rows by their sort keys. The sort is stable, so rows with equal keys
+// stay in the order they were read.
+fn sorted<'a>(order_by: &[OrderingTerm], mut rows: Vec<SortedRow>) -> Rows<'a> {
+    if !order_by.is_empty() {
+        rows.sort_by(|(a, _), (b, _)| compare_keys(order_by, a, b));
+    }
+    Box::new(rows.into_iter().map(|(_, values)| Ok(values)))
+}
+
+// Lazily leaves out rows equal to an earlier row, as SELECT DISTINCT does,
+// comparing each column with its collation
+fn distinct(rows: Rows, collations: Vec<Collation>) -> Rows {
+    let mut seen = BTreeSet::new();
+    Box::new(rows.filter(move |row| match row {
+        Ok(values) => {
+            let key = values.iter().zip(&collations);
+            seen.insert(Key(key.map(|(v, &c)| v.clone().collated(c)).collect()))
+        }
+        Err(_) => true,
+    }))
+}
+
+// Executes a statement, returning its rows
+pub fn execute<'a>(db: &'a Database, statement: &'a Statement) -> Result<Rows<'a>, anyhow::Error> {
+    match statement {
+        Statement::Select(select) => {
+            let context = Context::new(db);
+            let plan = prepare(select, &context)?;
+            run(select, plan, context)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::DatabaseOptions;
+    use crate::sql_parser::parse_statement;
+
+    fn query(sql: &str) -> Result<Vec<Vec<Data>>, anyhow::Error> {
+        query_in("sample.db", sql)
+    }
+
+    fn query_in(path: &str, sql: &str) -> Result<Vec<Vec<Data>>, anyhow::Error> {
+        let db = Database::open(path, DatabaseOptions::default())?;
+        let statement = parse_statement(sql)?;
+        let rows = execute(&db, &statement)?.collect();
+        rows
+    }
+
+    // Runs a query and returns the values of its first result column
+    fn column(sql: &str) -> Vec<Data> {
+        column_in("sample.db", sql)
+    }
+
+    fn column_in(path: &str, sql: &str) -> Vec<Data> {
+        query_in(path, sql)
+            .unwrap()
+            .into_iter()
+            .map(|row| row[0].clone())
+            .collect()
+    }
+
+    // The first result column of a query on indexes.db
+    fn ids(sql: &str) -> Vec<Data> {
+        column_in("indexes.db", sql)
+    }
+
+    fn text(s: &str) -> Data {
+        Data::Text(s.to_string())
+    }
+
+    fn integers(values: &[i64]) -> Vec<Data> {
+        values.iter().map(|&n| Data::Integer(n)).collect()
+    }
+
+    #[test]
+    fn test_select_where() {
+        assert_eq!(
+            query("SELECT id, name FROM apples WHERE color = 'Red'").unwrap(),
+            vec![vec![Data::Integer(2), text("Fuji")]]
+        );
+        assert_eq!(
+            query("select count(*) from apples").unwrap(),
+            vec![vec![Data::Integer(4)]]
+        );
+    }
+
+    #[test]
+    fn test_boolean_where() {
+        assert_eq!(
+            query("select name from apples where color >= 'Red' and not (name < 'G' or name = 'Fuji')")
+                .unwrap(),
+            vec![vec![text("Golden Delicious")]]
+        );
+        assert_eq!(
+            query("select count(*) from apples where color <> 'Red' or name != 'Fuji'").unwrap(),
+            vec![vec![Data::Integer(3)]]
+        );
+    }
+
+    #[test]
+    fn test_three_valued_logic() {
+        let scope = Scope::default();
+        let env = Env::new(&scope, &[]);
+        let eval_where =
+            |sql: &str| match parse_statement(&format!("select a from t where {sql}")).unwrap() {
+                Statement::Select(select) => eval(&select.where_.unwrap(), &env).unwrap(),
+            };
+        // Comparing with NULL is unknown, which AND and OR only propagate
+        // when the other side doesn't decide the result
+        let null = Expr::Literal(Data::Null);
+        let t = Expr::binary(
+            BinaryOp::Eq,
+            Expr::Literal(text("a")),
+            Expr::Literal(text("a")),
+        );
+        let f = Expr::binary(
+            BinaryOp::Eq,
+            Expr::Literal(text("a")),
+            Expr::Literal(text("b")),
+        );
+        let cases = [
+            (BinaryOp::And, null.clone(), f.clone(), Data::Integer(0)),
+            (BinaryOp::And, t.clone(), null.clone(), Data::Null),
+            (BinaryOp::Or, null.clone(), t.clone(), Data::Integer(1)),
+            (BinaryOp::Or, f.clone(), null.clone(), Data::Null),
+            (BinaryOp::Eq, null.clone(), null.clone(), Data::Null),
+        ];
+        for (op, left, right, expected) in cases {
+            assert_eq!(
+                eval(&Expr::binary(op, left, right), &env).unwrap(),
+                expected
+            );
+        }
+        let not_null = Expr::Unary {
+            op: UnaryOp::Not,
+            expr: Box::new(null),
+        };
+        assert_eq!(eval(&not_null, &env).unwrap(), Data::Null);
+        assert_eq!(eval_where("not 'a' = 'b'"), Data::Integer(1));
+    }
+
+    #[test]
+    fn test_typed_literals() {
+        let names = |sql: &str| column(&format!("select name from apples where {sql}"));
+        assert_eq!(names("id = 2"), vec![text("Fuji")]);
+        assert_eq!(names("id > 2.5 and id < 4.0"), vec![text("Honeycrisp")]);
+        assert_eq!(names("id >= -1 and name is null"), vec![]);
+        assert_eq!(names("color = x'526564'"), vec![]);
+        // The integer column converts text that looks like a number
+        assert_eq!(names("id = ' 3 '"), vec![text("Honeycrisp")]);
+        assert_eq!(names("id = '3x'"), vec![]);
+        // The text column converts numbers to text
+        assert_eq!(names("name = 3"), vec![]);
+        assert_eq!(
+            names("null is null and id is not 1 and id <= 2"),
+            vec![text("Fuji")]
+        );
+    }
+
+    #[test]
+    fn test_membership_operators() {
+        let names = |sql: &str| column(&format!("select name from apples where {sql}"));
+        assert_eq!(
+            names("name like 'g%'"),
+            vec![text("Granny Smith"), text("Golden Delicious")]
+        );
+        assert_eq!(names("name glob 'G*'"), names("name like 'g%'"));
+        assert_eq!(names("name glob 'g*'"), vec![]);
+        assert_eq!(
+            names("color not like '%red%'"),
+            vec![text("Granny Smith"), text("Golden Delicious")]
+        );
+        assert_eq!(
+            names("id in (2, '4', null)"),
+            vec![text("Fuji"), text("Golden Delicious")]
+        );
+        assert_eq!(names("id not in (1, 2, 3, null)"), vec![]);
+        assert_eq!(
+            names("id between 2 and 3"),
+            vec![text("Fuji"), text("Honeycrisp")]
+        );
+        assert_eq!(
+            names("id not between 2 and 3"),
+            vec![text("Granny Smith"), text("Golden Delicious")]
+        );
+        assert_eq!(
+            names("name regexp '^[FH]'"),
+            vec![text("Fuji"), text("Honeycrisp")]
+        );
+        assert!(query("select name from apples where name like 'a' escape 'xy'").is_err());
+    }
+
+    #[test]
+    fn test_order_by_and_limit() {
+        let names = |sql: &str| column(&format!("select name from apples {sql}"));
+        assert_eq!(
+            names("order by color desc limit 2"),
+            vec![text("Golden Delicious"), text("Fuji")]
+        );
+        assert_eq!(
+            names("order by name like 'G%', 1 desc"),
+            vec![
+                text("Honeycrisp"),
+                text("Fuji"),
+                text("Granny Smith"),
+                text("Golden Delicious")
+            ]
+        );
+        assert_eq!(
+            names("order by id limit 1 offset 2"),
+            vec![text("Honeycrisp")]
+        );
+        assert_eq!(
+            names("order by rowid desc limit 1, 1"),
+            vec![text("Honeycrisp")]
+        );
+        assert_eq!(names("limit -1 offset 3"), vec![text("Golden Delicious")]);
+        assert_eq!(names("where id > 10 order by name nulls last"), vec![]);
+        assert!(query("select name from apples order by 2").is_err());
+        assert!(query("select name from apples limit 'x'").is_err());
+    }
+
+    #[test]
+    fn test_nulls_order() {
+        let order_by = |descending, nulls| {
+            vec![OrderingTerm {
+                expr: Expr::Literal(Data::Null),
+                descending,
+                nulls,
+            }]
+        };
+        let null = [Data::Null];
+        let one = [Data::Integer(1)];
+        // NULLs are the smallest values unless NULLS FIRST or LAST says otherwise
+        assert_eq!(
+            compare_keys(&order_by(false, None), &null, &one),
+            Ordering::Less
+        );
+        assert_eq!(
+            compare_keys(&order_by(true, None), &null, &one),
+            Ordering::Greater
+        );
+        let last = Some(NullsOrder::Last);
+        assert_eq!(
+            compare_keys(&order_by(false, last), &null, &one),
+            Ordering::Greater
+        );
+        let first = Some(NullsOrder::First);
+        assert_eq!(
+            compare_keys(&order_by(true, first), &null, &one),
+            Ordering::Less
+        );
+    }
+
+    #[test]
+    fn test_aggregates() {
+        assert_eq!(
+            query("select count(*), sum(id), avg(id), min(name), max(name) from apples").unwrap(),
+            vec![vec![
+                Data::Integer(4),
+                Data::Integer(10),
+                Data::Float(2.5),
+                text("Fuji"),
+                text("Honeycrisp"),
+            ]]
+        );
+        // Without GROUP BY, there is one result row even with no input
+        assert_eq!(
+            query("select count(*), sum(id), name from apples where id > 10").unwrap(),
+            vec![vec![Data::Integer(0), Data::Null, Data::Null]]
+        );
+        // Bare columns come from the row holding the maximum
+        assert_eq!(
+            query("select name, max(id) from apples").unwrap(),
+            vec![vec![text("Golden Delicious"), Data::Integer(4)]]
+        );
+        assert_eq!(
+            query("select count(distinct color like '%red') from apples").unwrap(),
+            vec![vec![Data::Integer(2)]]
+        );
+    }
+
+    #[test]
+    fn test_group_by() {
+        assert_eq!(
+            query("select color, count(*) from apples group by color").unwrap(),
+            vec![
+                vec![text("Blush Red"), Data::Integer(1)],
+                vec![text("Light Green"), Data::Integer(1)],
+                vec![text("Red"), Data::Integer(1)],
+                vec![text("Yellow"), Data::Integer(1)],
+            ]
+        );
+        assert_eq!(
+            query(
+                "select color like '%red', count(*), group_concat(id, ';') from apples \
+                 group by 1 having count(*) > 1 and max(id) > 3"
+            )
+            .unwrap(),
+            vec![vec![Data::Integer(0), Data::Integer(2), text("1;4")]]
+        );
+        assert_eq!(
+            query("select color, min(id) from apples group by color order by 2 desc limit 2")
+                .unwrap(),
+            vec![
+                vec![text("Yellow"), Data::Integer(4)],
+                vec![text("Blush Red"), Data::Integer(3)],
+            ]
+        );
+        assert_eq!(
+            query("select color from apples where id > 10 group by color").unwrap(),
+            Vec::<Vec<Data>>::new()
+        );
+        assert_eq!(
+            query("select name from apples where count(*) > 1")
+                .unwrap_err()
+                .to_string(),
+            "misuse of aggregate function count()"
+        );
+    }
+
+    #[test]
+    fn test_aliases_in_where_and_having() {
+        assert_eq!(
+            query("select color like '%red' r, max(id) m from apples group by r having m > 3")
+                .unwrap(),
+            vec![vec![Data::Integer(0), Data::Integer(4)]]
+        );
+        assert_eq!(
+            column("select id * 2 d from apples where d > 4 and d - 1 < 7"),
+            vec![Data::Integer(6)]
+        );
+        // A column of the table hides a result column with the same name
+        assert_eq!(
+            column("select id as name from apples where name = 'Fuji'"),
+            vec![Data::Integer(2)]
+        );
+        assert_eq!(
+            query("select count(*) c from apples where c > 1")
+                .unwrap_err()
+                .to_string(),
+            "misuse of aggregate function count()"
+        );
+    }
+
+    #[test]
+    fn test_projection() {
+        assert_eq!(
+            query("select * from apples where id = 2").unwrap(),
+            vec![vec![Data::Integer(2), text("Fuji"), text("Red")]]
+        );
+        assert_eq!(
+            query("select apples.*, id * 2 + 1 as x from apples order by x desc limit 1").unwrap(),
+            vec![vec![
+                Data::Integer(4),
+                text("Golden Delicious"),
+                text("Yellow"),
+                Data::Integer(9),
+            ]]
+        );
+        assert_eq!(
+            query("select name || ' is ' || lower(color), length(name) from apples where id = 1")
+                .unwrap(),
+            vec![vec![text("Granny Smith is light green"), Data::Integer(12)]]
+        );
+        assert_eq!(
+            query("select color c, count(*) n from apples group by c order by n desc, c limit 1")
+                .unwrap(),
+            vec![vec![text("Blush Red"), Data::Integer(1)]]
+        );
+        assert_eq!(
+            query("select 7 / 2, 7.0 / 2, 5 % 0, '3abc' + 1, 9223372036854775807 + 1, 1 || 2.0")
+                .unwrap(),
+            vec![vec![
+                Data::Integer(3),
+                Data::Float(3.5),
+                Data::Null,
+                Data::Integer(4),
+                Data::Float(9223372036854775808.0),
+                text("12.0"),
+            ]]
+        );
+    }
+
+    #[test]
+    fn test_scalar_functions() {
+        // Functions work in the WHERE and ORDER BY clauses as well as the
+        // result columns
+        assert_eq!(
+            query("select upper(substr(name, 1, 3)), printf('%05.1f', id * 1.5) from apples where instr(lower(name), 'e') > 0 order by length(name) desc, name")
+                .unwrap(),
+            vec![
+                vec![text("GOL"), text("006.0")],
+                vec![text("HON"), text("004.5")],
+            ]
+        );
+        assert_eq!(
+            query("select coalesce(null, max(id, 3)), typeof(round(id)), quote(name) from apples where trim(color, 'Red ') = 'Blush'")
+                .unwrap(),
+            vec![vec![Data::Integer(3), text("real"), text("'Honeycrisp'")]]
+        );
+        assert_eq!(
+            query("select substr('abc')").unwrap_err().to_string(),
+            "wrong number of arguments to function substr()"
+        );
+    }
+
+    #[test]
+    fn test_joins() {
+        let names = |sql: &str| {
+            query(sql)
+                .unwrap()
+                .into_iter()
+                .map(|row| {
+                    row.iter()
+                        .map(|v| v.to_string())
+                        .collect::<Vec<_>>()
+                        .join("|")
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names("select a.name, o.name from apples a join oranges o on o.id = a.id + 2"),
+            vec![
+                "Granny Smith|Tangerine",
+                "Fuji|Clementine",
+                "Honeycrisp|Valencia Orange",
+                "Golden Delicious|Navel Orange"
+            ]
+        );
+        assert_eq!(
+            names("select apples.name, oranges.name from apples, oranges where apples.id = oranges.id and oranges.id > 3"),
+            vec!["Golden Delicious|Clementine"]
+        );
+        // Unmatched rows of a LEFT join are joined with NULLs, before WHERE
+        assert_eq!(
+            names("select a.id, b.id from apples a left join apples b on b.id = a.id * 2 where b.id is null"),
+            vec!["3|NULL", "4|NULL"]
+        );
+        // USING merges the columns, so id is neither ambiguous nor repeated
+        assert_eq!(
+            names("select * from apples join oranges using (id) where id = 1"),
+            vec!["1|Granny Smith|Light Green|Mandarin|great for snacking"]
+        );
+        assert_eq!(
+            names("select count(*) from apples cross join oranges"),
+            vec!["24"]
+        );
+        assert!(names("select * from apples natural join oranges").is_empty());
+        assert_eq!(
+            query("select id from apples, oranges")
+                .unwrap_err()
+                .to_string(),
+            "ambiguous column name: id"
+        );
+        assert_eq!(
+            query("select * from apples join oranges using (color)")
+                .unwrap_err()
+                .to_string(),
+            "cannot join using column color - column not present in both tables"
+        );
+        // Subqueries without an alias have no name, so they never clash
+        assert_eq!(
+            names("select * from (select 1 a) natural join (select 1 a, 2 b)"),
+            vec!["1|2"]
+        );
+        assert_eq!(
+            names("select * from (select 1 a) join (select 1 a, 2 b) using (a)"),
+            vec!["1|2"]
+        );
+        assert_eq!(
+            names("select * from (select 1 a), (select 2 a)"),
+            vec!["1|2"]
+        );
+        // but tables with the same name do
+        assert_eq!(
+            query("with t as (select 1 x) select * from t, t")
+                .unwrap_err()
+                .to_string(),
+            "ambiguous column name: t.x"
+        );
+        assert_eq!(
+            query("select a.* from apples a, oranges a")
+                .unwrap_err()
+                .to_string(),
+            "ambiguous column name: a.id"
+        );
+        assert_eq!(
+            names("with t as (select 1 x) select * from t, t u"),
+            vec!["1|1"]
+        );
+    }
+
+    #[test]
+    fn test_index_choice() {
+        // The index on t(a) only has the rows where b = 'keep'
+        assert_eq!(ids("select id from t where a = 5"), integers(&[1, 2, 4]));
+        assert_eq!(
+            ids("select u.id from t join t u on u.a = t.id + 4 where t.id = 1"),
+            integers(&[1, 2, 4])
+        );
+        // Names are matched as names, not as patterns
+        assert_eq!(
+            ids("select id from \"t(1\" where \"a+b\" = 'x'"),
+            integers(&[1, 3])
+        );
+        // A descending index isn't in the order the cursor expects
+        assert_eq!(ids("select v from d where v = 2"), integers(&[2, 2]));
+    }
+
+    #[test]
+    fn test_collations() {
+        // k is NOCASE, and its index is too, so it can't be searched for 'abc'
+        assert_eq!(ids("select id from c where k = 'abc'"), integers(&[1, 3]));
+        assert_eq!(ids("select id from c where 'abc' = k"), integers(&[1, 3]));
+        // b is BINARY, so its NOCASE index isn't used either
+        assert_eq!(ids("select id from c where b = 'abc'"), integers(&[2]));
+        // The left operand's collation wins
+        assert_eq!(
+            ids("select c.id from c join c d on d.k = c.b where d.id = 3"),
+            integers(&[1, 2])
+        );
+        assert_eq!(ids("select id from c where r = 'x'"), integers(&[1]));
+        assert_eq!(
+            ids("select id from c where k in ('abc')"),
+            integers(&[1, 3])
+        );
+        assert_eq!(ids("select id from c order by k, id"), integers(&[1, 3, 2]));
+        assert_eq!(ids("select count(*) from c group by k"), integers(&[2, 1]));
+        assert_eq!(
+            ids("select distinct k from c"),
+            vec![text("ABC"), text("abd")]
+        );
+        // A subquery's columns keep their collation
+        assert_eq!(
+            ids("select id from (select id, k from c) where k = 'ABC'"),
+            integers(&[1, 3])
+        );
+    }
+
+    #[test]
+    fn test_subqueries() {
+        assert_eq!(
+            column("select name from apples where id in (select id - 2 from oranges where id > 4)"),
+            vec![text("Honeycrisp"), text("Golden Delicious")]
+        );
+        assert_eq!(
+            column("select name from apples where id not in (select null)"),
+            Vec::<Data>::new()
+        );
+        // Correlated subqueries see the row of the query they're nested in
+        assert_eq!(
+            column("select name from apples a where exists (select 1 from oranges o where o.id = a.id * 2)"),
+            vec![text("Granny Smith"), text("Fuji"), text("Honeycrisp")]
+        );
+        assert_eq!(
+            query("select (select count(*) from oranges where id < a.id), (select max(id) from oranges) from apples a where id = 3")
+                .unwrap(),
+            vec![vec![Data::Integer(2), Data::Integer(6)]]
+        );
+        assert_eq!(
+            column("select (select name from apples where id > 10)"),
+            vec![Data::Null]
+        );
+        // A subquery in FROM is read like a table, with its result columns
+        assert_eq!(
+            query("select t.*, \"id:1\" from (select a.id, o.id, o.name n from apples a join oranges o on o.id = a.id + 2) t where n like '%Orange'")
+                .unwrap(),
+            vec![
+                vec![Data::Integer(3), Data::Integer(5), text("Valencia Orange"), Data::Integer(5)],
+                vec![Data::Integer(4), Data::Integer(6), text("Navel Orange"), Data::Integer(6)],
+            ]
+        );
+        assert_eq!(
+            query("select (select id, name from apples)")
+                .unwrap_err()
+                .to_string(),
+            "sub-select returns 2 columns - expected 1"
+        );
+        assert_eq!(
+            query(
+                "select name from apples a where exists (select 1 from oranges where size = a.id)"
+            )
+            .unwrap_err()
+            .to_string(),
+            "no such column: size"
+        );
+    }
+
+    #[test]
+    fn test_window_functions() {
+        let rows = |sql: &str| query(sql).unwrap();
+        // Without a final ORDER BY, the rows come out in the window's order
+        assert_eq!(
+            rows("select id, row_number() over (order by name desc), rank() over (order by id / 2) from apples"),
+            vec![integers(&[3, 1, 2]), integers(&[1, 2, 1]), integers(&[4, 3, 4]), integers(&[2, 4, 2])]
+        );
+        assert_eq!(
+            rows("select id, sum(id) over (partition by id % 2 order by id), lag(id, 1, 0) over (partition by id % 2 order by id) from oranges order by id"),
+            vec![
+                integers(&[1, 1, 0]),
+                integers(&[2, 2, 0]),
+                integers(&[3, 4, 1]),
+                integers(&[4, 6, 2]),
+                integers(&[5, 9, 3]),
+                integers(&[6, 12, 4]),
+            ]
+        );
+        assert_eq!(
+            rows("select id, count(*) over w, last_value(id) over (w rows between 1 preceding and 1 following) from oranges window w as (order by id / 2) order by id limit 3"),
+            vec![integers(&[1, 1, 2]), integers(&[2, 3, 3]), integers(&[3, 3, 4])]
+        );
+        // Window functions see the groups, and can sort the result
+        assert_eq!(
+            rows("select count(*), sum(count(*)) over () from apples group by id % 2"),
+            vec![integers(&[2, 4]), integers(&[2, 4])]
+        );
+        let err = |sql| query(sql).unwrap_err().to_string();
+        assert_eq!(
+            err("select id from apples where rank() over () > 1"),
+            "misuse of window function rank()"
+        );
+        assert_eq!(
+            err("select abs(id) over () from apples"),
+            "abs() may not be used as a window function"
+        );
+        assert_eq!(
+            err("select sum(id) over w from apples"),
+            "no such window: w"
+        );
+    }
+
+    #[test]
+    fn test_case_and_cast() {
+        let rows = |sql: &str| query(sql).unwrap();
+        assert_eq!(
+            rows("select case id % 2 when 0 then 'even' else name end, case when id > 3 then id end from apples where id > 2"),
+            vec![
+                vec![text("Honeycrisp"), Data::Null],
+                vec![text("even"), Data::Integer(4)],
+            ]
+        );
+        // NULL matches no WHEN, and text is true if it starts with a number
+        assert_eq!(
+            rows("select case null when null then 1 else 0 end, case when '1x' then 1 end, case when 'x' then 1 end"),
+            vec![vec![Data::Integer(0), Data::Integer(1), Data::Null]]
+        );
+        assert_eq!(
+            rows("select cast(' -12abc' as integer), cast(-1.9 as int), cast('1.0' as numeric), cast('3' as real), cast(1.5 as text), cast(x'6869' as varchar(2))"),
+            vec![vec![
+                Data::Integer(-12),
+                Data::Integer(-1),
+                Data::Integer(1),
+                Data::Float(3.0),
+                text("1.5"),
+                text("hi"),
+            ]]
+        );
+        // A cast has the affinity of its type in comparisons
+        assert_eq!(
+            rows("select cast(1 as text) = 1, cast(id as text) = '1', cast(1 as) = '1' from apples where id = 1"),
+            vec![vec![Data::Integer(1), Data::Integer(1), Data::Integer(1)]]
+        );
+    }
+
+    #[test]
+    fn test_ctes() {
+        assert_eq!(
+            column("with t(n) as (select name from apples where id > 2) select n from t"),
+            vec![text("Honeycrisp"), text("Golden Delicious")]
+        );
+        // Tables can read the ones after them, and be nested in subqueries
+        assert_eq!(
+            column("with a as (select z + 1 y from b), b as (select 3 z) select y from a"),
+            integers(&[4])
+        );
+        assert_eq!(
+            column("select (with t as (select a.id * 2 y) select y from t) from apples a"),
+            integers(&[2, 4, 6, 8])
+        );
+        assert_eq!(
+            column("with recursive t(x) as (select 1 union all select x + 1 from t limit 5 offset 2) select x from t"),
+            integers(&[3, 4, 5, 6, 7])
+        );
+        // The rows are worked out as they are read, so the LIMIT of a query
+        // that reads the table stops it too
+        assert_eq!(
+            column("with recursive t(x) as (select 1 union all select x + 1 from t) select x from t limit 3 offset 2"),
+            integers(&[3, 4, 5])
+        );
+        assert_eq!(
+            column("with recursive t(x) as (select 1 union all select x + 1 from t) select x from t where x > 3 limit 2"),
+            integers(&[4, 5])
+        );
+        assert_eq!(
+            column("with recursive t(x) as (select 1 union all select x + 1 from t) select (select x from t where x > 5 limit 1)"),
+            integers(&[6])
+        );
+        assert_eq!(
+            column(
+                "with recursive t(x) as (select 1 union select x % 3 + 1 from t) select x from t"
+            ),
+            integers(&[1, 2, 3])
+        );
+        // ORDER BY takes the deepest row first, which walks a tree depth-first
+        assert_eq!(
+            column("with recursive t(x, d) as (select 1, 0 union all select x * 2 + j, d + 1 from t, (select 0 j union all select 1) where d < 2 order by 2 desc) select x from t"),
+            integers(&[1, 2, 4, 5, 3, 6, 7])
+        );
+        let err = |sql| query(sql).unwrap_err().to_string();
+        assert_eq!(
+            err("with apples as (select * from apples) select * from apples"),
+            "circular reference: apples"
+        );
+        assert_eq!(
+            err("with t(a, b) as (select 1) select * from t"),
+            "table t has 1 values for 2 columns"
+        );
+        assert_eq!(
+            err("with recursive t(x) as (select 1 union all select x from t, t) select * from t"),
+            "multiple references to recursive table: t"
+        );
+        assert_eq!(
+            err("with recursive t(x) as (select 1 union all select count(*) from t) select * from t"),
+            "recursive aggregate queries not supported"
+        );
+    }
+
+    #[test]
+    fn test_compound_selects() {
+        // UNION sorts and removes repeated rows; UNION ALL keeps them in order
+        assert_eq!(
+            column("select 2 union select 1 union all select 0"),
+            vec![Data::Integer(1), Data::Integer(2), Data::Integer(0)]
+        );
+        assert_eq!(column("select 1 union select 1.0"), vec![Data::Float(1.0)]);
+        assert_eq!(
+            column("select id from oranges except select id from apples"),
+            vec![Data::Integer(5), Data::Integer(6)]
+        );
+        assert_eq!(
+            column("select id from oranges intersect select id + 2 from apples order by 1 desc"),
+            vec![
+                Data::Integer(6),
+                Data::Integer(5),
+                Data::Integer(4),
+                Data::Integer(3)
+            ]
+        );
+        // The ORDER BY and LIMIT apply to the combined rows
+        assert_eq!(
+            column("select id a from apples union select id b from oranges order by b desc limit 2 offset 1"),
+            vec![Data::Integer(5), Data::Integer(4)]
+        );
+        assert_eq!(
+            column("select * from (select 1 union all select 2 limit 1)"),
+            vec![Data::Integer(1)]
+        );
+        assert_eq!(
+            column("select distinct x from (select 3 x union all select 1 union all select 3.0)"),
+            vec![Data::Integer(3), Data::Integer(1)]
+        );
+        assert_eq!(
+            query("select id from apples union select 1, 2")
+                .unwrap_err()
+                .to_string(),
+            "SELECTs to the left and right of UNION do not have the same number of result columns"
+        );
+        assert_eq!(
+            query("select id from apples union select id from oranges order by name")
+                .unwrap_err()
+                .to_string(),
+            "1st ORDER BY term does not match any column in the result set"
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            query("select name from pears").unwrap_err().to_string(),
+            "no such table: pears"
+        );
+        assert_eq!(
+            query("select size from apples").unwrap_err().to_string(),
+            "no such column: size"
+        );
+        // Names are checked even if no rows are read
+        assert_eq!(
+            query("select id from apples where id > 10 order by size")
+                .unwrap_err()
+                .to_string(),
+            "no such column: size"
+        );
+        assert_eq!(
+            query("select nope(id) from apples where 0")
+                .unwrap_err()
+                .to_string(),
+            "no such function: nope"
+        );
+        assert_eq!(
+            query("select pears.* from apples").unwrap_err().to_string(),
+            "no such table: pears"
+        );
+    }
+}
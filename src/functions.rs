@@ -0,0 +1,417 @@
+use crate::aggregate;
+use crate::ast::FunctionArgs;
+use crate::parser::{format_real, parse_numeric_prefix, Data};
+use crate::printf;
+use crate::window;
+
+use anyhow::bail;
+use std::cmp::Ordering;
+use std::ops::RangeInclusive;
+
+// The numbers of arguments a scalar function accepts, or None if there is no
+// scalar function with that name
+fn arity(name: &str) -> Option<RangeInclusive<usize>> {
+    Some(match name {
+        "abs" | "hex" | "length" | "lower" | "quote" | "typeof" | "unicode" | "upper" => 1..=1,
+        "ltrim" | "round" | "rtrim" | "trim" => 1..=2,
+        "ifnull" | "instr" | "nullif" => 2..=2,
+        "substr" | "substring" => 2..=3,
+        "replace" => 3..=3,
+        // min and max with one argument are aggregates
+        "coalesce" | "max" | "min" => 2..=usize::MAX,
+        "char" | "format" | "printf" => 0..=usize::MAX,
+        _ => return None,
+    })
+}
+
+// Checks that a function call names a function that accepts its arguments
+pub fn check(name: &str, args: &FunctionArgs) -> Result<(), anyhow::Error> {
+    if aggregate::is_aggregate(name, args) {
+        return Ok(());
+    }
+    if window::is_window_function(name) {
+        bail!("misuse of window function {name}()");
+    }
+    // Scalar functions read * as no arguments
+    let argc = match args {
+        FunctionArgs::Star => 0,
+        FunctionArgs::List(args) => args.len(),
+    };
+    let lower = name.to_ascii_lowercase();
+    match arity(&lower) {
+        Some(arity) if arity.contains(&argc) => Ok(()),
+        Some(_) => bail!("wrong number of arguments to function {name}()"),
+        None if aggregate::AGGREGATES.contains(&lower.as_str()) => {
+            bail!("wrong number of arguments to function {name}()")
+        }
+        None => bail!("no such function: {name}"),
+    }
+}
+
+// Calls a scalar function. The call must have passed check.
+pub fn call(name: &str, args: &[Data]) -> Result<Data, anyhow::Error> {
+    Ok(match (name.to_ascii_lowercase().as_str(), args) {
+        ("typeof", [value]) => Data::Text(
+            match value {
+                Data::Null => "null",
+                Data::Integer(_) => "integer",
+                Data::Float(_) => "real",
+                Data::Text(_) => "text",
+                Data::Blob(_) => "blob",
+            }
+            .to_string(),
+        ),
+        ("coalesce" | "ifnull", args) => args
+            .iter()
+            .find(|value| **value != Data::Null)
+            .cloned()
+            .unwrap_or(Data::Null),
+        // Values are compared without affinity, so 1 and '1' differ
+        ("nullif", [a, b]) if a.compare(b) == Ordering::Equal => Data::Null,
+        ("nullif", [a, _]) => a.clone(),
+        ("quote", [value]) => Data::Text(quote(value)),
+        ("hex", [Data::Null]) => Data::Text(String::new()),
+        ("hex", [value]) => Data::Text(hex(&bytes_of(value))),
+        // Arguments are code points. NULL is 0, and invalid code points
+        // become the replacement character.
+        ("char", args) => Data::Text(
+            args.iter()
+                .map(|value| {
+                    let code = integer_of(value) & 0x1fffff;
+                    char::from_u32(code as u32).unwrap_or(char::REPLACEMENT_CHARACTER)
+                })
+                .collect(),
+        ),
+        ("printf" | "format", [] | [Data::Null, ..]) => Data::Null,
+        ("printf" | "format", [format, args @ ..]) => {
+            printf::format(&text_of(format), args).map_or(Data::Null, Data::Text)
+        }
+        // The other functions are NULL if any of their arguments is
+        (_, args) if args.contains(&Data::Null) => Data::Null,
+        // The smallest value is the last of equal ones, and the largest the
+        // first
+        ("min", [first, rest @ ..]) => rest
+            .iter()
+            .fold(first, |min, value| {
+                if min.compare(value).is_ge() {
+                    value
+                } else {
+                    min
+                }
+            })
+            .clone(),
+        ("max", [first, rest @ ..]) => rest
+            .iter()
+            .fold(first, |max, value| {
+                if max.compare(value).is_lt() {
+                    value
+                } else {
+                    max
+                }
+            })
+            .clone(),
+        // The length of text is in characters up to the first NUL, and of a
+        // blob in bytes
+        ("length", [Data::Blob(b)]) => Data::Integer(b.len() as i64),
+        ("length", [value]) => {
+            Data::Integer(text_of(value).chars().take_while(|&c| c != '\0').count() as i64)
+        }
+        // Only ASCII letters change case
+        ("lower", [value]) => Data::Text(text_of(value).to_ascii_lowercase()),
+        ("upper", [value]) => Data::Text(text_of(value).to_ascii_uppercase()),
+        ("substr" | "substring", [value, start, length @ ..]) => {
+            substr(value, integer_of(start), length.first().map(integer_of))
+        }
+        (function @ ("trim" | "ltrim" | "rtrim"), [value, chars @ ..]) => {
+            let chars = match chars {
+                [chars] => text_of(chars).chars().collect(),
+                _ => vec![' '],
+            };
+            let text = text_of(value);
+            Data::Text(
+                match function {
+                    "ltrim" => text.trim_start_matches(&chars[..]),
+                    "rtrim" => text.trim_end_matches(&chars[..]),
+                    _ => text.trim_matches(&chars[..]),
+                }
+                .to_string(),
+            )
+        }
+        ("replace", [value, pattern, replacement]) => match text_of(pattern) {
+            pattern if pattern.is_empty() => Data::Text(text_of(value)),
+            pattern => Data::Text(text_of(value).replace(&pattern, &text_of(replacement))),
+        },
+        // Positions are in bytes if both values are blobs, and in characters
+        // otherwise. 0 means not found.
+        ("instr", [Data::Blob(haystack), Data::Blob(needle)]) => Data::Integer(
+            haystack
+                .windows(needle.len().max(1))
+                .position(|window| needle.is_empty() || window == &needle[..])
+                .map_or(0, |i| i as i64 + 1),
+        ),
+        ("instr", [haystack, needle]) => {
+            let haystack = text_of(haystack);
+            Data::Integer(
+                haystack
+                    .find(&text_of(needle))
+                    .map_or(0, |i| haystack[..i].chars().count() as i64 + 1),
+            )
+        }
+        // Only integers stay integers. Anything else is converted to a real.
+        ("abs", [Data::Integer(i)]) => match i.checked_abs() {
+            Some(i) => Data::Integer(i),
+            None => bail!("integer overflow"),
+        },
+        ("abs", [value]) => Data::Float(real_of(value).abs()),
+        ("round", [value, digits @ ..]) => Data::Float(round(
+            real_of(value),
+            digits.first().map_or(0, integer_of).clamp(0, 30),
+        )),
+        ("unicode", [value]) => text_of(value)
+            .chars()
+            .next()
+            .map_or(Data::Null, |c| Data::Integer(c as i64)),
+        _ => bail!("no such function: {name}"),
+    })
+}
+
+// Returns the part of text or a blob that starts at a 1-based position and
+// has a length. A negative position counts from the end, and a negative
+// length takes the characters before the position instead of after it.
+fn substr(value: &Data, start: i64, length: Option<i64>) -> Data {
+    let chars = match value {
+        Data::Blob(_) => Vec::new(),
+        value => text_of(value).chars().collect::<Vec<_>>(),
+    };
+    let len = match value {
+        Data::Blob(b) => b.len(),
+        _ => chars.len(),
+    } as i64;
+    let mut start = start;
+    let mut length = length.unwrap_or(i64::MAX);
+    let before = length < 0;
+    length = length.saturating_abs();
+    // Position 0 is before the first character, so it takes one less
+    if start < 0 {
+        start += len;
+        if start < 0 {
+            length = length.saturating_add(start).max(0);
+            start = 0;
+        }
+    } else if start > 0 {
+        start -= 1;
+    } else if length > 0 {
+        length -= 1;
+    }
+    if before {
+        start -= length;
+        if start < 0 {
+            length += start;
+            start = 0;
+        }
+    }
+    let start = start.min(len) as usize;
+    let end = start.saturating_add(length as usize).min(len as usize);
+    match value {
+        Data::Blob(b) => Data::Blob(b[start..end].to_vec()),
+        _ => Data::Text(chars[start..end].iter().collect()),
+    }
+}
+
+// Rounds a real to a number of digits after the decimal point, halves away
+// from zero, the way SQLite does
+fn round(x: f64, digits: i64) -> f64 {
+    // Reals this large have no fraction
+    if x.abs() > 4503599627370496.0 {
+        x
+    } else if digits == 0 {
+        (x + 0.5f64.copysign(x)).trunc()
+    } else {
+        printf::format(&format!("%!.{digits}f"), &[Data::Float(x)])
+            .and_then(|text| text.parse().ok())
+            .unwrap_or(x)
+    }
+}
+
+// Writes a value as an SQL literal. Reals that 15 digits don't give back
+// exactly are written with more.
+fn quote(value: &Data) -> String {
+    match value {
+        Data::Null => "NULL".to_string(),
+        Data::Integer(i) => i.to_string(),
+        // Infinities are written as a literal too large for a real
+        Data::Float(x) if x.is_infinite() => format!("{}9.0e+999", if *x < 0.0 { "-" } else { "" }),
+        Data::Float(x) => match format_real(*x) {
+            text if text.parse() == Ok(*x) => text,
+            _ => printf::format("%!.20e", &[Data::Float(*x)]).unwrap_or_default(),
+        },
+        Data::Text(s) => format!("'{}'", s.replace('\'', "''")),
+        Data::Blob(b) => format!("X'{}'", hex(b)),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02X}")).collect()
+}
+
+// Converts a value to text for a string function. Blobs are read as UTF-8.
+pub fn text_of(value: &Data) -> String {
+    match value {
+        Data::Blob(b) => String::from_utf8_lossy(b).into_owned(),
+        value => value.to_string(),
+    }
+}
+
+// The bytes of a value: those of a blob, or of the value as text
+fn bytes_of(value: &Data) -> Vec<u8> {
+    match value {
+        Data::Blob(b) => b.clone(),
+        value => text_of(value).into_bytes(),
+    }
+}
+
+// Converts a value to an integer argument. NULL is 0, reals are truncated
+// and text is read up to the end of its longest numeric prefix.
+pub fn integer_of(value: &Data) -> i64 {
+    match value {
+        Data::Null => 0,
+        Data::Integer(i) => *i,
+        Data::Float(x) => *x as i64,
+        Data::Text(_) | Data::Blob(_) => integer_of(&parse_numeric_prefix(&text_of(value))),
+    }
+}
+
+// Converts a value to a real argument, the way integer_of does
+pub fn real_of(value: &Data) -> f64 {
+    match value {
+        Data::Null => 0.0,
+        Data::Integer(i) => *i as f64,
+        Data::Float(x) => *x,
+        Data::Text(_) | Data::Blob(_) => real_of(&parse_numeric_prefix(&text_of(value))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(s: &str) -> Data {
+        Data::Text(s.to_string())
+    }
+
+    #[test]
+    fn test_call() {
+        assert_eq!(call("LENGTH", &[text("héllo")]).unwrap(), Data::Integer(5));
+        assert_eq!(
+            call("length", &[Data::Blob(vec![0xc3, 0xa9])]).unwrap(),
+            Data::Integer(2)
+        );
+        assert_eq!(
+            call("length", &[Data::Float(2.5)]).unwrap(),
+            Data::Integer(3)
+        );
+        assert_eq!(call("upper", &[text("héllo")]).unwrap(), text("HéLLO"));
+        assert_eq!(call("lower", &[Data::Null]).unwrap(), Data::Null);
+        assert_eq!(call("typeof", &[Data::Null]).unwrap(), text("null"));
+        assert_eq!(call("typeof", &[Data::Float(1.0)]).unwrap(), text("real"));
+    }
+
+    #[test]
+    fn test_string_functions() {
+        let call = |name, args: &[Data]| call(name, args).unwrap();
+        let int = Data::Integer;
+        assert_eq!(call("substr", &[text("hello"), int(2)]), text("ello"));
+        assert_eq!(
+            call("substr", &[text("hello"), int(-3), int(2)]),
+            text("ll")
+        );
+        assert_eq!(call("substr", &[text("hello"), int(0), int(2)]), text("h"));
+        assert_eq!(
+            call("substr", &[text("hello"), int(4), int(-3)]),
+            text("hel")
+        );
+        assert_eq!(call("substr", &[text("héllo"), int(2), int(1)]), text("é"));
+        assert_eq!(
+            call("substr", &[Data::Blob(vec![1, 2, 3]), int(2)]),
+            Data::Blob(vec![2, 3])
+        );
+        assert_eq!(call("substr", &[text("hello"), Data::Null]), Data::Null);
+        assert_eq!(call("trim", &[text("  a  ")]), text("a"));
+        assert_eq!(call("ltrim", &[text("xxaxx"), text("x")]), text("axx"));
+        assert_eq!(call("rtrim", &[Data::Float(12.5), text("5")]), text("12."));
+        assert_eq!(
+            call("replace", &[int(12345), int(3), text("x")]),
+            text("12x45")
+        );
+        assert_eq!(
+            call("replace", &[text("abc"), text(""), text("x")]),
+            text("abc")
+        );
+        assert_eq!(call("instr", &[text("héllo"), text("l")]), int(3));
+        assert_eq!(call("instr", &[text("abc"), text("z")]), int(0));
+        assert_eq!(call("hex", &[text("é")]), text("C3A9"));
+        assert_eq!(call("hex", &[Data::Null]), text(""));
+        assert_eq!(call("quote", &[text("it's")]), text("'it''s'"));
+        assert_eq!(call("quote", &[Data::Blob(vec![10])]), text("X'0A'"));
+        assert_eq!(call("quote", &[Data::Float(0.1)]), text("0.1"));
+        assert_eq!(call("char", &[int(72), int(105)]), text("Hi"));
+        assert_eq!(call("unicode", &[text("é")]), int(233));
+        assert_eq!(call("unicode", &[text("")]), Data::Null);
+        assert_eq!(
+            call("printf", &[text("%5.1f|%s"), Data::Float(2.25), Data::Null]),
+            text("  2.3|")
+        );
+        assert_eq!(call("format", &[Data::Null]), Data::Null);
+    }
+
+    #[test]
+    fn test_numeric_functions() {
+        let call = |name, args: &[Data]| call(name, args).unwrap();
+        let int = Data::Integer;
+        assert_eq!(call("abs", &[int(-3)]), int(3));
+        assert_eq!(call("abs", &[text("-3")]), Data::Float(3.0));
+        assert_eq!(
+            super::call("abs", &[int(i64::MIN)])
+                .unwrap_err()
+                .to_string(),
+            "integer overflow"
+        );
+        assert_eq!(call("round", &[Data::Float(-2.5)]), Data::Float(-3.0));
+        assert_eq!(
+            call("round", &[Data::Float(2.675), int(2)]),
+            Data::Float(2.67)
+        );
+        assert_eq!(call("round", &[text("3.7")]), Data::Float(4.0));
+        assert_eq!(call("round", &[int(1), Data::Null]), Data::Null);
+        assert_eq!(call("coalesce", &[Data::Null, int(3), int(4)]), int(3));
+        assert_eq!(call("ifnull", &[Data::Null, Data::Null]), Data::Null);
+        // nullif and multi-argument min and max compare without affinity
+        assert_eq!(call("nullif", &[int(1), Data::Float(1.0)]), Data::Null);
+        assert_eq!(call("nullif", &[int(1), text("1")]), int(1));
+        assert_eq!(call("min", &[int(1), Data::Float(1.0)]), Data::Float(1.0));
+        assert_eq!(call("max", &[int(1), Data::Float(1.0)]), int(1));
+        assert_eq!(call("max", &[text("a"), int(1)]), text("a"));
+        assert_eq!(call("min", &[int(2), Data::Null, int(1)]), Data::Null);
+    }
+
+    #[test]
+    fn test_check() {
+        let args = |n: usize| FunctionArgs::List(vec![crate::ast::Expr::Literal(Data::Null); n]);
+        assert!(check("upper", &args(1)).is_ok());
+        assert!(check("count", &FunctionArgs::Star).is_ok());
+        assert!(check("char", &FunctionArgs::Star).is_ok());
+        assert!(check("min", &args(3)).is_ok());
+        assert_eq!(
+            check("upper", &args(2)).unwrap_err().to_string(),
+            "wrong number of arguments to function upper()"
+        );
+        assert_eq!(
+            check("sum", &args(2)).unwrap_err().to_string(),
+            "wrong number of arguments to function sum()"
+        );
+        assert_eq!(
+            check("nope", &args(0)).unwrap_err().to_string(),
+            "no such function: nope"
+        );
+    }
+}
@@ -0,0 +1,473 @@
+use crate::ast::{
+    BinaryOp, ColumnDef, ColumnRef, Expr, JoinConstraint, JoinKind, Select, TableRef,
+};
+use crate::data::{find_rowid, get_row, Database, TableCursor};
+use crate::executor::{
+    self, eval, is_true, Context, CteRows, Env, JoinedRow, Recursion, Row, Scope,
+};
+use crate::parser::{Collation, Data};
+use crate::sql_parser;
+
+use anyhow::{anyhow, bail};
+use std::iter;
+use std::rc::Rc;
+
+// The rows of a FROM clause
+pub type JoinedRows<'a> = Box<dyn Iterator<Item = Result<JoinedRow, anyhow::Error>> + 'a>;
+
+// One table of a FROM clause, with the constraint that joins it to the
+// tables before it
+#[derive(Clone)]
+pub struct Table {
+    name: String,
+    source: Source,
+    pub columns: Vec<ColumnDef>,
+    kind: JoinKind,
+    // The terms of the ON clause, and the equalities USING and NATURAL imply
+    on: Vec<Expr>,
+}
+
+// Where the rows of a table come from
+#[derive(Clone)]
+enum Source {
+    // The b-tree of a table in the database, by its root page
+    Btree(u64),
+    // The rows of a subquery or a WITH table, read when the table is opened
+    Rows(Rc<Vec<Row>>),
+    // The rows of a recursive WITH table, worked out as they are read
+    Recursive(Rc<Recursion>),
+}
+
+// How the rows of a table are found for a row of the tables before it
+enum Access {
+    Scan,
+    // By rowid, or by value with an index on the column. The column is
+    // compared with an expression over the tables before it.
+    Rowid {
+        column: Expr,
+        value: Expr,
+    },
+    Index {
+        name: String,
+        column: Expr,
+        value: Expr,
+    },
+}
+
+// A table along with how its rows are found and filtered
+struct Level {
+    table: Table,
+    access: Access,
+    // The WHERE terms that can be checked once this table has a row
+    filter: Vec<Expr>,
+}
+
+// Splits an expression into the terms joined by AND
+fn conjuncts(expr: &Expr) -> Vec<&Expr> {
+    match expr {
+        Expr::Binary {
+            op: BinaryOp::And,
+            left,
+            right,
+        } => {
+            let mut terms = conjuncts(left);
+            terms.extend(conjuncts(right));
+            terms
+        }
+        expr => vec![expr],
+    }
+}
+
+// Returns true if every term is true of a joined row
+fn passes(
+    terms: &[Expr],
+    scope: &Scope,
+    context: &Context,
+    row: &[Row],
+) -> Result<bool, anyhow::Error> {
+    let env = Env::new(scope, row).with_context(context);
+    for term in terms {
+        if !is_true(&eval(term, &env)?) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+// Opens the tables of a FROM clause, returning them along with the scope
+// of their columns. Subqueries are run as they are opened.
+pub fn open(select: &Select, context: &Context) -> Result<(Vec<Table>, Scope), anyhow::Error> {
+    let Some(from) = &select.from else {
+        bail!("no tables specified");
+    };
+    let mut scope = Scope::default();
+    let mut tables = Vec::new();
+    let joins = select.joins.iter().map(|join| (&join.table, Some(join)));
+    for (table_ref, join) in iter::once((from, None)).chain(joins) {
+        let (name, source, columns) = match table_ref {
+            TableRef::Table { name, .. } => match context.cte(name)? {
+                Some((columns, CteRows::Rows(rows))) => {
+                    (name.as_str(), Source::Rows(rows), columns)
+                }
+                Some((columns, CteRows::Recursive(recursion))) => {
+                    (name.as_str(), Source::Recursive(recursion), columns)
+                }
+                None => {
+                    let (root_page, columns) = schema(context.db, name)?;
+                    (name.as_str(), Source::Btree(root_page), columns)
+                }
+            },
+            TableRef::Subquery { select, .. } => {
+                let (columns, rows) = executor::derived_table(select, context)?;
+                ("", Source::Rows(Rc::new(rows)), columns)
+            }
+        };
+        let (kind, constraint) = match join {
+            Some(join) => (join.kind, join.constraint.as_ref()),
+            None => (JoinKind::Inner, None),
+        };
+        let using = match constraint {
+            Some(JoinConstraint::Using(names)) => names.clone(),
+            // The columns of the table that an earlier table also has
+            Some(JoinConstraint::Natural) => columns
+                .iter()
+                .filter(|c| scope.resolve(&unqualified(&c.name)).is_ok())
+                .map(|c| c.name.clone())
+                .collect(),
+            _ => Vec::new(),
+        };
+        let mut on = match constraint {
+            Some(JoinConstraint::On(on)) => conjuncts(on).into_iter().cloned().collect(),
+            _ => Vec::new(),
+        };
+        for column in &using {
+            let missing =
+                || anyhow!("cannot join using column {column} - column not present in both tables");
+            let (left, left_name) = scope.resolve(&unqualified(column)).map_err(|_| missing())?;
+            let right = columns
+                .iter()
+                .find(|c| c.name.eq_ignore_ascii_case(column))
+                .ok_or_else(missing)?;
+            on.push(Expr::binary(
+                BinaryOp::Eq,
+                Expr::TableColumn {
+                    table: left,
+                    name: left_name.to_string(),
+                },
+                Expr::TableColumn {
+                    table: tables.len(),
+                    name: right.name.clone(),
+                },
+            ));
+        }
+        let rowid = matches!(source, Source::Btree(_));
+        scope.add_table(table_ref.scope_name(), &columns, rowid);
+        for column in &using {
+            scope.merge(column);
+        }
+        tables.push(Table {
+            name: name.to_string(),
+            source,
+            columns,
+            kind,
+            on,
+        });
+    }
+    Ok((tables, scope))
+}
+
+// Finds the root page and the columns of a table in the database
+fn schema(db: &Database, name: &str) -> Result<(u64, Vec<ColumnDef>), anyhow::Error> {
+    let Some(Data::Integer(root_page)) = db.get_root_page(name)? else {
+        bail!("no such table: {name}");
+    };
+    let Some(Data::Text(sql)) = db.get_create_table(name)? else {
+        bail!("no such table: {name}");
+    };
+    let table = sql_parser::parse_create_table(&sql)?;
+    // Such tables are stored as an index on their primary key
+    if table.without_rowid {
+        bail!("cannot read WITHOUT ROWID table {name}");
+    }
+    for collation in table.columns.iter().filter_map(ColumnDef::collation) {
+        if Collation::from_name(collation).is_none() {
+            bail!("no such collation sequence: {collation}");
+        }
+    }
+    Ok((root_page as u64, table.columns))
+}
+
+fn unqualified(name: &str) -> ColumnRef {
+    ColumnRef {
+        table: None,
+        name: name.to_string(),
+    }
+}
+
+impl Table {
+    // Chooses how to find the rows of the table, the `index`th of the FROM
+    // clause. A term that compares one of its columns with values from the
+    // tables before it can look the rows up by rowid, or with an index on
+    // the column, instead of scanning the table. The rows of a subquery or a WITH
+    // table are always scanned.
+    fn access(
+        &self,
+        scope: &Scope,
+        context: &Context,
+        index: usize,
+        terms: &[&Expr],
+    ) -> Result<Access, anyhow::Error> {
+        let mut access = Access::Scan;
+        if !self.is_stored() {
+            return Ok(access);
+        }
+        let env = Env::new(scope, &[]).with_context(context);
+        for term in terms {
+            let Expr::Binary {
+                op: BinaryOp::Eq,
+                left,
+                right,
+            } = term
+            else {
+                continue;
+            };
+            for (column, value) in [(left, right), (right, left)] {
+                let (table, name) = match column.as_ref() {
+                    Expr::Column(column_ref) => match scope.resolve(column_ref) {
+                        Ok(found) => found,
+                        Err(_) => continue,
+                    },
+                    Expr::TableColumn { table, name } => (*table, name.as_str()),
+                    _ => continue,
+                };
+                let earlier = match scope.last_table(value) {
+                    Ok(last) => last.is_none_or(|last| last < index),
+                    Err(_) => false,
+                };
+                if table != index || !earlier || !env.can_look_up(column, value) {
+                    continue;
+                }
+                let is_rowid = self.columns.iter().any(|c| c.ipk && c.name == name)
+                    || (name == "rowid" && !self.columns.iter().any(|c| c.name == "rowid"));
+                let (column, value) = (column.as_ref().clone(), value.as_ref().clone());
+                if is_rowid {
+                    return Ok(Access::Rowid { column, value });
+                }
+                if matches!(access, Access::Scan)
+                    && context.db.find_index_root(name, &self.name)?.is_some()
+                {
+                    access = Access::Index {
+                        name: name.to_string(),
+                        column,
+                        value,
+                    };
+                }
+            }
+        }
+        Ok(access)
+    }
+
+    // Reads every row of the table, in rowid order
+    fn scan<'a>(
+        &self,
+        context: &Context<'a>,
+    ) -> Box<dyn Iterator<Item = Result<Row, anyhow::Error>> + 'a> {
+        match &self.source {
+            Source::Btree(root_page) => {
+                let columns = self.columns.clone();
+                let rows = TableCursor::new(context.db, *root_page);
+                Box::new(rows.map(move |row| get_row(&row?, &columns)))
+            }
+            Source::Rows(rows) => {
+                let rows = rows.clone();
+                Box::new((0..rows.len()).map(move |i| Ok(rows[i].clone())))
+            }
+            Source::Recursive(recursion) => {
+                let (recursion, context) = (recursion.clone(), context.clone());
+                Box::new((0..).map_while(move |i| recursion.row(i, &context).transpose()))
+            }
+        }
+    }
+
+    // Returns true if the rows of the table are stored in the database
+    pub fn is_stored(&self) -> bool {
+        matches!(self.source, Source::Btree(_))
+    }
+
+    // Returns a copy of the table that reads other rows, if it reads `old`.
+    // A recursive WITH table is read one row at a time this way.
+    pub fn with_rows(&self, old: &Rc<Vec<Row>>, new: &Rc<Vec<Row>>) -> Table {
+        let mut table = self.clone();
+        if matches!(&self.source, Source::Rows(rows) if Rc::ptr_eq(rows, old)) {
+            table.source = Source::Rows(new.clone());
+        }
+        table
+    }
+
+    // The root page of the table, which only tables in the database have
+    fn root_page(&self) -> u64 {
+        match self.source {
+            Source::Btree(root_page) => root_page,
+            Source::Rows(_) | Source::Recursive(_) => {
+                unreachable!("rows of queries are always scanned")
+            }
+        }
+    }
+}
+
+impl Level {
+    // Finds the rows of the table for a row of the tables before it, or
+    // returns None if the table has to be scanned. Rows found by index are
+    // in rowid order too, since index keys end with the rowid.
+    fn look_up(
+        &self,
+        scope: &Scope,
+        context: &Context,
+        outer: &[Row],
+    ) -> Result<Option<Vec<Row>>, anyhow::Error> {
+        let (column, value) = match &self.access {
+            Access::Scan => return Ok(None),
+            Access::Rowid { column, value } | Access::Index { column, value, .. } => {
+                (column, value)
+            }
+        };
+        let env = Env::new(scope, outer).with_context(context);
+        let key = env.lookup_value(column, value, eval(value, &env)?);
+        let db = context.db;
+        let rows = match (&self.access, key) {
+            (_, Data::Null) => Vec::new(),
+            (Access::Rowid { .. }, key) => {
+                let rowid = match key {
+                    Data::Integer(i) => Some(i),
+                    // Only reals that are whole numbers can equal a rowid
+                    Data::Float(x) if x.fract() == 0.0 && x.abs() < i64::MAX as f64 => {
+                        Some(x as i64)
+                    }
+                    _ => None,
+                };
+                match rowid {
+                    Some(rowid) => find_rowid(db, self.table.root_page(), rowid)?
+                        .map(|row| get_row(&row, &self.table.columns))
+                        .transpose()?
+                        .into_iter()
+                        .collect(),
+                    None => Vec::new(),
+                }
+            }
+            (Access::Index { name, .. }, key) => db
+                .find_by_index(name, &self.table.name, key)?
+                .iter()
+                .map(|row| get_row(row, &self.table.columns))
+                .collect::<Result<_, _>>()?,
+            (Access::Scan, _) => unreachable!(),
+        };
+        Ok(Some(rows))
+    }
+
+    // Joins a row of the tables before this one with the matching rows of
+    // this table. The rows of a table that has to be scanned are read once
+    // into `cache`. If nothing matches a LEFT join, the row is joined with a
+    // row of NULLs.
+    fn join(
+        &self,
+        scope: &Scope,
+        context: &Context,
+        outer: JoinedRow,
+        cache: &mut Option<Vec<Row>>,
+    ) -> Result<Vec<JoinedRow>, anyhow::Error> {
+        let found;
+        let candidates = match self.look_up(scope, context, &outer)? {
+            Some(rows) => {
+                found = rows;
+                &found
+            }
+            None => {
+                if cache.is_none() {
+                    *cache = Some(self.table.scan(context).collect::<Result<_, _>>()?);
+                }
+                cache.as_ref().unwrap()
+            }
+        };
+        let mut joined = Vec::new();
+        for row in candidates {
+            let mut candidate = outer.clone();
+            candidate.push(row.clone());
+            if passes(&self.table.on, scope, context, &candidate)? {
+                joined.push(candidate);
+            }
+        }
+        if joined.is_empty() && self.table.kind == JoinKind::Left {
+            let mut candidate = outer;
+            candidate.push(Row::new());
+            joined.push(candidate);
+        }
+        let mut res = Vec::new();
+        for row in joined {
+            if passes(&self.filter, scope, context, &row)? {
+                res.push(row);
+            }
+        }
+        Ok(res)
+    }
+}
+
+// Reads the rows of the tables of a FROM clause that satisfy a WHERE clause,
+// joining them with nested loops. The first table is read lazily, so the
+// join produces its rows in the order of the first table.
+pub fn read<'a>(
+    scope: &Scope,
+    tables: Vec<Table>,
+    where_: Option<&Expr>,
+    context: Context<'a>,
+) -> Result<JoinedRows<'a>, anyhow::Error> {
+    // Each WHERE term is checked as soon as every table it refers to has a
+    // row, which for a LEFT join is after NULLs fill in a missing row
+    let mut filters = tables.iter().map(|_| Vec::new()).collect::<Vec<_>>();
+    for term in where_.map(conjuncts).unwrap_or_default() {
+        let last = scope.last_table(term)?.unwrap_or(0);
+        filters[last].push(term);
+    }
+    let mut levels = Vec::new();
+    for (i, (table, filter)) in tables.into_iter().zip(filters).enumerate() {
+        // A LEFT join has to find the same rows whatever the WHERE clause says
+        let mut terms = table.on.iter().collect::<Vec<_>>();
+        if table.kind == JoinKind::Inner {
+            terms.extend(&filter);
+        }
+        let access = table.access(scope, &context, i, &terms)?;
+        levels.push(Level {
+            table,
+            access,
+            filter: filter.into_iter().cloned().collect(),
+        });
+    }
+
+    let mut levels = levels.into_iter();
+    let first = levels
+        .next()
+        .ok_or_else(|| anyhow!("no tables specified"))?;
+    let rows: Box<dyn Iterator<Item = Result<Row, anyhow::Error>> + 'a> =
+        match first.look_up(scope, &context, &[])? {
+            Some(rows) => Box::new(rows.into_iter().map(Ok)),
+            None => first.table.scan(&context),
+        };
+    let (first_scope, first_context) = (scope.clone(), context.clone());
+    let mut rows: JoinedRows<'a> = Box::new(rows.filter_map(move |row| {
+        row.and_then(|row| {
+            let row = vec![row];
+            Ok(passes(&first.filter, &first_scope, &first_context, &row)?.then_some(row))
+        })
+        .transpose()
+    }));
+    for level in levels {
+        let (scope, context) = (scope.clone(), context.clone());
+        let mut cache = None;
+        rows = Box::new(rows.flat_map(move |outer| {
+            match outer.and_then(|outer| level.join(&scope, &context, outer, &mut cache)) {
+                Ok(rows) => rows.into_iter().map(Ok).collect::<Vec<_>>(),
+                Err(e) => vec![Err(e)],
+            }
+        }));
+    }
+    Ok(rows)
+}
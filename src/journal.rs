@@ -0,0 +1,230 @@
+use anyhow::bail;
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+
+// Every segment of a rollback journal starts with a header holding this magic
+const JOURNAL_MAGIC: [u8; 8] = [0xd9, 0xd5, 0x05, 0xf9, 0x20, 0xa1, 0x63, 0xd7];
+const JOURNAL_HEADER_SIZE: usize = 28;
+// A record count of -1 means the records run to the end of the journal
+const JOURNAL_RECORDS_TO_END: u32 = 0xffff_ffff;
+
+// The header at the start of each journal segment
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct JournalHeader {
+    record_count: u32,
+    // Added to the checksum of each record, so that records left over from
+    // an earlier transaction fail the checksum
+    nonce: u32,
+    // The size of the database in pages before the transaction started
+    initial_size: u32,
+    sector_size: u32,
+    page_size: u32,
+}
+
+fn be_u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
+// Parse a segment header. Returns None if it doesn't start with the magic,
+// which is how a journal that has been committed or abandoned is marked.
+fn parse_header(buf: &[u8]) -> Option<JournalHeader> {
+    if buf.len() < JOURNAL_HEADER_SIZE || buf[..8] != JOURNAL_MAGIC {
+        return None;
+    }
+    Some(JournalHeader {
+        record_count: be_u32_at(buf, 8),
+        nonce: be_u32_at(buf, 12),
+        initial_size: be_u32_at(buf, 16),
+        sector_size: be_u32_at(buf, 20),
+        page_size: be_u32_at(buf, 24),
+    })
+}
+
+// The checksum of a journal record: the nonce plus every 200th byte of the
+// page, counting down from 200 bytes before its end
+fn record_checksum(nonce: u32, page: &[u8]) -> u32 {
+    let mut sum = nonce;
+    let mut i = page.len() as isize - 200;
+    while i > 0 {
+        sum = sum.wrapping_add(page[i as usize] as u32);
+        i -= 200;
+    }
+    sum
+}
+
+// A hot rollback journal: the original content of the pages changed by a
+// transaction that never committed, because its writer crashed. Until the
+// journal is played back, the database file may hold a mix of old and new pages.
+pub struct HotJournal {
+    path: String,
+    contents: Vec<u8>,
+    header: JournalHeader,
+}
+
+impl HotJournal {
+    // Look for a hot journal next to the database at the given path
+    pub fn find(db_path: &str) -> Result<Option<Self>, anyhow::Error> {
+        let path = format!("{db_path}-journal");
+        let mut contents = Vec::new();
+        match File::open(&path) {
+            Ok(mut file) => file.read_to_end(&mut contents)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        // Empty journals and journals with a zeroed header are left behind
+        // by committed transactions
+        Ok(parse_header(&contents).map(|header| Self {
+            path,
+            contents,
+            header,
+        }))
+    }
+
+    // Collect the original pages saved in the journal, as page numbers and
+    // page contents. Playback stops at the first record that fails its
+    // checksum, since the journal may not have been fully written.
+    fn records(&self) -> Result<Vec<(u32, &[u8])>, anyhow::Error> {
+        let mut records = Vec::new();
+        let mut seen = HashSet::new();
+        let mut offset = 0;
+        while let Some(header) = self.contents.get(offset..).and_then(parse_header) {
+            let page_size = header.page_size as usize;
+            let sector_size = header.sector_size as usize;
+            if !page_size.is_power_of_two() || sector_size < JOURNAL_HEADER_SIZE {
+                bail!("Invalid rollback journal header");
+            }
+            let record_size = page_size + 8;
+            offset += sector_size;
+            let record_count = if header.record_count == JOURNAL_RECORDS_TO_END {
+                (self.contents.len().saturating_sub(offset) / record_size) as u32
+            } else {
+                header.record_count
+            };
+            for _ in 0..record_count {
+                let Some(record) = self.contents.get(offset..offset + record_size) else {
+                    return Ok(records);
+                };
+                let page_number = be_u32_at(record, 0);
+                let page = &record[4..4 + page_size];
+                if record_checksum(header.nonce, page) != be_u32_at(record, 4 + page_size) {
+                    return Ok(records);
+                }
+                // The first record of a page holds its original content
+                if page_number != 0 && seen.insert(page_number) {
+                    records.push((page_number, page));
+                }
+                offset += record_size;
+            }
+            // The next segment starts at the next sector boundary
+            offset = offset.div_ceil(sector_size) * sector_size;
+        }
+        Ok(records)
+    }
+
+    // Play the journal back: write the original pages into the database,
+    // truncate it to its original size, and delete the journal
+    pub fn rollback(self, db_path: &str) -> Result<(), anyhow::Error> {
+        let page_size = self.header.page_size as u64;
+        let mut db = OpenOptions::new().write(true).open(db_path)?;
+        for (page_number, page) in self.records()? {
+            if page_number > self.header.initial_size {
+                continue;
+            }
+            db.seek(SeekFrom::Start((page_number as u64 - 1) * page_size))?;
+            db.write_all(page)?;
+        }
+        db.set_len(self.header.initial_size as u64 * page_size)?;
+        db.sync_all()?;
+        std::fs::remove_file(&self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE_SIZE: usize = 512;
+    const SECTOR_SIZE: usize = 512;
+
+    fn header(record_count: u32, nonce: u32, initial_size: u32) -> Vec<u8> {
+        let mut header = JOURNAL_MAGIC.to_vec();
+        for field in [
+            record_count,
+            nonce,
+            initial_size,
+            SECTOR_SIZE as u32,
+            PAGE_SIZE as u32,
+        ] {
+            header.extend_from_slice(&field.to_be_bytes());
+        }
+        header.resize(SECTOR_SIZE, 0);
+        header
+    }
+
+    fn record(page_number: u32, fill: u8, nonce: u32) -> Vec<u8> {
+        let page = vec![fill; PAGE_SIZE];
+        let mut record = page_number.to_be_bytes().to_vec();
+        record.extend_from_slice(&page);
+        record.extend_from_slice(&record_checksum(nonce, &page).to_be_bytes());
+        record
+    }
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("{name}-{}.db", std::process::id()));
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_record_checksum() {
+        let mut page = vec![0; 512];
+        page[312] = 3;
+        page[112] = 4;
+        // Bytes that aren't sampled don't change the checksum
+        page[0] = 100;
+        assert_eq!(record_checksum(10, &page), 17);
+    }
+
+    #[test]
+    fn test_rollback() {
+        let db_path = temp_path("journal-rollback");
+        // The interrupted transaction changed page 2 and grew the database
+        // from two pages to three
+        let mut db = vec![1; PAGE_SIZE];
+        db.extend(vec![9; PAGE_SIZE * 2]);
+        std::fs::write(&db_path, &db).unwrap();
+        let mut journal = header(2, 42, 2);
+        journal.extend(record(2, 2, 42));
+        // A torn record that fails its checksum is ignored
+        let mut torn = record(1, 7, 42);
+        let last = torn.len() - 1;
+        torn[last] ^= 0xff;
+        journal.extend(torn);
+        std::fs::write(format!("{db_path}-journal"), &journal).unwrap();
+
+        let hot = HotJournal::find(&db_path).unwrap().unwrap();
+        hot.rollback(&db_path).unwrap();
+        let mut expected = vec![1; PAGE_SIZE];
+        expected.extend(vec![2; PAGE_SIZE]);
+        assert_eq!(std::fs::read(&db_path).unwrap(), expected);
+        assert!(HotJournal::find(&db_path).unwrap().is_none());
+        std::fs::remove_file(db_path).unwrap();
+    }
+
+    #[test]
+    fn test_zeroed_journal_is_not_hot() {
+        let db_path = temp_path("journal-zeroed");
+        let journal_path = format!("{db_path}-journal");
+        std::fs::write(&journal_path, vec![0; SECTOR_SIZE]).unwrap();
+        assert!(HotJournal::find(&db_path).unwrap().is_none());
+        std::fs::write(&journal_path, []).unwrap();
+        assert!(HotJournal::find(&db_path).unwrap().is_none());
+        std::fs::remove_file(journal_path).unwrap();
+    }
+}
@@ -0,0 +1,323 @@
+use anyhow::{anyhow, bail};
+use nom::{
+    branch::alt,
+    bytes::complete::{tag, take_until, take_while, take_while1},
+    character::complete::{char, digit0, digit1, multispace1, one_of},
+    combinator::{map, opt, recognize, value},
+    multi::many0,
+    sequence::{delimited, pair, preceded, tuple},
+    IResult,
+};
+
+// Represents a single SQL token
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    // A bare word, which is either a keyword or an identifier depending on
+    // where it appears
+    Word(String),
+    // An identifier quoted with "", [] or ``, which is never a keyword
+    QuotedIdentifier(String),
+    String(String),
+    // A numeric literal, kept as written until the parser decides its type
+    Number(String),
+    Blob(Vec<u8>),
+    // An operator or punctuation
+    Symbol(&'static str),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Word(s) | Token::Number(s) => write!(f, "{s}"),
+            Token::QuotedIdentifier(s) => write!(f, "\"{}\"", s.replace('"', "\"\"")),
+            Token::String(s) => write!(f, "'{}'", s.replace('\'', "''")),
+            Token::Blob(b) => {
+                write!(f, "x'")?;
+                for byte in b {
+                    write!(f, "{byte:02X}")?;
+                }
+                write!(f, "'")
+            }
+            Token::Symbol(s) => write!(f, "{s}"),
+        }
+    }
+}
+
+// Operators and punctuation, with longer symbols before their prefixes
+const SYMBOLS: [&str; 25] = [
+    "||", "<=", ">=", "<>", "!=", "==", "<<", ">>", "(", ")", ",", ";", ".", "=", "<", ">", "+",
+    "-", "*", "/", "%", "&", "|", "~", "?",
+];
+
+// Shorthand type for a lexer result
+type LexResult<'a, T> = IResult<&'a str, T>;
+
+// Skips whitespace, line comments and block comments. An unterminated block
+// comment runs to the end of the input.
+fn skip(input: &str) -> LexResult<'_, ()> {
+    value(
+        (),
+        many0(alt((
+            value((), multispace1),
+            value((), pair(tag("--"), take_while(|c| c != '\n'))),
+            value(
+                (),
+                pair(tag("/*"), alt((terminated_by("*/"), take_while(|_| true)))),
+            ),
+        ))),
+    )(input)
+}
+
+// Parses everything up to and including a terminator
+fn terminated_by<'a>(end: &'static str) -> impl FnMut(&'a str) -> LexResult<'a, &'a str> {
+    move |input| {
+        let (rest, body) = take_until(end)(input)?;
+        let (rest, _) = tag(end)(rest)?;
+        Ok((rest, body))
+    }
+}
+
+// Parses text delimited by a quote character, where a doubled quote stands
+// for the quote itself
+fn quoted(quote: char) -> impl FnMut(&str) -> LexResult<'_, String> {
+    move |input| {
+        let (mut rest, _) = char(quote)(input)?;
+        let mut res = String::new();
+        loop {
+            let end = rest.find(quote).ok_or_else(|| {
+                nom::Err::Failure(nom::error::Error::new(input, nom::error::ErrorKind::Char))
+            })?;
+            res.push_str(&rest[..end]);
+            rest = &rest[end + quote.len_utf8()..];
+            match rest.strip_prefix(quote) {
+                Some(after) => {
+                    res.push(quote);
+                    rest = after;
+                }
+                None => return Ok((rest, res)),
+            }
+        }
+    }
+}
+
+fn is_word_start(c: char) -> bool {
+    c.is_alphabetic() || c == '_' || !c.is_ascii()
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '$' || !c.is_ascii()
+}
+
+fn word(input: &str) -> LexResult<'_, Token> {
+    map(
+        recognize(pair(take_while1(is_word_start), take_while(is_word_char))),
+        |s: &str| Token::Word(s.to_string()),
+    )(input)
+}
+
+fn quoted_identifier(input: &str) -> LexResult<'_, Token> {
+    map(
+        alt((
+            quoted('"'),
+            quoted('`'),
+            map(
+                delimited(char('['), take_until("]"), char(']')),
+                |s: &str| s.to_string(),
+            ),
+        )),
+        Token::QuotedIdentifier,
+    )(input)
+}
+
+fn string(input: &str) -> LexResult<'_, Token> {
+    map(quoted('\''), Token::String)(input)
+}
+
+// Parses a blob literal, x'...' with an even number of hex digits. Once
+// x' is read, anything else up to the closing quote makes the whole literal
+// an unrecognized token, so the error is reported where the literal ends.
+fn blob(input: &str) -> LexResult<'_, Token> {
+    let (rest, hex) = preceded(pair(one_of("xX"), char('\'')), take_while(|c| c != '\''))(input)?;
+    let (rest, closed) = opt(char('\''))(rest)?;
+    if closed.is_none() || hex.len() % 2 != 0 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(nom::Err::Failure(nom::error::Error::new(
+            rest,
+            nom::error::ErrorKind::HexDigit,
+        )));
+    }
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect();
+    Ok((rest, Token::Blob(bytes)))
+}
+
+// Parses a numeric literal: a hexadecimal integer, or a decimal number with
+// an optional fraction and exponent
+fn number(input: &str) -> LexResult<'_, Token> {
+    let hex = recognize(pair(
+        alt((tag("0x"), tag("0X"))),
+        take_while1(|c: char| c.is_ascii_hexdigit()),
+    ));
+    let exponent = tuple((one_of("eE"), opt(one_of("+-")), digit1));
+    let decimal = recognize(tuple((
+        alt((
+            recognize(pair(digit1, opt(pair(char('.'), digit0)))),
+            recognize(pair(char('.'), digit1)),
+        )),
+        opt(exponent),
+    )));
+    let (rest, n) = alt((hex, decimal))(input)?;
+    // A number can't run straight into a word, as in 12abc
+    if rest.starts_with(is_word_char) {
+        return Err(nom::Err::Failure(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Digit,
+        )));
+    }
+    Ok((rest, Token::Number(n.to_string())))
+}
+
+fn symbol(input: &str) -> LexResult<'_, Token> {
+    for s in SYMBOLS {
+        if let Some(rest) = input.strip_prefix(s) {
+            return Ok((rest, Token::Symbol(s)));
+        }
+    }
+    Err(nom::Err::Error(nom::error::Error::new(
+        input,
+        nom::error::ErrorKind::Tag,
+    )))
+}
+
+fn token(input: &str) -> LexResult<'_, Token> {
+    alt((blob, word, quoted_identifier, string, number, symbol))(input)
+}
+
+// Splits SQL text into tokens, skipping whitespace and comments
+pub fn tokenize(input: &str) -> Result<Vec<Token>, anyhow::Error> {
+    let mut tokens = Vec::new();
+    let (mut rest, _) = skip(input).map_err(|e| anyhow!("{e}"))?;
+    while !rest.is_empty() {
+        match token(rest) {
+            Ok((after, token)) => {
+                tokens.push(token);
+                rest = skip(after).map_err(|e| anyhow!("{e}"))?.0;
+            }
+            // A token that is only found to be wrong partway, like a bad blob
+            // literal, reports where it ends
+            Err(nom::Err::Failure(e)) if e.input.len() < rest.len() => {
+                bail!(
+                    "unrecognized token: \"{}\"",
+                    &rest[..rest.len() - e.input.len()]
+                );
+            }
+            Err(_) => {
+                // Report the rest of the current word or symbol
+                let end = rest
+                    .char_indices()
+                    .skip(1)
+                    .find(|(_, c)| c.is_whitespace())
+                    .map_or(rest.len(), |(i, _)| i);
+                bail!("unrecognized token: \"{}\"", &rest[..end]);
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn word(s: &str) -> Token {
+        Token::Word(s.to_string())
+    }
+
+    #[test]
+    fn test_tokenize_select() {
+        let tokens = tokenize("SELECT id, name FROM t WHERE name = 'Bob';").unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                word("SELECT"),
+                word("id"),
+                Token::Symbol(","),
+                word("name"),
+                word("FROM"),
+                word("t"),
+                word("WHERE"),
+                word("name"),
+                Token::Symbol("="),
+                Token::String("Bob".to_string()),
+                Token::Symbol(";"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_quoted_identifiers() {
+        let tokens = tokenize(r#""a ""b""" [c d] `e``f` "select""#).unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::QuotedIdentifier("a \"b\"".to_string()),
+                Token::QuotedIdentifier("c d".to_string()),
+                Token::QuotedIdentifier("e`f".to_string()),
+                Token::QuotedIdentifier("select".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_literals() {
+        let tokens = tokenize("'it''s' 42 3.5 .5 1e10 2.5E-3 0x1F x'0aFF' X''").unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::String("it's".to_string()),
+                Token::Number("42".to_string()),
+                Token::Number("3.5".to_string()),
+                Token::Number(".5".to_string()),
+                Token::Number("1e10".to_string()),
+                Token::Number("2.5E-3".to_string()),
+                Token::Number("0x1F".to_string()),
+                Token::Blob(vec![0x0a, 0xff]),
+                Token::Blob(vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_operators_and_comments() {
+        let tokens = tokenize("a<=b -- comment\n||c/* block */<>d != e==f /* open").unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                word("a"),
+                Token::Symbol("<="),
+                word("b"),
+                Token::Symbol("||"),
+                word("c"),
+                Token::Symbol("<>"),
+                word("d"),
+                Token::Symbol("!="),
+                word("e"),
+                Token::Symbol("=="),
+                word("f"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(tokenize("'unterminated").is_err());
+        let err = |sql| tokenize(sql).unwrap_err().to_string();
+        assert_eq!(err("x'abc'"), "unrecognized token: \"x'abc'\"");
+        assert_eq!(err("select x'zz', 1"), "unrecognized token: \"x'zz'\"");
+        assert_eq!(err("x'a b' c"), "unrecognized token: \"x'a b'\"");
+        assert_eq!(err("x'ab"), "unrecognized token: \"x'ab\"");
+        assert!(tokenize("12abc").is_err());
+        assert!(tokenize("a # b").is_err());
+    }
+}
@@ -1,58 +1,131 @@
+mod aggregate;
+mod ast;
+mod cache;
 mod data;
+mod executor;
+mod functions;
+mod join;
+mod journal;
+mod lexer;
 mod parser;
+mod pattern;
+mod printf;
+mod serializer;
+mod sql_parser;
+mod storage;
+mod wal;
+mod window;
 
 use anyhow::{anyhow, bail, Result};
-use data::get_rows;
-use data::Database;
-use parser::{parse_cell, parse_page, Data};
+use cache::CacheSize;
+use data::{Database, DatabaseOptions};
+use parser::{Data, PageValue};
+use storage::Backend;
 
-use std::fs::File;
-use std::io::prelude::*;
-
-use crate::parser::parse_cell_pointers;
+// Parses the options that come before the database path, in the style of the
+// sqlite3 shell. Returns the options and the remaining arguments.
+fn parse_options(args: &[String]) -> Result<(DatabaseOptions, bool, &[String])> {
+    let mut options = DatabaseOptions::default();
+    let mut stats = false;
+    let mut rest = args;
+    while let Some((arg, tail)) = rest.split_first() {
+        if !arg.starts_with('-') {
+            break;
+        }
+        rest = tail;
+        match arg.as_str() {
+            // Map the file into memory instead of reading it through the page cache
+            "-mmap" => options.backend = Backend::Mmap,
+            // A positive size is a number of pages, and a negative one a number
+            // of KiB, like PRAGMA cache_size
+            "-cachesize" => {
+                let (size, tail) = rest
+                    .split_first()
+                    .ok_or(anyhow!("Missing argument to -cachesize"))?;
+                rest = tail;
+                let size = size
+                    .parse::<i64>()
+                    .map_err(|_| anyhow!("Invalid cache size {size}"))?;
+                options.cache_size = if size < 0 {
+                    CacheSize::Bytes(size.unsigned_abs() as usize * 1024)
+                } else {
+                    CacheSize::Pages(size as usize)
+                };
+            }
+            // Print the page cache hits and misses after the command
+            "-stats" => stats = true,
+            _ => bail!("Unknown option {arg}"),
+        }
+    }
+    Ok((options, stats, rest))
+}
 
 fn main() -> Result<()> {
     // Parse arguments
-    let args = std::env::args().collect::<Vec<_>>();
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let (options, stats, args) = parse_options(&args)?;
     match args.len() {
-        0 | 1 => bail!("Missing <database path> and <command>"),
-        2 => bail!("Missing <command>"),
+        0 => bail!("Missing <database path> and <command>"),
+        1 => bail!("Missing <command>"),
         _ => {}
     }
 
     // Parse command and act accordingly
-    let command = &args[2];
-    match command.as_str() {
+    let command = &args[1];
+    let db = match command.as_str() {
         ".dbinfo" => {
-            let mut file = File::open(&args[1])?;
-            let mut buf = Vec::new();
-            file.read_to_end(&mut buf)?;
-            let (rest, header) = parser::parse_header(&buf).map_err(|e| anyhow::anyhow!("{e}"))?;
-            let (rest, page_header) =
-                parser::parse_page_header(rest).map_err(|e| anyhow::anyhow!("{e}"))?;
-            let (_rest, _cell_pointers) = parse_cell_pointers(rest, page_header.number_of_cells)
-                .map_err(|e| anyhow::anyhow!("{e}"))?;
+            let db = Database::open(&args[0], options)?;
+            let schema_page = db.parse_page_at(1)?;
+            let header = db.header();
+            let encoding = match header.text_encoding {
+                1 => " (utf8)",
+                2 => " (utf16le)",
+                3 => " (utf16be)",
+                _ => "",
+            };
 
-            println!("database page size: {}", header.page_size);
-            println!("number of tables: {}", page_header.number_of_cells);
+            println!("database page size: {}", db.page_size());
+            println!("write format: {}", header.file_format_write_version);
+            println!("read format: {}", header.file_format_read_version);
+            println!(
+                "reserved bytes: {}",
+                header.bytes_reserved_at_end_of_each_page
+            );
+            println!("file change counter: {}", header.file_change_counter);
+            println!("database page count: {}", header.database_size_in_pages);
+            println!("freelist page count: {}", header.number_of_freelist_pages);
+            println!("schema cookie: {}", header.schema_cookie);
+            println!("schema format: {}", header.schema_format_number);
+            println!("default cache size: {}", header.default_page_cache_size);
+            println!(
+                "autovacuum top root: {}",
+                header.largest_root_btree_page_number
+            );
+            println!("incremental vacuum: {}", header.incremental_vacuum_mode);
+            println!("text encoding: {}{encoding}", header.text_encoding);
+            println!("user version: {}", header.user_version);
+            println!("application id: {}", header.application_id);
+            println!("software version: {}", header.sqlite_version_number);
+            println!("number of tables: {}", schema_page.header.number_of_cells);
+            db
+        }
+
+        // Open the database in recovery mode, playing back any hot journal
+        ".rollback" => {
+            let options = DatabaseOptions {
+                recover_journal: true,
+                ..options
+            };
+            Database::open(&args[0], options)?
         }
 
         ".tables" => {
-            let mut file = File::open(&args[1])?;
-            let mut buf = Vec::new();
-            file.read_to_end(&mut buf)?;
-            let db = &buf[100..];
-            let (rest, page_header) =
-                parser::parse_page_header(db).map_err(|e| anyhow::anyhow!("{e}"))?;
-            let (_rest, cell_pointers) = parse_cell_pointers(rest, page_header.number_of_cells)
-                .map_err(|e| anyhow::anyhow!("{e}"))?;
+            let db = Database::open(&args[0], options)?;
             let mut res = Vec::new();
-            for p in cell_pointers {
-                let (_rest, cell) = parse_cell(&db[p as usize - 100..], page_header.page_type)
-                    .map_err(|e| anyhow!("{e}"))?;
-                match cell {
-                    parser::Cell::TableLeaf(content) => {
-                        if let parser::Data::Text(s) = content.payload[2].clone() {
+            for value in db.schema() {
+                match value {
+                    PageValue::LeafTable { payload, .. } => {
+                        if let Data::Text(s) = payload[2].clone() {
                             res.push(s);
                         }
                     }
@@ -62,102 +135,29 @@ fn main() -> Result<()> {
             for r in res {
                 print!("{r} ");
             }
+            db
         }
         s => {
-            let db = Database::new(&args[1])?;
-            match parser::parse_select(s) {
-                Ok((_rest, (names, table, where_))) => {
-                    let root_page = db.get_root_page(table)?;
-                    if let Some(Data::Integer(n)) = root_page {
-                        let leaf_pages = data::get_pages(n as usize, &db)?;
-                        let mut values = Vec::new();
-                        for leaf in &leaf_pages {
-                            let (_rest, page) = parse_page(&db.read_page_at(*leaf as u64)?, false)
-                                .map_err(|e| anyhow!("{e}"))?;
-                            values.extend(page.values);
-                        }
-                        if names == ["count(*)"] {
-                            println!("{}", values.len());
-                        // If there is a where clause reference an indexed column, use the index
-                        } else if where_.is_some()
-                            && db
-                                .find_index_root(&where_.as_ref().unwrap().column, table) //Unwrap is safe due to is_some check
-                                .is_some()
-                        {
-                            if let Some(where_) = where_ {
-                                // I've only implemented index search for one column,
-                                // and only for text values
-                                let rows = db.find_by_index(
-                                    &where_.column,
-                                    table,
-                                    Data::Text(where_.value.clone()),
-                                )?;
-                                let create_table = db.get_create_table(table)?;
-                                // Use the create-table statement to get the column names
-                                if let Some(Data::Text(_s)) = create_table {
-                                    let row_maps = rows
-                                        .iter()
-                                        .map(|row| db.match_row_with_column_names(row, table))
-                                        .collect::<Result<Vec<_>, _>>()?;
-                                    for map in row_maps.iter().filter(|m| {
-                                        m.get(&where_.column)
-                                            == Some(&Data::Text(where_.value.clone()))
-                                    }) {
-                                        let mut row_values = Vec::new();
-                                        for name in &names {
-                                            if let Some(value) = map.get(*name) {
-                                                row_values.push(value);
-                                            }
-                                        }
-                                        println!(
-                                            "{}",
-                                            row_values
-                                                .iter()
-                                                .map(|v| v.to_string())
-                                                .collect::<Vec<_>>()
-                                                .join("|")
-                                        );
-                                    }
-                                }
-                            }
-                        // Handle the general case where there is no index
-                        } else {
-                            let create_table = db.get_create_table(table)?;
-                            if let Some(Data::Text(s)) = create_table {
-                                let (_rest, columns) =
-                                    parser::parse_create_table(&s).map_err(|e| anyhow!("{e}"))?;
-                                let rows = leaf_pages
-                                    .iter()
-                                    .flat_map(|i| {
-                                        let (_rest, page) =
-                                            parse_page(&db.read_page_at(*i as u64)?, false)
-                                                .map_err(|e| anyhow!("{e}"))?;
-                                        get_rows(&page, &columns, where_.clone())
-                                    })
-                                    .flatten();
-                                for row in rows {
-                                    let mut row_values = Vec::new();
-                                    for name in &names {
-                                        if let Some(value) = row.get(*name) {
-                                            row_values.push(value);
-                                        }
-                                    }
-                                    println!(
-                                        "{}",
-                                        row_values
-                                            .iter()
-                                            .map(|v| v.to_string())
-                                            .collect::<Vec<_>>()
-                                            .join("|")
-                                    );
-                                }
-                            }
-                        }
-                    }
-                }
-                Err(e) => bail!("{e}"),
+            let db = Database::open(&args[0], options)?;
+            let statement = sql_parser::parse_statement(s)?;
+            for row in executor::execute(&db, &statement)? {
+                let row = row?;
+                println!(
+                    "{}",
+                    row.iter()
+                        .map(|v| v.to_string())
+                        .collect::<Vec<_>>()
+                        .join("|")
+                );
             }
+            db
         }
+    };
+
+    if stats {
+        let stats = db.cache_stats();
+        println!("Page cache hits: {}", stats.hits);
+        println!("Page cache misses: {}", stats.misses);
     }
 
     Ok(())
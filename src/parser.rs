@@ -1,17 +1,14 @@
+use crate::storage::PageBytes;
+use anyhow::anyhow;
 use nom::{
-    branch::alt,
-    bytes::complete::{tag, tag_no_case, take, take_while1},
-    character::complete::{anychar, char, multispace0},
-    combinator::{cond, consumed, map, opt},
-    error::ParseError,
-    multi::{count, many0, many_till, separated_list0, separated_list1},
-    number::complete::{
-        be_f64, be_i16, be_i24, be_i32, be_i64, be_i8, be_u16, be_u24, be_u32, be_u8,
-    },
-    sequence::{delimited, preceded, terminated, tuple},
+    bytes::complete::{tag, take},
+    combinator::consumed,
+    error::{ErrorKind, ParseError},
+    number::complete::{be_f64, be_i16, be_i24, be_i32, be_i64, be_i8, be_u16, be_u32, be_u8},
+    sequence::{terminated, tuple},
     IResult,
 };
-use regex::Regex;
+use std::cmp::Ordering;
 
 // Flag values for the types of table page
 const PAGE_TYPE_INTERIOR_INDEX: u8 = 2;
@@ -20,10 +17,11 @@ const PAGE_TYPE_LEAF_INDEX: u8 = 10;
 const PAGE_TYPE_LEAF_TABLE: u8 = 13;
 
 // Represents the header of a SQLite database file
+#[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct Header {
     pub magic: String,
-    pub page_size: u16,
+    pub page_size: u32,
     pub file_format_write_version: u8,
     pub file_format_read_version: u8,
     pub bytes_reserved_at_end_of_each_page: u8,
@@ -50,7 +48,7 @@ pub struct Header {
 type ParseResult<'a, T, I = &'a [u8], E = nom::error::Error<I>> = IResult<I, T, E>;
 
 // Parses the header of a SQLite database file
-pub fn parse_header(input: &[u8]) -> ParseResult<Header> {
+pub fn parse_header(input: &[u8]) -> ParseResult<'_, Header> {
     // The tuple parser has limited length, so we need to split it into two
     let mut first_parser = tuple((
         terminated(tag("SQLite format 3"), tag("\0")),
@@ -85,7 +83,8 @@ pub fn parse_header(input: &[u8]) -> ParseResult<Header> {
         rest,
         Header {
             magic: String::from_utf8(t1.0.to_vec()).unwrap(),
-            page_size: t1.1,
+            // The page size 65536 doesn't fit in two bytes, so it is stored as 1
+            page_size: if t1.1 == 1 { 65536 } else { t1.1 as u32 },
             file_format_write_version: t1.2,
             file_format_read_version: t1.3,
             bytes_reserved_at_end_of_each_page: t1.4,
@@ -111,6 +110,7 @@ pub fn parse_header(input: &[u8]) -> ParseResult<Header> {
 }
 
 // Represents the header of a SQLite database page
+#[allow(dead_code)]
 #[derive(Debug)]
 pub struct PageHeader {
     pub page_type: PageType,
@@ -131,7 +131,7 @@ pub enum PageType {
 }
 
 // Parses the type of a SQLite database page
-fn parse_page_type(input: &[u8]) -> ParseResult<PageType> {
+fn parse_page_type(input: &[u8]) -> ParseResult<'_, PageType> {
     let (rest, x) = be_u8(input)?;
     match x {
         PAGE_TYPE_INTERIOR_INDEX => Ok((rest, PageType::InteriorIndex)),
@@ -146,7 +146,7 @@ fn parse_page_type(input: &[u8]) -> ParseResult<PageType> {
 }
 
 // Parses the header of a SQLite database page
-pub fn parse_page_header(input: &[u8]) -> ParseResult<PageHeader> {
+pub fn parse_page_header(input: &[u8]) -> ParseResult<'_, PageHeader> {
     let (_rest, page_type) = parse_page_type(input)?;
     match page_type {
         // Interior pages include a right-most pointer
@@ -183,7 +183,7 @@ pub fn parse_page_header(input: &[u8]) -> ParseResult<PageHeader> {
 }
 
 // Parses the cell pointers of a SQLite database page
-pub fn parse_cell_pointers(input: &[u8], number_of_cells: u16) -> ParseResult<Vec<u16>> {
+pub fn parse_cell_pointers(input: &[u8], number_of_cells: u16) -> ParseResult<'_, Vec<u16>> {
     let parser = take(number_of_cells as usize * 2);
     let (rest, cells) = parser(input)?;
     let mut res = Vec::<u16>::new();
@@ -196,11 +196,12 @@ pub fn parse_cell_pointers(input: &[u8], number_of_cells: u16) -> ParseResult<Ve
     Ok((rest, res))
 }
 
-// Parses a variable-length integer
-fn varint(input: &[u8]) -> ParseResult<i64> {
+// Parses a variable-length integer. The first eight bytes hold seven bits
+// each, and a ninth byte, if there is one, holds eight bits.
+pub fn varint(input: &[u8]) -> ParseResult<'_, i64> {
     let mut res = 0;
     let mut index = 0;
-    while index < 9 {
+    while index < 8 {
         let (rest, byte) = be_u8(&input[index as usize..])?;
         let byte = i64::from_be_bytes([0, 0, 0, 0, 0, 0, 0, byte]);
         let add = byte & 0b0111_1111_i64;
@@ -210,14 +211,13 @@ fn varint(input: &[u8]) -> ParseResult<i64> {
         }
         index += 1;
     }
-    Err(nom::Err::Error(nom::error::Error::new(
-        input,
-        nom::error::ErrorKind::Fail,
-    )))
+    let (rest, byte) = be_u8(&input[index as usize..])?;
+    Ok((rest, (res << 8) | byte as i64))
 }
 
 // Represent a SQLite database cell, depending on the type of page
 
+#[allow(dead_code)]
 #[derive(Debug)]
 pub struct TableLeafCell {
     pub row_id: i64,
@@ -254,24 +254,158 @@ pub enum Cell {
     IndexInterior(IndexInteriorCell),
 }
 
-// Overflow cells have not been implemented yet, so we assume there are none
-fn does_overflow(_u: usize, _p: usize) -> bool {
-    false
+// The parts of the database header that determine how much of a cell's
+// payload is stored on its own page and how much spills onto overflow pages
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PageLayout {
+    pub usable_size: usize,
+    pub max_embedded_payload_fraction: u8,
+    pub min_embedded_payload_fraction: u8,
+    pub leaf_payload_fraction: u8,
+}
+
+impl PageLayout {
+    pub fn from_header(header: &Header) -> Self {
+        Self {
+            usable_size: header.page_size as usize
+                - header.bytes_reserved_at_end_of_each_page as usize,
+            max_embedded_payload_fraction: header.max_embedded_payload_fraction,
+            min_embedded_payload_fraction: header.min_embedded_payload_fraction,
+            leaf_payload_fraction: header.leaf_payload_fraction,
+        }
+    }
+
+    // Given the total size of a cell's payload, return the number of bytes
+    // stored on the page itself. The rest is stored on overflow pages.
+    pub fn local_payload_size(&self, payload_size: usize, page_type: PageType) -> usize {
+        let u = self.usable_size;
+        let fraction = |f: u8| (u - 12) * f as usize / 255 - 23;
+        // Table leaves may fill the page; index cells must leave room for at
+        // least four cells per page
+        let (max_local, min_local) = match page_type {
+            PageType::LeafTable => (u - 35, fraction(self.leaf_payload_fraction)),
+            _ => (
+                fraction(self.max_embedded_payload_fraction),
+                fraction(self.min_embedded_payload_fraction),
+            ),
+        };
+        if payload_size <= max_local {
+            return payload_size;
+        }
+        let k = min_local + (payload_size - min_local) % (u - 4);
+        if k <= max_local {
+            k
+        } else {
+            min_local
+        }
+    }
+}
+
+// Gives the cell parsers what they need beyond the bytes of the page being
+// parsed: the page layout, and a way to read overflow pages.
+pub trait PageSource {
+    fn layout(&self) -> PageLayout;
+    fn text_encoding(&self) -> TextEncoding;
+    fn overflow_page(&self, page_number: u32) -> Result<PageBytes, anyhow::Error>;
+}
+
+// Error for parsing the cells of a page: either the page is malformed, or an
+// overflow page couldn't be read
+#[derive(Debug)]
+pub enum PageError<'a> {
+    Malformed(nom::error::Error<&'a [u8]>),
+    Read(anyhow::Error),
+}
+
+impl<'a> ParseError<&'a [u8]> for PageError<'a> {
+    fn from_error_kind(input: &'a [u8], kind: ErrorKind) -> Self {
+        PageError::Malformed(nom::error::Error::new(input, kind))
+    }
+
+    fn append(_: &'a [u8], _: ErrorKind, other: Self) -> Self {
+        other
+    }
+}
+
+impl<'a> From<nom::error::Error<&'a [u8]>> for PageError<'a> {
+    fn from(e: nom::error::Error<&'a [u8]>) -> Self {
+        PageError::Malformed(e)
+    }
+}
+
+// Converts an error from parsing a page to one that doesn't borrow the page.
+// Errors reading overflow pages are passed on as they are.
+pub fn page_error(e: nom::Err<PageError>) -> anyhow::Error {
+    match e {
+        nom::Err::Error(PageError::Read(e)) | nom::Err::Failure(PageError::Read(e)) => e,
+        nom::Err::Error(PageError::Malformed(e)) => anyhow!("{}", nom::Err::Error(e)),
+        nom::Err::Failure(PageError::Malformed(e)) => anyhow!("{}", nom::Err::Failure(e)),
+        nom::Err::Incomplete(needed) => anyhow!("{}", nom::Err::<()>::Incomplete(needed)),
+    }
+}
+
+// Error for input that is well-formed on its own, but inconsistent with the
+// rest of the database
+fn fail<'a, E: ParseError<&'a [u8]>>(input: &'a [u8]) -> nom::Err<E> {
+    nom::Err::Error(E::from_error_kind(input, ErrorKind::Fail))
+}
+
+// Parses the payload of a cell, which starts with the local part of the
+// payload. If the payload overflows, the local part is followed by the first
+// overflow page number, and the rest is read from the overflow chain.
+fn parse_payload<'a>(
+    input: &'a [u8],
+    payload_size: usize,
+    page_type: PageType,
+    source: &dyn PageSource,
+) -> ParseResult<'a, (Vec<Data>, Option<u32>), &'a [u8], PageError<'a>> {
+    let layout = source.layout();
+    let local_size = layout.local_payload_size(payload_size, page_type);
+    let (rest, local) = take(local_size)(input)?;
+    if local_size == payload_size {
+        let (_, record) = parse_record(local, source.text_encoding()).map_err(nom::Err::convert)?;
+        return Ok((rest, (record, None)));
+    }
+    let (rest, first_overflow_page) = be_u32(rest)?;
+    let mut payload = local.to_vec();
+    let mut next_page = first_overflow_page;
+    // Each overflow page starts with the number of the next page in the chain
+    while payload.len() < payload_size {
+        if next_page == 0 {
+            return Err(fail(input));
+        }
+        let page = source
+            .overflow_page(next_page)
+            .map_err(|e| nom::Err::Failure(PageError::Read(e)))?;
+        let n = (payload_size - payload.len()).min(layout.usable_size - 4);
+        if page.len() < n + 4 {
+            return Err(fail(input));
+        }
+        next_page = u32::from_be_bytes([page[0], page[1], page[2], page[3]]);
+        payload.extend_from_slice(&page[4..n + 4]);
+    }
+    let (_, record) = parse_record(&payload, source.text_encoding()).map_err(|_| fail(input))?;
+    Ok((rest, (record, Some(first_overflow_page))))
 }
 
 // Parses a SQLite database cell
-pub fn parse_cell(input: &[u8], page_type: PageType) -> ParseResult<Cell> {
+pub fn parse_cell<'a>(
+    input: &'a [u8],
+    page_type: PageType,
+    source: &dyn PageSource,
+) -> ParseResult<'a, Cell, &'a [u8], PageError<'a>> {
+    let varint = |input| varint(input).map_err(nom::Err::convert);
     match page_type {
         PageType::LeafTable => {
             let (rest, payload_size) = varint(input)?;
             let (rest, row_id) = varint(rest)?;
-            let (rest, payload) = take(payload_size as usize)(rest)?;
-            let (rest, overflow_page) = cond(does_overflow(0, 0), be_u32)(rest)?;
+            let (rest, (payload, overflow_page)) =
+                parse_payload(rest, payload_size as usize, page_type, source)?;
             Ok((
                 rest,
                 Cell::TableLeaf(TableLeafCell {
                     row_id,
-                    payload: parse_record(payload)?.1,
+                    payload,
                     overflow_page,
                 }),
             ))
@@ -289,14 +423,12 @@ pub fn parse_cell(input: &[u8], page_type: PageType) -> ParseResult<Cell> {
         }
         PageType::LeafIndex => {
             let (rest, payload_size) = varint(input)?;
-            let (rest, payload) = take(payload_size as usize)(rest)?;
-            let (_rest, overflow_page) = cond(does_overflow(0, 0), be_u32)(rest)?;
-
-            let (rest, record) = parse_record(payload)?;
+            let (rest, (payload, overflow_page)) =
+                parse_payload(rest, payload_size as usize, page_type, source)?;
             Ok((
                 rest,
                 Cell::IndexLeaf(IndexLeafCell {
-                    payload: record,
+                    payload,
                     overflow_page,
                 }),
             ))
@@ -304,17 +436,13 @@ pub fn parse_cell(input: &[u8], page_type: PageType) -> ParseResult<Cell> {
         PageType::InteriorIndex => {
             let (rest, left_child_page) = be_u32(input)?;
             let (rest, payload_size) = varint(rest)?;
-            let (rest, payload) = take(payload_size as usize)(rest)?;
-            let (_rest, overflow_page) = cond(does_overflow(0, 0), be_u32)(rest)?;
-
-            let (rest, record) = parse_record(payload)?;
-
+            let (rest, (payload, overflow_page)) =
+                parse_payload(rest, payload_size as usize, page_type, source)?;
             Ok((
                 rest,
                 Cell::IndexInterior(IndexInteriorCell {
                     left_child_page,
-                    // payload: all_consuming(parse_record)(payload)?.1,
-                    payload: record,
+                    payload,
                     overflow_page,
                 }),
             ))
@@ -332,20 +460,395 @@ pub enum Data {
     Blob(Vec<u8>),
 }
 
+impl Data {
+    // Compares two values in SQLite's sort order: NULLs first, then numbers,
+    // then text, then blobs. Text is compared byte by byte, like the BINARY
+    // collation.
+    pub fn compare(&self, other: &Data) -> Ordering {
+        // The position of a value's storage class in the sort order
+        fn class(d: &Data) -> u8 {
+            match d {
+                Data::Null => 0,
+                Data::Integer(_) | Data::Float(_) => 1,
+                Data::Text(_) => 2,
+                Data::Blob(_) => 3,
+            }
+        }
+        match (self, other) {
+            (Data::Integer(a), Data::Integer(b)) => a.cmp(b),
+            (Data::Integer(a), Data::Float(b)) => compare_integer_float(*a, *b),
+            (Data::Float(a), Data::Integer(b)) => compare_integer_float(*b, *a).reverse(),
+            (Data::Float(a), Data::Float(b)) => a.partial_cmp(b).unwrap_or(Ordering::Equal),
+            (Data::Text(a), Data::Text(b)) => a.cmp(b),
+            (Data::Blob(a), Data::Blob(b)) => a.cmp(b),
+            _ => class(self).cmp(&class(other)),
+        }
+    }
+
+    // Compares two values like compare, but orders text by its bytes in the
+    // given encoding, which is the order of the database's indexes
+    pub fn compare_encoded(&self, other: &Data, encoding: TextEncoding) -> Ordering {
+        match (self, other) {
+            (Data::Text(a), Data::Text(b)) => encoding.compare(a, b),
+            _ => self.compare(other),
+        }
+    }
+
+    // Converts text to the text that compares the same under the BINARY
+    // collation as it does under another one, so that compare can order
+    // values by any collation. Other values are unchanged.
+    pub fn collated(self, collation: Collation) -> Data {
+        match (self, collation) {
+            (Data::Text(t), Collation::NoCase) => Data::Text(t.to_ascii_lowercase()),
+            (Data::Text(t), Collation::Rtrim) => Data::Text(t.trim_end_matches(' ').to_string()),
+            (value, _) => value,
+        }
+    }
+
+    // Converts a value to a column affinity. Text that looks like a number
+    // becomes a number under the numeric affinities, and numbers become
+    // text under TEXT affinity. Other values are unchanged.
+    pub fn apply_affinity(self, affinity: Affinity) -> Data {
+        match (affinity, self) {
+            (Affinity::Text, Data::Integer(i)) => Data::Text(i.to_string()),
+            (Affinity::Text, Data::Float(x)) => Data::Text(format_real(x)),
+            (a, Data::Text(s)) if a.is_numeric() => match parse_numeric_text(&s) {
+                Some(Data::Float(x)) if a != Affinity::Real && is_lossless_integer(x) => {
+                    Data::Integer(x as i64)
+                }
+                Some(Data::Integer(i)) if a == Affinity::Real => Data::Float(i as f64),
+                Some(value) => value,
+                None => Data::Text(s),
+            },
+            (Affinity::Real, Data::Integer(i)) => Data::Float(i as f64),
+            (_, value) => value,
+        }
+    }
+
+    // Converts a value the way CAST does. Unlike a column affinity, CAST
+    // always converts: text becomes the number at its start, reals are
+    // truncated to integers, and anything can become text or a blob.
+    pub fn cast(self, affinity: Affinity) -> Data {
+        let text = |value: Data| match value {
+            Data::Blob(b) => String::from_utf8_lossy(&b).into_owned(),
+            value => value.to_string(),
+        };
+        match (affinity, self) {
+            (_, Data::Null) => Data::Null,
+            (Affinity::Text, value) => Data::Text(text(value)),
+            (Affinity::Blob, Data::Blob(b)) => Data::Blob(b),
+            (Affinity::Blob, value) => Data::Blob(text(value).into_bytes()),
+            (Affinity::Integer, Data::Integer(i)) => Data::Integer(i),
+            // Out of range reals are clamped to the nearest integer
+            (Affinity::Integer, Data::Float(x)) => Data::Integer(x as i64),
+            (Affinity::Integer, value) => Data::Integer(parse_integer_prefix(&text(value))),
+            (Affinity::Real, Data::Integer(i)) => Data::Float(i as f64),
+            (Affinity::Real, Data::Float(x)) => Data::Float(x),
+            (Affinity::Real, value) => match parse_numeric_prefix(&text(value)) {
+                Data::Integer(i) => Data::Float(i as f64),
+                number => number,
+            },
+            (Affinity::Numeric, value @ (Data::Integer(_) | Data::Float(_))) => value,
+            // Text that reads as a small enough whole number becomes an
+            // integer, even when it's written as a real
+            (Affinity::Numeric, value) => match parse_numeric_prefix(&text(value)) {
+                Data::Float(x)
+                    if x.fract() == 0.0
+                        && (-2251799813685248.0..2251799813685248.0).contains(&x) =>
+                {
+                    Data::Integer(x as i64)
+                }
+                number => number,
+            },
+        }
+    }
+}
+
+// Returns true if a real holds an integer that fits in 64 bits
+fn is_lossless_integer(x: f64) -> bool {
+    x.fract() == 0.0 && (-9223372036854775808.0..9223372036854775808.0).contains(&x)
+}
+
+// The type affinity of a column, which decides how values are converted
+// before they are compared with the column's values. Expressions that aren't
+// columns have BLOB affinity, which means no conversion.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Affinity {
+    Text,
+    Numeric,
+    Integer,
+    Real,
+    Blob,
+}
+
+impl Affinity {
+    // Derives an affinity from a declared column type, using the first of
+    // SQLite's rules that matches
+    pub fn from_declared_type(declared_type: &str) -> Affinity {
+        let t = declared_type.to_ascii_uppercase();
+        if t.contains("INT") {
+            Affinity::Integer
+        } else if t.contains("CHAR") || t.contains("CLOB") || t.contains("TEXT") {
+            Affinity::Text
+        } else if t.contains("BLOB") || t.is_empty() {
+            Affinity::Blob
+        } else if t.contains("REAL") || t.contains("FLOA") || t.contains("DOUB") {
+            Affinity::Real
+        } else {
+            Affinity::Numeric
+        }
+    }
+
+    pub fn is_numeric(self) -> bool {
+        matches!(self, Affinity::Numeric | Affinity::Integer | Affinity::Real)
+    }
+
+    // A declared type that has this affinity
+    pub fn declared_type(self) -> &'static str {
+        match self {
+            Affinity::Text => "TEXT",
+            Affinity::Numeric => "NUMERIC",
+            Affinity::Integer => "INTEGER",
+            Affinity::Real => "REAL",
+            Affinity::Blob => "",
+        }
+    }
+}
+
+// The collation of a column, which decides how its text is compared.
+// NOCASE folds ASCII letters to lower case, and RTRIM ignores trailing spaces.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Collation {
+    #[default]
+    Binary,
+    NoCase,
+    Rtrim,
+}
+
+impl Collation {
+    // Get one of SQLite's built-in collations by name, regardless of case
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "binary" => Some(Collation::Binary),
+            "nocase" => Some(Collation::NoCase),
+            "rtrim" => Some(Collation::Rtrim),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Collation::Binary => "BINARY",
+            Collation::NoCase => "NOCASE",
+            Collation::Rtrim => "RTRIM",
+        }
+    }
+}
+
+// Parses text that is a well-formed integer or real literal, allowing
+// surrounding whitespace. Integers too large for 64 bits become reals.
+pub fn parse_numeric_text(s: &str) -> Option<Data> {
+    let s = s.trim();
+    let digits = s.strip_prefix(['+', '-']).unwrap_or(s);
+    let well_formed = digits.starts_with(|c: char| c.is_ascii_digit() || c == '.')
+        && digits
+            .chars()
+            .all(|c| c.is_ascii_digit() || matches!(c, '.' | 'e' | 'E' | '+' | '-'));
+    if !well_formed {
+        return None;
+    }
+    if let Ok(i) = s.parse::<i64>() {
+        return Some(Data::Integer(i));
+    }
+    s.parse::<f64>().ok().map(Data::Float)
+}
+
+// Reads the longest prefix of text that is a number, after any leading
+// whitespace, the way SQLite converts text for arithmetic. Text that doesn't
+// start with a number is 0.
+pub fn parse_numeric_prefix(s: &str) -> Data {
+    let s = s.trim_start();
+    let bytes = s.as_bytes();
+    let digits = |from: usize| {
+        bytes[from.min(bytes.len())..]
+            .iter()
+            .take_while(|b| b.is_ascii_digit())
+            .count()
+    };
+    let mut end = matches!(bytes.first(), Some(b'+' | b'-')) as usize;
+    let integer_digits = digits(end);
+    end += integer_digits;
+    let mut is_real = false;
+    if bytes.get(end) == Some(&b'.') {
+        let fraction_digits = digits(end + 1);
+        if integer_digits + fraction_digits == 0 {
+            return Data::Integer(0);
+        }
+        is_real = true;
+        end += 1 + fraction_digits;
+    } else if integer_digits == 0 {
+        return Data::Integer(0);
+    }
+    if matches!(bytes.get(end), Some(b'e' | b'E')) {
+        let sign = matches!(bytes.get(end + 1), Some(b'+' | b'-')) as usize;
+        let exponent_digits = digits(end + 1 + sign);
+        if exponent_digits > 0 {
+            is_real = true;
+            end += 1 + sign + exponent_digits;
+        }
+    }
+    let prefix = &s[..end];
+    match prefix.parse::<i64>() {
+        Ok(i) if !is_real => Data::Integer(i),
+        _ => Data::Float(prefix.parse::<f64>().unwrap_or(0.0)),
+    }
+}
+
+// Reads the integer at the start of text, after any leading whitespace, the
+// way CAST converts text to INTEGER. Integers too large for 64 bits are
+// clamped, and text that doesn't start with one is 0.
+pub fn parse_integer_prefix(s: &str) -> i64 {
+    let s = s.trim_start();
+    let (negative, digits) = match s.as_bytes().first() {
+        Some(b'-') => (true, &s[1..]),
+        Some(b'+') => (false, &s[1..]),
+        _ => (false, s),
+    };
+    digits
+        .bytes()
+        .take_while(u8::is_ascii_digit)
+        .fold(0i64, |value, digit| {
+            let digit = (digit - b'0') as i64;
+            if negative {
+                value.saturating_mul(10).saturating_sub(digit)
+            } else {
+                value.saturating_mul(10).saturating_add(digit)
+            }
+        })
+}
+
+// Formats a real the way SQLite does, with up to 15 significant digits and
+// always with a decimal point or an exponent
+pub fn format_real(x: f64) -> String {
+    if x.is_nan() {
+        return "NaN".to_string();
+    }
+    if x.is_infinite() {
+        return if x > 0.0 { "Inf" } else { "-Inf" }.to_string();
+    }
+    let scientific = format!("{:.14e}", x.abs());
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let exponent = exponent.parse::<i32>().unwrap();
+    let digits = mantissa.replace('.', "");
+    let digits = digits.trim_end_matches('0');
+    let digits = if digits.is_empty() { "0" } else { digits };
+    let sign = if x.is_sign_negative() && x != 0.0 {
+        "-"
+    } else {
+        ""
+    };
+    if !(-4..15).contains(&exponent) {
+        let fraction = if digits.len() > 1 { &digits[1..] } else { "0" };
+        let exp_sign = if exponent < 0 { '-' } else { '+' };
+        return format!(
+            "{sign}{}.{fraction}e{exp_sign}{:02}",
+            &digits[..1],
+            exponent.abs()
+        );
+    }
+    if exponent < 0 {
+        let zeros = "0".repeat((-exponent - 1) as usize);
+        return format!("{sign}0.{zeros}{digits}");
+    }
+    let int_len = exponent as usize + 1;
+    if digits.len() <= int_len {
+        let zeros = "0".repeat(int_len - digits.len());
+        format!("{sign}{digits}{zeros}.0")
+    } else {
+        format!("{sign}{}.{}", &digits[..int_len], &digits[int_len..])
+    }
+}
+
+// Compares an integer with a float without rounding the integer, which
+// converting it to a float would do for large values
+fn compare_integer_float(i: i64, f: f64) -> Ordering {
+    if f.is_nan() || f < -9223372036854775808.0 {
+        return Ordering::Greater;
+    }
+    if f >= 9223372036854775808.0 {
+        return Ordering::Less;
+    }
+    match i.cmp(&(f.trunc() as i64)) {
+        Ordering::Equal => 0.0.partial_cmp(&f.fract()).unwrap_or(Ordering::Equal),
+        c => c,
+    }
+}
+
 impl std::fmt::Display for Data {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Data::Null => write!(f, "NULL"),
             Data::Integer(x) => write!(f, "{x}"),
-            Data::Float(x) => write!(f, "{x}"),
+            Data::Float(x) => write!(f, "{}", format_real(*x)),
             Data::Text(x) => write!(f, "{x}"),
             Data::Blob(x) => write!(f, "{x:?}"),
         }
     }
 }
 
-// Parses a SQLite database record
-pub fn parse_record(input: &[u8]) -> ParseResult<Vec<Data>> {
+// The encoding of all text values in a database, given by the header
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextEncoding {
+    Utf8,
+    Utf16le,
+    Utf16be,
+}
+
+impl TextEncoding {
+    // Get the encoding from the header's text encoding field. Databases that
+    // have never had a schema may leave the field as 0.
+    pub fn from_header(value: u32) -> Option<Self> {
+        match value {
+            0 | 1 => Some(TextEncoding::Utf8),
+            2 => Some(TextEncoding::Utf16le),
+            3 => Some(TextEncoding::Utf16be),
+            _ => None,
+        }
+    }
+
+    // Compare two strings by their bytes in this encoding. Comparing
+    // big-endian code units is the same as comparing their bytes, and
+    // swapping the bytes of each unit does the same for little-endian.
+    pub fn compare(&self, a: &str, b: &str) -> Ordering {
+        match self {
+            TextEncoding::Utf8 => a.cmp(b),
+            TextEncoding::Utf16le => a
+                .encode_utf16()
+                .map(u16::swap_bytes)
+                .cmp(b.encode_utf16().map(u16::swap_bytes)),
+            TextEncoding::Utf16be => a.encode_utf16().cmp(b.encode_utf16()),
+        }
+    }
+
+    // Decode a text value. Returns None for invalid sequences.
+    pub fn decode(&self, bytes: &[u8]) -> Option<String> {
+        let units = |from_bytes: fn([u8; 2]) -> u16| {
+            bytes
+                .chunks_exact(2)
+                .map(|c| from_bytes([c[0], c[1]]))
+                .collect::<Vec<_>>()
+        };
+        match self {
+            TextEncoding::Utf8 => String::from_utf8(bytes.to_vec()).ok(),
+            _ if !bytes.len().is_multiple_of(2) => None,
+            TextEncoding::Utf16le => String::from_utf16(&units(u16::from_le_bytes)).ok(),
+            TextEncoding::Utf16be => String::from_utf16(&units(u16::from_be_bytes)).ok(),
+        }
+    }
+}
+
+// Parses a SQLite database record, decoding text values with the given encoding
+pub fn parse_record(input: &[u8], encoding: TextEncoding) -> ParseResult<'_, Vec<Data>> {
     let (mut rest_outer, (bytes_consumed, header_size)) = consumed(varint)(input)?;
     let mut remaining_in_header = header_size - bytes_consumed.len() as i64;
     let mut serial_types = Vec::new();
@@ -380,10 +883,11 @@ pub fn parse_record(input: &[u8]) -> ParseResult<Vec<Data>> {
                 res.push(Data::Integer(x as i64));
             }
             5 => {
-                let (rest, xs) = count(be_u24, 2)(rest_outer)?;
-                let x = (*xs.first().unwrap() as u64 + (*xs.first().unwrap() as u64)) << 24;
+                // A 48-bit big-endian integer, sign-extended to 64 bits
+                let (rest, x) = take(6usize)(rest_outer)?;
+                let x = x.iter().fold(0i64, |acc, b| (acc << 8) | *b as i64);
                 rest_outer = rest;
-                res.push(Data::Integer(x as i64));
+                res.push(Data::Integer((x << 16) >> 16));
             }
             6 => {
                 let (rest, x) = be_i64(rest_outer)?;
@@ -406,8 +910,9 @@ pub fn parse_record(input: &[u8]) -> ParseResult<Vec<Data>> {
                 } else if (s % 2 == 1) & (s >= 13) {
                     let n = (s - 13) / 2;
                     let (rest, x) = take(n as usize)(rest_outer)?;
+                    let text = encoding.decode(x).ok_or_else(|| fail(rest_outer))?;
                     rest_outer = rest;
-                    res.push(Data::Text(String::from_utf8(x.to_vec()).unwrap()));
+                    res.push(Data::Text(text));
                 } else {
                     Err(nom::Err::Error(nom::error::Error::new(
                         input,
@@ -458,13 +963,20 @@ pub struct Page {
 }
 
 // Parses a SQLite database page. The is_first_page argument is used to skip the header of the first page.
-pub fn parse_page(input: &[u8], is_first_page: bool) -> ParseResult<Page> {
+pub fn parse_page<'a>(
+    input: &'a [u8],
+    is_first_page: bool,
+    source: &dyn PageSource,
+) -> ParseResult<'a, Page, &'a [u8], PageError<'a>> {
     let offset = if is_first_page { 100 } else { 0 };
-    let (rest, page_header) = parse_page_header(&input[offset..])?;
-    let (rest, cell_pointers) = parse_cell_pointers(rest, page_header.number_of_cells)?;
+    let (rest, page_header) = parse_page_header(input.get(offset..).ok_or_else(|| fail(input))?)
+        .map_err(nom::Err::convert)?;
+    let (rest, cell_pointers) =
+        parse_cell_pointers(rest, page_header.number_of_cells).map_err(nom::Err::convert)?;
     let mut res = Vec::new();
     for p in cell_pointers {
-        let (_rest, cell) = parse_cell(&input[p as usize..], page_header.page_type)?;
+        let cell_input = input.get(p as usize..).ok_or_else(|| fail(input))?;
+        let (_rest, cell) = parse_cell(cell_input, page_header.page_type, source)?;
         match cell {
             Cell::TableLeaf(content) => {
                 res.push(PageValue::LeafTable {
@@ -500,178 +1012,22 @@ pub fn parse_page(input: &[u8], is_first_page: bool) -> ParseResult<Page> {
     ))
 }
 
-// Combinator that parses a whitespace-delimited parser
-fn ws<'a, F: 'a, O, E: ParseError<&'a str>>(
-    inner: F,
-) -> impl FnMut(&'a str) -> IResult<&'a str, O, E>
-where
-    F: FnMut(&'a str) -> IResult<&'a str, O, E>,
-{
-    delimited(multispace0, inner, multispace0)
-}
-
-// Represents a column definition
-#[derive(Debug, PartialEq, Clone)]
-pub struct ColumnDef {
-    pub name: String,
-    pub modifiers: String,
-    pub ipk: bool, // is an integer primary key
-}
-
-// Represents a comparator that could appear in a WHERE clause
-#[allow(dead_code)]
-#[derive(Debug, PartialEq, Clone)]
-pub enum Comparator {
-    Eq,
-    Ne,
-    Lt,
-    Gt,
-    Le,
-    Ge,
-}
-
-// Represents a WHERE clause
-#[derive(Debug, PartialEq, Clone)]
-pub struct WhereClause {
-    pub column: String,
-    pub operator: Comparator,
-    pub value: String,
-}
-
-// Parses a WHERE clause
-fn parse_where(input: &str) -> ParseResult<WhereClause, &str> {
-    let (rest, _) = ws(tag_no_case("where"))(input)?;
-    let (rest, column) = ws(identifier)(rest)?;
-    let (rest, operator) = alt((
-        tag("="),
-        tag("!="),
-        tag("<"),
-        tag(">"),
-        tag("<="),
-        tag(">="),
-    ))(rest)?;
-    let (rest, value) = ws(preceded(
-        char('\''),
-        map(many_till(anychar, char('\'')), |(chars, _)| chars),
-    ))(rest)?;
-    Ok((
-        rest,
-        WhereClause {
-            column: column.to_string(),
-            operator: match operator {
-                "=" => Comparator::Eq,
-                // "!=" => Comparator::Ne,
-                // "<" => Comparator::Lt,
-                // ">" => Comparator::Gt,
-                // "<=" => Comparator::Le,
-                // ">=" => Comparator::Ge,
-                _ => {
-                    return Err(nom::Err::Error(nom::error::Error {
-                        input,
-                        code: nom::error::ErrorKind::Fail,
-                    }))
-                }
-            },
-            value: value.iter().collect::<String>(),
-        },
-    ))
-}
-
-// Parses a SELECT statement
-pub fn parse_select(input: &str) -> ParseResult<(Vec<&str>, &str, Option<WhereClause>), &str> {
-    let columns = separated_list1(ws(char::<&str, nom::error::Error<_>>(',')), identifier);
-    let (rest, (columns, _from, table, where_)) = preceded(
-        ws(tag_no_case("select")),
-        tuple((
-            alt((
-                map(tag_no_case("count(*)"), |s| vec![s]),
-                // map(alphanumeric1, |s| vec![s]),
-                columns,
-            )),
-            ws(tag_no_case("from")),
-            identifier,
-            opt(parse_where),
-        )),
-    )(input)?;
-    Ok((rest, (columns, table, where_)))
-}
-
-// Parses a snake_caps identifier, or a quoted identifier with spaces
-fn identifier(input: &str) -> ParseResult<&str, &str> {
-    alt((
-        take_while1(|c: char| c.is_alphanumeric() || c == '_'),
-        delimited(
-            char('"'),
-            take_while1(|c: char| c.is_alphanumeric() || c == '_' || c == ' '),
-            char('"'),
-        ),
-    ))(input)
-}
-
-// Parses a CREATE TABLE statement
-pub fn parse_create_table(input: &str) -> ParseResult<Vec<ColumnDef>, &str> {
-    let ipk_regex = Regex::new(r"(?i)integer primary key").unwrap();
-    let list = delimited(
-        ws(char('(')),
-        separated_list0(ws(char(',')), many0(ws(identifier))),
-        ws(char(')')),
-    );
-    let (rest, desc) = preceded(
-        ws(tag_no_case("create table")),
-        preceded(
-            ws(delimited(opt(char('"')), identifier, opt(char('"')))),
-            list,
-        ),
-    )(input)?;
-    let columns: Vec<String> = desc.iter().map(|x| x.join(" ")).collect::<Vec<String>>();
-    let res = columns
-        .iter()
-        .map(|x| ColumnDef {
-            name: x.split(' ').collect::<Vec<_>>()[0].to_string(),
-            modifiers: x.split(' ').collect::<Vec<_>>()[1..].join(" "),
-            ipk: ipk_regex.is_match(x),
-        })
-        .collect();
-    Ok((rest, res))
-}
-
 #[cfg(test)]
 mod tests {
-    use nom::{character::complete::alphanumeric1, UnspecializedInput};
-
     use super::*;
 
     #[test]
-    fn test_ws_identifier() {
-        let input = "hello there";
-        let res = many0(ws(identifier))(input);
-        println!("{:?}", res);
-        assert_eq!(res, Ok(("", vec!["hello", "there"])));
-    }
-
-    #[test]
-    fn test_create_table() {
-        let input = "create table foo (id integer primary key, name text)";
-        let res = parse_create_table(input);
-        println!("{:?}", res);
-        assert_eq!(
-            res,
-            Ok((
-                "",
-                vec![
-                    ColumnDef {
-                        name: "id".to_string(),
-                        modifiers: "integer primary key".to_string(),
-                        ipk: true,
-                    },
-                    ColumnDef {
-                        name: "name".to_string(),
-                        modifiers: "text".to_string(),
-                        ipk: false,
-                    }
-                ]
-            ))
-        );
+    fn test_header_page_size() {
+        let mut header = [0u8; 100];
+        header[..16].copy_from_slice(b"SQLite format 3\0");
+        header[16..18].copy_from_slice(&4096u16.to_be_bytes());
+        assert_eq!(parse_header(&header).unwrap().1.page_size, 4096);
+        // A page size of 1 means 65536
+        header[16..18].copy_from_slice(&1u16.to_be_bytes());
+        header[20] = 8;
+        let (_, parsed) = parse_header(&header).unwrap();
+        assert_eq!(parsed.page_size, 65536);
+        assert_eq!(PageLayout::from_header(&parsed).usable_size, 65528);
     }
 
     #[test]
@@ -684,67 +1040,283 @@ mod tests {
         assert_eq!(res, Ok((&Vec::new()[..], 0b100_0000_1000_0001)));
     }
 
+    // Serves overflow pages from memory
+    struct TestPages {
+        layout: PageLayout,
+        pages: Vec<Vec<u8>>,
+    }
+
+    impl PageSource for TestPages {
+        fn layout(&self) -> PageLayout {
+            self.layout
+        }
+
+        fn text_encoding(&self) -> TextEncoding {
+            TextEncoding::Utf8
+        }
+
+        fn overflow_page(&self, page_number: u32) -> Result<PageBytes, anyhow::Error> {
+            self.pages
+                .get(page_number as usize - 1)
+                .cloned()
+                .map(PageBytes::from)
+                .ok_or_else(|| anyhow!("No page {page_number}"))
+        }
+    }
+
+    fn test_layout(usable_size: usize) -> PageLayout {
+        PageLayout {
+            usable_size,
+            max_embedded_payload_fraction: 64,
+            min_embedded_payload_fraction: 32,
+            leaf_payload_fraction: 32,
+        }
+    }
+
     #[test]
-    fn test_where() {
-        let input = "where name_o = \'hello\'";
-        let res = parse_where(input);
-        println!("{:?}", res);
+    fn test_local_payload_size() {
+        let layout = test_layout(4096);
+        // Payloads that fit are stored entirely on the page
+        assert_eq!(layout.local_payload_size(4061, PageType::LeafTable), 4061);
+        assert_eq!(layout.local_payload_size(1002, PageType::LeafIndex), 1002);
+        // Otherwise the local part is between the minimum and maximum local size
+        assert_eq!(layout.local_payload_size(4062, PageType::LeafTable), 489);
+        assert_eq!(layout.local_payload_size(5000, PageType::LeafTable), 908);
+        assert_eq!(layout.local_payload_size(1003, PageType::LeafIndex), 489);
         assert_eq!(
-            res,
-            Ok((
-                "",
-                WhereClause {
-                    column: "name_o".to_string(),
-                    operator: Comparator::Eq,
-                    value: "hello".to_string()
-                }
-            ))
+            layout.local_payload_size(8000, PageType::InteriorIndex),
+            489
+        );
+    }
+
+    #[test]
+    fn test_overflow_cell() {
+        let layout = test_layout(512);
+        // A 1000-byte text value, stored with a two-byte record header
+        let text = (0..1000)
+            .map(|i| (b'a' + (i % 26) as u8) as char)
+            .collect::<String>();
+        let mut record = vec![3, 0x8f, 0x5d];
+        record.extend_from_slice(text.as_bytes());
+        let payload_size = record.len();
+        let local_size = layout.local_payload_size(payload_size, PageType::LeafTable);
+        assert!(local_size < payload_size);
+
+        // Cell: payload size 1003 and rowid 7, then the local payload and the
+        // first overflow page number
+        let mut cell_bytes = vec![0x87, 0x6b, 7];
+        cell_bytes.extend_from_slice(&record[..local_size]);
+        cell_bytes.extend_from_slice(&1u32.to_be_bytes());
+        // Two overflow pages, the first pointing to the second
+        let mut first = 2u32.to_be_bytes().to_vec();
+        first.extend_from_slice(&record[local_size..local_size + 508]);
+        let mut second = 0u32.to_be_bytes().to_vec();
+        second.extend_from_slice(&record[local_size + 508..]);
+        let source = TestPages {
+            layout,
+            pages: vec![first, second],
+        };
+
+        let (rest, cell) = parse_cell(&cell_bytes, PageType::LeafTable, &source).unwrap();
+        assert!(rest.is_empty());
+        match cell {
+            Cell::TableLeaf(cell) => {
+                assert_eq!(cell.row_id, 7);
+                assert_eq!(cell.overflow_page, Some(1));
+                assert_eq!(cell.payload, vec![Data::Text(text)]);
+            }
+            _ => panic!("Expected a table leaf cell"),
+        }
+
+        // An overflow page that can't be read reports the read error
+        let source = TestPages {
+            layout,
+            pages: vec![source.pages[0].clone()],
+        };
+        let err = parse_cell(&cell_bytes, PageType::LeafTable, &source).unwrap_err();
+        assert_eq!(page_error(err).to_string(), "No page 2");
+    }
+
+    #[test]
+    fn test_compare() {
+        let ordered = [
+            Data::Null,
+            Data::Integer(-3),
+            Data::Float(-2.5),
+            Data::Integer(1),
+            Data::Float(1.5),
+            Data::Integer(2),
+            Data::Integer(i64::MAX),
+            Data::Float(1e19),
+            Data::Text("A".to_string()),
+            Data::Text("a".to_string()),
+            Data::Text("ab".to_string()),
+            Data::Blob(vec![0]),
+        ];
+        for (i, a) in ordered.iter().enumerate() {
+            for (j, b) in ordered.iter().enumerate() {
+                assert_eq!(a.compare(b), i.cmp(&j), "{a:?} vs {b:?}");
+            }
+        }
+        assert_eq!(Data::Integer(2).compare(&Data::Float(2.0)), Ordering::Equal);
+    }
+
+    #[test]
+    fn test_affinity() {
+        assert_eq!(Affinity::from_declared_type("VARCHAR(20)"), Affinity::Text);
+        assert_eq!(Affinity::from_declared_type("BIGINT"), Affinity::Integer);
+        assert_eq!(Affinity::from_declared_type("DOUBLE"), Affinity::Real);
+        assert_eq!(Affinity::from_declared_type(""), Affinity::Blob);
+        assert_eq!(
+            Affinity::from_declared_type("DECIMAL(10,5)"),
+            Affinity::Numeric
+        );
+        let text = |s: &str| Data::Text(s.to_string());
+        assert_eq!(
+            text(" 12 ").apply_affinity(Affinity::Integer),
+            Data::Integer(12)
         );
+        assert_eq!(
+            text("2.0").apply_affinity(Affinity::Numeric),
+            Data::Integer(2)
+        );
+        assert_eq!(text("2").apply_affinity(Affinity::Real), Data::Float(2.0));
+        assert_eq!(text("1e3x").apply_affinity(Affinity::Integer), text("1e3x"));
+        assert_eq!(Data::Float(2.5).apply_affinity(Affinity::Text), text("2.5"));
+        assert_eq!(text("5").apply_affinity(Affinity::Blob), text("5"));
     }
 
     #[test]
-    fn test_select() {
-        let input = "SELECT id, name, eye_color FROM superheroes WHERE eye_color = \'Pink Eyes\'";
-        let res = parse_select(input);
-        println!("{:?}", res);
+    fn test_numeric_prefix() {
+        let cases = [
+            ("3abc", Data::Integer(3)),
+            (" 12 ", Data::Integer(12)),
+            ("-.5x", Data::Float(-0.5)),
+            ("1.", Data::Float(1.0)),
+            ("1e5", Data::Float(100000.0)),
+            ("2e", Data::Integer(2)),
+            (".e5", Data::Integer(0)),
+            ("0x10", Data::Integer(0)),
+            ("abc", Data::Integer(0)),
+            ("9223372036854775808", Data::Float(9223372036854775808.0)),
+        ];
+        for (s, expected) in cases {
+            assert_eq!(parse_numeric_prefix(s), expected, "{s}");
+        }
+    }
+
+    #[test]
+    fn test_cast() {
+        let text = |s: &str| Data::Text(s.to_string());
+        let cases = [
+            (text("1.9"), Affinity::Integer, Data::Integer(1)),
+            (text(" -12abc"), Affinity::Integer, Data::Integer(-12)),
+            (text("1e3"), Affinity::Integer, Data::Integer(1)),
+            (
+                text("99999999999999999999"),
+                Affinity::Integer,
+                Data::Integer(i64::MAX),
+            ),
+            (Data::Float(-1.9), Affinity::Integer, Data::Integer(-1)),
+            (
+                Data::Float(-1e30),
+                Affinity::Integer,
+                Data::Integer(i64::MIN),
+            ),
+            (text("3"), Affinity::Real, Data::Float(3.0)),
+            (text("1.0abc"), Affinity::Numeric, Data::Integer(1)),
+            (text("1e18"), Affinity::Numeric, Data::Float(1e18)),
+            (Data::Float(1.0), Affinity::Numeric, Data::Float(1.0)),
+            (
+                Data::Blob(b"12".to_vec()),
+                Affinity::Numeric,
+                Data::Integer(12),
+            ),
+            (Data::Float(1.5), Affinity::Text, text("1.5")),
+            (Data::Integer(1), Affinity::Blob, Data::Blob(b"1".to_vec())),
+            (Data::Null, Affinity::Integer, Data::Null),
+        ];
+        for (value, affinity, expected) in cases {
+            assert_eq!(value.clone().cast(affinity), expected, "{value:?}");
+        }
+    }
+
+    #[test]
+    fn test_format_real() {
+        let cases = [
+            (1.0, "1.0"),
+            (-2.5, "-2.5"),
+            (0.1, "0.1"),
+            (1.0 / 3.0, "0.333333333333333"),
+            (123456789012345.0, "123456789012345.0"),
+            (1e15, "1.0e+15"),
+            (1.5e-5, "1.5e-05"),
+            (0.0001, "0.0001"),
+            (0.0, "0.0"),
+        ];
+        for (x, expected) in cases {
+            assert_eq!(format_real(x), expected);
+        }
+    }
+
+    #[test]
+    fn test_utf16_text() {
+        // A record with the single text value "héllo"
+        let le = [2, 33, b'h', 0, 0xe9, 0, b'l', 0, b'l', 0, b'o', 0];
+        let be = [2, 33, 0, b'h', 0, 0xe9, 0, b'l', 0, b'l', 0, b'o'];
+        let expected = vec![Data::Text("héllo".to_string())];
         assert_eq!(
-            res,
-            Ok((
-                "",
-                (
-                    vec!["id", "name"],
-                    "foo",
-                    Some(WhereClause {
-                        column: "name".to_string(),
-                        operator: Comparator::Eq,
-                        value: "hello".to_string()
-                    })
-                )
-            ))
+            parse_record(&le, TextEncoding::Utf16le).unwrap().1,
+            expected
+        );
+        assert_eq!(
+            parse_record(&be, TextEncoding::Utf16be).unwrap().1,
+            expected
         );
+        // Unpaired surrogates and invalid UTF-8 are errors, not panics
+        assert!(parse_record(&[2, 17, 0x00, 0xd8], TextEncoding::Utf16le).is_err());
+        assert!(parse_record(&[2, 15, 0xff], TextEncoding::Utf8).is_err());
     }
 
     #[test]
-    fn test() {
-        let input = "id integer, name text";
-        let res = separated_list0(
-            char(','),
-            many0(ws(alphanumeric1::<&str, nom::error::Error<_>>)),
-        )(input);
-        println!("{:?}", res);
+    fn test_integer_serial_types() {
+        // NULL, the constants 0 and 1, then integers of 1, 2, 3, 4, 6 and 8 bytes
+        let mut record = vec![11, 0, 8, 9, 1, 2, 3, 4, 5, 6, 7];
+        record.push(0x80);
+        record.extend_from_slice(&[0x7f, 0xff]);
+        record.extend_from_slice(&[0x80, 0x00, 0x00]);
+        record.extend_from_slice(&[0xff, 0xff, 0xff, 0xfe]);
+        record.extend_from_slice(&[0xed, 0xcb, 0xa9, 0x87, 0x65, 0x44]);
+        record.extend_from_slice(&i64::MIN.to_be_bytes());
+        record.extend_from_slice(&(-1.25f64).to_be_bytes());
+        let expected = vec![
+            Data::Null,
+            Data::Integer(0),
+            Data::Integer(1),
+            Data::Integer(-128),
+            Data::Integer(0x7fff),
+            Data::Integer(-0x80_0000),
+            Data::Integer(-2),
+            Data::Integer(-0x1234_5678_9abc),
+            Data::Integer(i64::MIN),
+            Data::Float(-1.25),
+        ];
         assert_eq!(
-            res,
-            Ok(("", vec![vec!["id", "integer"], vec!["name", "text"]]))
+            parse_record(&record, TextEncoding::Utf8),
+            Ok((&[][..], expected))
         );
     }
 
     #[test]
-    fn test_identifier() {
-        let input = "hello_there";
-        let res = identifier(input);
-        println!("{:?}", res);
-        assert_eq!(res, Ok(("", "hello_there")));
-        let res2 = identifier("hello there");
-        assert_eq!(res2, Ok((" there", "hello")));
+    fn test_encoded_text_order() {
+        let a = Data::Text("Ā".to_string());
+        let b = Data::Text("B".to_string());
+        assert_eq!(a.compare_encoded(&b, TextEncoding::Utf8), Ordering::Greater);
+        assert_eq!(
+            a.compare_encoded(&b, TextEncoding::Utf16be),
+            Ordering::Greater
+        );
+        // U+0100 is stored as 00 01 and "B" as 42 00
+        assert_eq!(a.compare_encoded(&b, TextEncoding::Utf16le), Ordering::Less);
     }
 }
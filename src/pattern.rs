@@ -0,0 +1,199 @@
+use anyhow::bail;
+use regex::Regex;
+use std::cell::RefCell;
+
+// One element of a LIKE or GLOB pattern
+#[derive(Debug, Clone, PartialEq)]
+enum Wildcard {
+    // % in LIKE, * in GLOB
+    AnySequence,
+    // _ in LIKE, ? in GLOB
+    AnyChar,
+    Char(char),
+    // A GLOB character class like [a-z] or [^0-9]
+    Class {
+        ranges: Vec<(char, char)>,
+        negated: bool,
+    },
+}
+
+impl Wildcard {
+    fn matches(&self, c: char, fold_case: bool) -> bool {
+        match self {
+            Wildcard::AnySequence | Wildcard::AnyChar => true,
+            Wildcard::Char(p) if fold_case => p.eq_ignore_ascii_case(&c),
+            Wildcard::Char(p) => *p == c,
+            Wildcard::Class { ranges, negated } => {
+                ranges.iter().any(|(lo, hi)| (*lo..=*hi).contains(&c)) != *negated
+            }
+        }
+    }
+}
+
+// Matches text against a parsed pattern. A % or * first tries to match
+// nothing, and on a later mismatch backtracks to swallow one more character.
+fn wildcard_match(pattern: &[Wildcard], text: &[char], fold_case: bool) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // The position after the last % or *, and the text position it resumes from
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(Wildcard::AnySequence) => {
+                p += 1;
+                backtrack = Some((p, t));
+            }
+            Some(w) if w.matches(text[t], fold_case) => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((bp, bt)) => {
+                    p = bp;
+                    t = bt + 1;
+                    backtrack = Some((bp, bt + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|w| *w == Wildcard::AnySequence)
+}
+
+// Parses a LIKE pattern, where an escape character makes the next character
+// match literally
+fn parse_like(pattern: &str, escape: Option<char>) -> Vec<Wildcard> {
+    let mut res = Vec::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        res.push(match c {
+            c if Some(c) == escape => match chars.next() {
+                Some(c) => Wildcard::Char(c),
+                // A trailing escape character can't match anything
+                None => Wildcard::Class {
+                    ranges: Vec::new(),
+                    negated: false,
+                },
+            },
+            '%' => Wildcard::AnySequence,
+            '_' => Wildcard::AnyChar,
+            c => Wildcard::Char(c),
+        });
+    }
+    res
+}
+
+// Parses a GLOB pattern. Returns None if a character class isn't closed, in
+// which case the pattern matches nothing.
+fn parse_glob(pattern: &str) -> Option<Vec<Wildcard>> {
+    let mut res = Vec::new();
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        res.push(match c {
+            '*' => Wildcard::AnySequence,
+            '?' => Wildcard::AnyChar,
+            '[' => {
+                let negated = chars.next_if_eq(&'^').is_some();
+                let mut ranges = Vec::new();
+                // A ] right after the opening bracket is part of the class
+                if chars.next_if_eq(&']').is_some() {
+                    ranges.push((']', ']'));
+                }
+                loop {
+                    match chars.next()? {
+                        ']' => break,
+                        lo => {
+                            let range = chars.peek() == Some(&'-')
+                                && chars.clone().nth(1).is_some_and(|c| c != ']');
+                            if range {
+                                chars.next();
+                                ranges.push((lo, chars.next()?));
+                            } else {
+                                ranges.push((lo, lo));
+                            }
+                        }
+                    }
+                }
+                Wildcard::Class { ranges, negated }
+            }
+            c => Wildcard::Char(c),
+        });
+    }
+    Some(res)
+}
+
+// Implements LIKE: % matches any sequence of characters and _ matches one
+// character. ASCII letters match regardless of case.
+pub fn like(pattern: &str, text: &str, escape: Option<char>) -> bool {
+    let text = text.chars().collect::<Vec<_>>();
+    wildcard_match(&parse_like(pattern, escape), &text, true)
+}
+
+// Implements GLOB: * and ? are wildcards, [...] is a character class, and
+// case matters
+pub fn glob(pattern: &str, text: &str) -> bool {
+    let text = text.chars().collect::<Vec<_>>();
+    parse_glob(pattern).is_some_and(|pattern| wildcard_match(&pattern, &text, false))
+}
+
+thread_local! {
+    // The most recently compiled regular expression, since a query usually
+    // matches every row against the same pattern
+    static LAST_REGEX: RefCell<Option<(String, Regex)>> = const { RefCell::new(None) };
+}
+
+// Implements REGEXP: true if the regular expression matches anywhere in the text
+pub fn regexp(pattern: &str, text: &str) -> Result<bool, anyhow::Error> {
+    LAST_REGEX.with(|last| {
+        let mut last = last.borrow_mut();
+        if !matches!(&*last, Some((p, _)) if p == pattern) {
+            match Regex::new(pattern) {
+                Ok(regex) => *last = Some((pattern.to_string(), regex)),
+                Err(e) => bail!("invalid regular expression: {e}"),
+            }
+        }
+        Ok(last.as_ref().unwrap().1.is_match(text))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_like() {
+        assert!(like("foo%", "Foobar", None));
+        assert!(like("%BAR", "foobar", None));
+        assert!(like("f_o", "foo", None));
+        assert!(!like("f_o", "fo", None));
+        assert!(like("%a%b%", "xxaxxbxx", None));
+        assert!(!like("%a%b", "xxaxxbxx", None));
+        assert!(like("", "", None));
+        // Case folding only applies to ASCII
+        assert!(!like("é", "É", None));
+        assert!(like("100\\%", "100%", Some('\\')));
+        assert!(!like("100\\%", "1000", Some('\\')));
+        assert!(like("a!_c", "a_c", Some('!')));
+        assert!(!like("a!_c", "abc", Some('!')));
+    }
+
+    #[test]
+    fn test_glob() {
+        assert!(glob("foo*", "foobar"));
+        assert!(!glob("foo*", "Foobar"));
+        assert!(glob("?oo", "zoo"));
+        assert!(glob("[a-c]x", "bx"));
+        assert!(!glob("[^a-c]x", "bx"));
+        assert!(glob("[]]", "]"));
+        assert!(glob("[a-]", "-"));
+        assert!(!glob("[abc", "a"));
+        assert!(glob("*.rs", "main.rs"));
+    }
+
+    #[test]
+    fn test_regexp() {
+        assert!(regexp("^ab+c$", "abbbc").unwrap());
+        assert!(regexp("b", "abc").unwrap());
+        assert!(!regexp("^b", "abc").unwrap());
+        assert!(regexp("(", "x").is_err());
+    }
+}
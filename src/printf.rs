@@ -0,0 +1,508 @@
+use crate::functions::{integer_of, real_of, text_of};
+use crate::parser::Data;
+
+// The flags, width and precision of one conversion in a format string
+#[derive(Debug, Default)]
+struct Spec {
+    // -, which pads on the right instead of the left
+    left: bool,
+    // + and space, which put a sign before positive numbers
+    plus: bool,
+    space: bool,
+    // #, which keeps the trailing zeros of %g and prefixes %x and %o
+    alternate: bool,
+    // 0, which pads numbers with zeros after their sign
+    zero: bool,
+    // ,, which separates the thousands of a number
+    commas: bool,
+    // !, which measures text in characters rather than bytes and gives reals
+    // more digits, without trailing zeros
+    extended: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+
+// Formats values the way SQLite's printf() does. Each conversion takes the
+// next value, and conversions past the last value read NULL. Formatting
+// stops at a conversion SQLite doesn't know, and if nothing was written
+// before it, or the format is empty, the result is None.
+pub fn format(format: &str, args: &[Data]) -> Option<String> {
+    let mut args = args.iter();
+    let mut next_arg = || args.next().unwrap_or(&Data::Null);
+    let mut out = String::new();
+    let mut converted = false;
+    let mut chars = format.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        if chars.peek().is_none() {
+            out.push('%');
+            break;
+        }
+        let mut spec = Spec::default();
+        while let Some(&c) = chars.peek() {
+            match c {
+                '-' => spec.left = true,
+                '+' => spec.plus = true,
+                ' ' => spec.space = true,
+                '#' => spec.alternate = true,
+                '0' => spec.zero = true,
+                ',' => spec.commas = true,
+                '!' => spec.extended = true,
+                _ => break,
+            }
+            chars.next();
+        }
+        if chars.next_if_eq(&'*').is_some() {
+            let width = integer_of(next_arg());
+            spec.left |= width < 0;
+            spec.width = width.unsigned_abs() as usize;
+        } else {
+            spec.width = number(&mut chars);
+        }
+        if chars.next_if_eq(&'.').is_some() {
+            spec.precision = if chars.next_if_eq(&'*').is_some() {
+                usize::try_from(integer_of(next_arg())).ok()
+            } else {
+                Some(number(&mut chars))
+            };
+        }
+        while chars.next_if_eq(&'l').is_some() {}
+        let Some(conversion) = chars.next() else {
+            break;
+        };
+        let (prefix, body) = match conversion {
+            'd' | 'i' | 'u' | 'x' | 'X' | 'o' | 'p' | 'r' => {
+                integer(conversion, &spec, integer_of(next_arg()))
+            }
+            'f' | 'e' | 'E' | 'g' | 'G' => real(conversion, &spec, real_of(next_arg())),
+            's' | 'z' => (String::new(), text(&spec, next_arg())),
+            'c' => {
+                let c = match next_arg() {
+                    Data::Null => None,
+                    value => text_of(value).chars().next(),
+                };
+                let count = spec.precision.unwrap_or(1);
+                (
+                    String::new(),
+                    c.map(|c| c.to_string().repeat(count)).unwrap_or_default(),
+                )
+            }
+            'q' | 'Q' | 'w' => (String::new(), quoted(conversion, &spec, next_arg())),
+            '%' => (String::new(), "%".to_string()),
+            // %n writes nothing
+            'n' => (String::new(), String::new()),
+            _ => break,
+        };
+        let numeric = !matches!(conversion, 's' | 'z' | 'c' | 'q' | 'Q' | 'w' | '%' | 'n');
+        pad(&mut out, &spec, numeric, &prefix, body);
+        converted = true;
+    }
+    (converted || !out.is_empty()).then_some(out)
+}
+
+// Reads the digits of a width or precision
+fn number(chars: &mut std::iter::Peekable<std::str::Chars>) -> usize {
+    let mut n = 0usize;
+    while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+        n = n
+            .saturating_mul(10)
+            .saturating_add(digit as usize - '0' as usize);
+    }
+    n
+}
+
+// Formats an integer conversion, returning its sign or radix prefix and its
+// digits. %p is %X with a 0x prefix, and %r is an ordinal like 2nd.
+fn integer(conversion: char, spec: &Spec, value: i64) -> (String, String) {
+    let (prefix, mut digits) = match conversion {
+        'd' | 'i' | 'r' => (sign(spec, value < 0), value.unsigned_abs().to_string()),
+        'u' => (String::new(), (value as u64).to_string()),
+        'x' => (
+            radix_prefix(spec, value, "0x"),
+            format!("{:x}", value as u64),
+        ),
+        'X' => (
+            radix_prefix(spec, value, "0X"),
+            format!("{:X}", value as u64),
+        ),
+        'p' => (
+            radix_prefix(spec, value, "0x"),
+            format!("{:X}", value as u64),
+        ),
+        _ => (
+            radix_prefix(spec, value, "0"),
+            format!("{:o}", value as u64),
+        ),
+    };
+    if let Some(precision) = spec.precision {
+        digits = format!("{digits:0>precision$}");
+    }
+    if spec.commas && matches!(conversion, 'd' | 'i' | 'u' | 'r') {
+        digits = separate_thousands(&digits);
+    }
+    if conversion == 'r' {
+        let n = value.unsigned_abs();
+        digits += match (n % 100, n % 10) {
+            (11..=13, _) => "th",
+            (_, 1) => "st",
+            (_, 2) => "nd",
+            (_, 3) => "rd",
+            _ => "th",
+        };
+    }
+    (prefix, digits)
+}
+
+fn radix_prefix(spec: &Spec, value: i64, prefix: &str) -> String {
+    if spec.alternate && value != 0 {
+        prefix.to_string()
+    } else {
+        String::new()
+    }
+}
+
+fn sign(spec: &Spec, negative: bool) -> String {
+    if negative {
+        "-"
+    } else if spec.plus {
+        "+"
+    } else if spec.space {
+        " "
+    } else {
+        ""
+    }
+    .to_string()
+}
+
+// Formats a real conversion, returning its sign and its digits
+fn real(conversion: char, spec: &Spec, x: f64) -> (String, String) {
+    let sign = sign(spec, x < 0.0);
+    if x.is_nan() {
+        return (String::new(), "NaN".to_string());
+    }
+    if x.is_infinite() {
+        return (sign, "Inf".to_string());
+    }
+    // SQLite rounds to at most 16 significant digits, or 26 with !
+    let max_digits = if spec.extended { 26 } else { 16 };
+    let precision = spec.precision.unwrap_or(6) as i32;
+    let mut body = match conversion {
+        'f' => {
+            let exponent = significant_digits(x).1;
+            let (digits, exponent) = round_digits(x, exponent + 1 + precision, max_digits);
+            fixed(&digits, exponent, precision)
+        }
+        'e' | 'E' => {
+            let (digits, exponent) = round_digits(x, precision + 1, max_digits);
+            scientific(&digits, exponent, precision, conversion)
+        }
+        _ => {
+            let precision = precision.max(1);
+            let (digits, exponent) = round_digits(x, precision, max_digits);
+            let e = if conversion == 'g' { 'e' } else { 'E' };
+            let body = if exponent < -4 || exponent >= precision {
+                scientific(&digits, exponent, precision - 1, e)
+            } else {
+                fixed(&digits, exponent, precision - 1 - exponent)
+            };
+            if spec.alternate || spec.extended {
+                body
+            } else {
+                strip_zeros(&body, "")
+            }
+        }
+    };
+    if spec.extended {
+        body = strip_zeros(&body, ".0");
+    } else if spec.alternate && !body.contains('.') {
+        let at = body.find(['e', 'E']).unwrap_or(body.len());
+        body.insert(at, '.');
+    }
+    if spec.commas {
+        let end = body.find(['.', 'e', 'E']).unwrap_or(body.len());
+        body = separate_thousands(&body[..end]) + &body[end..];
+    }
+    (sign, body)
+}
+
+// The first 18 or 19 significant digits of a real, and the power of ten of
+// the first, worked out like SQLite does: the real is scaled to an integer
+// just below 2^63, which is cut off towards the nearest double rather than
+// rounded
+fn significant_digits(x: f64) -> (Vec<u8>, i32) {
+    if x == 0.0 {
+        return (Vec::new(), 0);
+    }
+    let scientific = format!("{:.39e}", x.abs());
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let mut exponent = exponent.parse::<i32>().unwrap();
+    let all = mantissa.replace('.', "");
+    let len = if all[..19] > *"9223372036854774784" {
+        18
+    } else {
+        19
+    };
+    let (integer, fraction) = all.split_at(len);
+    let mut scaled = integer.parse::<u64>().unwrap();
+    let nearest = format!("{integer}.{fraction}").parse::<f64>().unwrap();
+    if nearest as u64 > scaled && fraction.contains(|c| c != '0') {
+        scaled += 1;
+    }
+    let digits = scaled.to_string();
+    exponent += (digits.len() - len) as i32;
+    (digits.bytes().map(|b| b - b'0').collect(), exponent)
+}
+
+// Rounds a real half away from zero to a number of significant digits, and
+// returns those digits with the power of ten of the first. Digits past
+// `max_digits` are left as zeros. A real that rounds to zero has no digits.
+fn round_digits(x: f64, count: i32, max_digits: i32) -> (Vec<u8>, i32) {
+    let (all, mut exponent) = significant_digits(x);
+    let count = count.min(max_digits);
+    if count < 0 {
+        return (Vec::new(), exponent);
+    }
+    let count = count as usize;
+    if count >= all.len() {
+        return (all, exponent);
+    }
+    let mut digits = all[..count].to_vec();
+    if all[count] >= 5 {
+        let carried = digits.iter().rposition(|&d| d != 9);
+        match carried {
+            Some(i) => {
+                digits[i] += 1;
+                digits[i + 1..].fill(0);
+            }
+            None => {
+                digits.fill(0);
+                digits.insert(0, 1);
+                exponent += 1;
+            }
+        }
+    }
+    (digits, exponent)
+}
+
+// The digit of a rounded real at a power of ten
+fn digit_at(digits: &[u8], exponent: i32, power: i32) -> char {
+    let digit = usize::try_from(exponent - power)
+        .ok()
+        .and_then(|i| digits.get(i))
+        .unwrap_or(&0);
+    (b'0' + digit) as char
+}
+
+// Writes a rounded real with a number of digits after the decimal point
+fn fixed(digits: &[u8], exponent: i32, precision: i32) -> String {
+    let mut out = (0..=exponent.max(0))
+        .rev()
+        .map(|power| digit_at(digits, exponent, power))
+        .collect::<String>();
+    if precision > 0 {
+        out.push('.');
+        out.extend((1..=precision).map(|i| digit_at(digits, exponent, -i)));
+    }
+    out
+}
+
+// Writes a rounded real as a mantissa and a power of ten, like 1.5e+03
+fn scientific(digits: &[u8], exponent: i32, precision: i32, e: char) -> String {
+    let exponent = if digits.is_empty() { 0 } else { exponent };
+    let mut out = digit_at(digits, exponent, exponent).to_string();
+    if precision > 0 {
+        out.push('.');
+        out.extend((1..=precision).map(|i| digit_at(digits, exponent, exponent - i)));
+    }
+    let exp_sign = if exponent < 0 { '-' } else { '+' };
+    format!("{out}{e}{exp_sign}{:02}", exponent.abs())
+}
+
+// Removes the trailing zeros after the decimal point of a real, leaving
+// `keep` in place of a fraction that is all zeros
+fn strip_zeros(body: &str, keep: &str) -> String {
+    let (mantissa, exponent) = match body.find(['e', 'E']) {
+        Some(i) => body.split_at(i),
+        None => (body, ""),
+    };
+    let mantissa = match mantissa.split_once('.') {
+        Some((int, fraction)) => match fraction.trim_end_matches('0') {
+            "" => format!("{int}{keep}"),
+            fraction => format!("{int}.{fraction}"),
+        },
+        None => format!("{mantissa}{keep}"),
+    };
+    mantissa + exponent
+}
+
+fn separate_thousands(digits: &str) -> String {
+    let mut out = String::new();
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            out.push(',');
+        }
+        out.push(c);
+    }
+    out
+}
+
+// Formats %s, which writes NULL as nothing
+fn text(spec: &Spec, value: &Data) -> String {
+    match value {
+        Data::Null => String::new(),
+        value => truncate(spec, text_of(value)),
+    }
+}
+
+// Formats %q and %Q, which double single quotes, and %w, which doubles
+// double quotes. %Q also quotes the text, and writes NULL as NULL.
+fn quoted(conversion: char, spec: &Spec, value: &Data) -> String {
+    let quote = if conversion == 'w' { "\"" } else { "'" };
+    match value {
+        Data::Null if conversion == 'Q' => "NULL".to_string(),
+        Data::Null => "(NULL)".to_string(),
+        value => {
+            let escaped = truncate(spec, text_of(value)).replace(quote, &quote.repeat(2));
+            if conversion == 'Q' {
+                format!("'{escaped}'")
+            } else {
+                escaped
+            }
+        }
+    }
+}
+
+// Cuts text to the precision of a conversion, counted in bytes unless the
+// ! flag is set. A character is never split.
+fn truncate(spec: &Spec, mut text: String) -> String {
+    if let Some(precision) = spec.precision {
+        let end = if spec.extended {
+            text.char_indices().nth(precision).map(|(i, _)| i)
+        } else {
+            (precision < text.len()).then(|| {
+                (0..=precision)
+                    .rev()
+                    .find(|&i| text.is_char_boundary(i))
+                    .unwrap()
+            })
+        };
+        if let Some(end) = end {
+            text.truncate(end);
+        }
+    }
+    text
+}
+
+// Writes a conversion padded to its width. Numbers padded with zeros get the
+// zeros between their prefix and their digits, even if they're left-aligned.
+fn pad(out: &mut String, spec: &Spec, numeric: bool, prefix: &str, mut body: String) {
+    let len = |s: &str| {
+        if spec.extended {
+            s.chars().count()
+        } else {
+            s.len()
+        }
+    };
+    if spec.zero && numeric {
+        let width = spec.width.saturating_sub(prefix.len());
+        body = format!("{body:0>width$}");
+    }
+    let padding = " ".repeat(spec.width.saturating_sub(len(prefix) + len(&body)));
+    if spec.left {
+        out.extend([prefix, &body, &padding]);
+    } else {
+        out.extend([&padding, prefix, &body]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(s: &str) -> Data {
+        Data::Text(s.to_string())
+    }
+
+    fn reals(values: &[f64]) -> Vec<Data> {
+        values.iter().map(|&x| Data::Float(x)).collect()
+    }
+
+    #[test]
+    fn test_format() {
+        let args = [
+            Data::Integer(42),
+            Data::Float(2.71964),
+            text("ab"),
+            Data::Integer(255),
+            Data::Null,
+        ];
+        assert_eq!(
+            format("%d|%5.2f|%-4s|%#x|%s|%%", &args).unwrap(),
+            "42| 2.72|ab  |0xff||%"
+        );
+        assert_eq!(
+            format(
+                "%05d|%,d|%r",
+                &[
+                    Data::Integer(-42),
+                    Data::Integer(1234567),
+                    Data::Integer(22)
+                ]
+            )
+            .unwrap(),
+            "-0042|1,234,567|22nd"
+        );
+        assert_eq!(
+            format(
+                "%q|%Q|%Q|%.2s|%3c|",
+                &[
+                    text("it's"),
+                    text("a'b"),
+                    Data::Null,
+                    text("éé"),
+                    text("xyz")
+                ]
+            )
+            .unwrap(),
+            "it''s|'a''b'|NULL|é|  x|"
+        );
+        // Missing values read as NULL
+        assert_eq!(format("%d %s.", &[]).unwrap(), "0 .");
+        // An unknown conversion stops the output
+        assert_eq!(format("a%Tb", &[]).unwrap(), "a");
+        assert_eq!(format("%Tb", &[]), None);
+        assert_eq!(format("100%", &[]).unwrap(), "100%");
+    }
+
+    #[test]
+    fn test_format_reals() {
+        // Reals round half away from zero, from their exact binary value
+        assert_eq!(
+            format("%.0f %.2f %.1f %+.2e", &reals(&[2.5, 2.675, 1.25, 12345.0])).unwrap(),
+            "3 2.67 1.3 +1.23e+04"
+        );
+        assert_eq!(
+            format("%.20f", &reals(&[0.7])).unwrap(),
+            "0.70000000000000000000"
+        );
+        assert_eq!(
+            format("%!.25f", &reals(&[0.7])).unwrap(),
+            "0.6999999999999999556"
+        );
+        assert_eq!(
+            format("%g %g %g %#g", &reals(&[100000.0, 1e6, 1e-5, 1.0])).unwrap(),
+            "100000 1e+06 1e-05 1.00000"
+        );
+        assert_eq!(
+            format("%!.3g %!.0f", &reals(&[2.0, 2.0])).unwrap(),
+            "2.0 2.0"
+        );
+        assert_eq!(
+            format("%08.2f|%,.1f", &reals(&[-2.71964, 1234.56])).unwrap(),
+            "-0002.72|1,234.6"
+        );
+    }
+}
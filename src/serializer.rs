@@ -0,0 +1,193 @@
+use crate::parser::Data;
+
+// Schema format 4 added the serial types for the constants 0 and 1
+const SCHEMA_FORMAT_WITH_BOOLEAN_CONSTANTS: u32 = 4;
+
+// Encodes a variable-length integer, the inverse of parser::varint. Values
+// that need more than 56 bits use all nine bytes, the last holding eight bits.
+#[allow(dead_code)]
+pub fn encode_varint(value: i64) -> Vec<u8> {
+    let mut v = value as u64;
+    if v >> 56 != 0 {
+        let mut buf = [0u8; 9];
+        buf[8] = v as u8;
+        v >>= 8;
+        for byte in buf[..8].iter_mut().rev() {
+            *byte = (v & 0x7f) as u8 | 0x80;
+            v >>= 7;
+        }
+        return buf.to_vec();
+    }
+    let mut buf = vec![(v & 0x7f) as u8];
+    v >>= 7;
+    while v != 0 {
+        buf.push((v & 0x7f) as u8 | 0x80);
+        v >>= 7;
+    }
+    buf.reverse();
+    buf
+}
+
+// Returns the smallest serial type that can hold a value. The constants 0
+// and 1 only have their own serial types from schema format 4 on.
+fn serial_type(value: &Data, schema_format: u32) -> i64 {
+    match value {
+        Data::Null => 0,
+        Data::Integer(0) if schema_format >= SCHEMA_FORMAT_WITH_BOOLEAN_CONSTANTS => 8,
+        Data::Integer(1) if schema_format >= SCHEMA_FORMAT_WITH_BOOLEAN_CONSTANTS => 9,
+        Data::Integer(x) => match x {
+            -0x80..=0x7f => 1,
+            -0x8000..=0x7fff => 2,
+            -0x80_0000..=0x7f_ffff => 3,
+            -0x8000_0000..=0x7fff_ffff => 4,
+            -0x8000_0000_0000..=0x7fff_ffff_ffff => 5,
+            _ => 6,
+        },
+        Data::Float(_) => 7,
+        Data::Blob(b) => 12 + 2 * b.len() as i64,
+        Data::Text(s) => 13 + 2 * s.len() as i64,
+    }
+}
+
+// Encodes the body of a value given its serial type
+fn encode_value(value: &Data, serial_type: i64, out: &mut Vec<u8>) {
+    match value {
+        Data::Integer(x) => {
+            let size = match serial_type {
+                1..=4 => serial_type as usize,
+                5 => 6,
+                6 => 8,
+                _ => 0,
+            };
+            out.extend_from_slice(&x.to_be_bytes()[8 - size..]);
+        }
+        Data::Float(x) => out.extend_from_slice(&x.to_be_bytes()),
+        Data::Text(s) => out.extend_from_slice(s.as_bytes()),
+        Data::Blob(b) => out.extend_from_slice(b),
+        Data::Null => {}
+    }
+}
+
+// Encodes values as a SQLite record, the inverse of parser::parse_record.
+// The record header holds its own size, followed by the serial type of each
+// value, and the values follow the header.
+#[allow(dead_code)]
+pub fn serialize_record(values: &[Data], schema_format: u32) -> Vec<u8> {
+    let serial_types = values
+        .iter()
+        .map(|v| serial_type(v, schema_format))
+        .collect::<Vec<_>>();
+    let types = serial_types
+        .iter()
+        .flat_map(|t| encode_varint(*t))
+        .collect::<Vec<_>>();
+    // The header size includes the varint that holds it, which may need more
+    // bytes as the size grows
+    let mut header_size = types.len() as i64 + 1;
+    while encode_varint(header_size).len() as i64 + types.len() as i64 != header_size {
+        header_size += 1;
+    }
+    let mut record = encode_varint(header_size);
+    record.extend(types);
+    for (value, serial_type) in values.iter().zip(serial_types) {
+        encode_value(value, serial_type, &mut record);
+    }
+    record
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse_record, varint, TextEncoding};
+
+    #[test]
+    fn test_varint_round_trip() {
+        let values = [
+            0,
+            1,
+            0x7f,
+            0x80,
+            0x3fff,
+            0x4000,
+            0xff_ffff,
+            1 << 42,
+            (1 << 56) - 1,
+            1 << 56,
+            i64::MAX,
+            -1,
+            i64::MIN,
+        ];
+        for value in values {
+            let encoded = encode_varint(value);
+            assert_eq!(varint(&encoded), Ok((&[][..], value)), "{value}");
+        }
+        assert_eq!(encode_varint(0x7f), vec![0x7f]);
+        assert_eq!(encode_varint(0x80), vec![0x81, 0x00]);
+        assert_eq!(encode_varint(-1).len(), 9);
+    }
+
+    #[test]
+    fn test_minimal_serial_types() {
+        let cases = [
+            (Data::Null, 0),
+            (Data::Integer(0), 8),
+            (Data::Integer(1), 9),
+            (Data::Integer(2), 1),
+            (Data::Integer(-129), 2),
+            (Data::Integer(40000), 3),
+            (Data::Integer(-9_000_000), 4),
+            (Data::Integer(1 << 40), 5),
+            (Data::Integer(-(1 << 40)), 5),
+            (Data::Integer(1 << 48), 6),
+            (Data::Float(2.5), 7),
+            (Data::Blob(vec![1, 2]), 16),
+            (Data::Text("abc".to_string()), 19),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(serial_type(&value, 4), expected, "{value:?}");
+        }
+        // Older schema formats store 0 and 1 as one-byte integers
+        assert_eq!(serial_type(&Data::Integer(0), 1), 1);
+        assert_eq!(serial_type(&Data::Integer(1), 3), 1);
+    }
+
+    #[test]
+    fn test_record_round_trip() {
+        let values = vec![
+            Data::Null,
+            Data::Integer(0),
+            Data::Integer(1),
+            Data::Integer(-100),
+            Data::Integer(30000),
+            Data::Integer(-8_000_000),
+            Data::Integer(2_000_000_000),
+            Data::Integer(0x7fff_ffff_ffff),
+            Data::Integer(-0x8000_0000_0000),
+            Data::Integer(-0x1234_5678_9abc),
+            Data::Integer(i64::MIN),
+            Data::Float(-1.25),
+            Data::Text(String::new()),
+            Data::Text("hello, world".to_string()),
+            Data::Blob(vec![0xde, 0xad, 0xbe, 0xef]),
+        ];
+        for schema_format in [1, 4] {
+            let record = serialize_record(&values, schema_format);
+            assert_eq!(
+                parse_record(&record, TextEncoding::Utf8),
+                Ok((&[][..], values.clone()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_large_record_header() {
+        // 200 one-byte serial types need a two-byte header size
+        let values = vec![Data::Integer(5); 200];
+        let record = serialize_record(&values, 4);
+        assert_eq!(varint(&record), Ok((&record[2..], 202)));
+        assert_eq!(
+            parse_record(&record, TextEncoding::Utf8),
+            Ok((&[][..], values))
+        );
+    }
+}
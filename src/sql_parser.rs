@@ -0,0 +1,2152 @@
+use crate::ast::{
+    BinaryOp, ColumnConstraint, ColumnDef, ColumnRef, CompoundOp, CreateIndex, CreateTable, Cte,
+    Expr, ForeignKey, Frame, FrameBound, FrameUnits, FunctionArgs, IndexedColumn, Join,
+    JoinConstraint, JoinKind, LikeOp, NullsOrder, OrderingTerm, ResultColumn, Select, Statement,
+    TableConstraint, TableRef, UnaryOp, Window,
+};
+use crate::lexer::{tokenize, Token};
+use crate::parser::{Affinity, Data};
+
+use anyhow::{anyhow, bail};
+use std::rc::Rc;
+
+// Keywords that can't be used as bare identifiers. Other keywords, like
+// KEY or ROWID, are only special in certain positions.
+const RESERVED_KEYWORDS: [&str; 49] = [
+    "ALL",
+    "AND",
+    "AS",
+    "ASC",
+    "BETWEEN",
+    "BY",
+    "CASE",
+    "CAST",
+    "CROSS",
+    "DESC",
+    "DISTINCT",
+    "ELSE",
+    "END",
+    "ESCAPE",
+    "EXCEPT",
+    "EXISTS",
+    "FROM",
+    "GLOB",
+    "GROUP",
+    "HAVING",
+    "IN",
+    "INNER",
+    "INTERSECT",
+    "IS",
+    "ISNULL",
+    "JOIN",
+    "LEFT",
+    "LIKE",
+    "LIMIT",
+    "NATURAL",
+    "NOT",
+    "NOTNULL",
+    "NULL",
+    "OFFSET",
+    "ON",
+    "OR",
+    "ORDER",
+    "OUTER",
+    "REGEXP",
+    "SELECT",
+    "THEN",
+    "UNION",
+    "USING",
+    "VALUES",
+    "WHEN",
+    "WHERE",
+    "WINDOW",
+    "WITH",
+    "RECURSIVE",
+];
+
+// Words that end the declared type of a column and start its constraints
+const COLUMN_CONSTRAINT_KEYWORDS: [&str; 11] = [
+    "constraint",
+    "primary",
+    "not",
+    "null",
+    "unique",
+    "check",
+    "default",
+    "collate",
+    "references",
+    "generated",
+    "as",
+];
+
+// Words that start a table constraint in CREATE TABLE
+const TABLE_CONSTRAINT_KEYWORDS: [&str; 5] =
+    ["constraint", "primary", "unique", "check", "foreign"];
+
+// Keywords of the operators that can follow NOT
+const MEMBERSHIP_KEYWORDS: [&str; 5] = ["like", "glob", "regexp", "in", "between"];
+
+fn is_reserved(word: &str) -> bool {
+    RESERVED_KEYWORDS
+        .iter()
+        .any(|k| k.eq_ignore_ascii_case(word))
+}
+
+// A recursive descent parser over the tokens of one statement
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    // Returns a syntax error at the current token
+    fn error(&self) -> anyhow::Error {
+        match self.peek() {
+            Some(token) => anyhow!("near \"{token}\": syntax error"),
+            None => anyhow!("incomplete input"),
+        }
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        self.is_keyword_at(0, keyword)
+    }
+
+    // Returns true if the token a number of places ahead is a keyword
+    fn is_keyword_at(&self, offset: usize, keyword: &str) -> bool {
+        matches!(self.tokens.get(self.pos + offset),
+            Some(Token::Word(w)) if w.eq_ignore_ascii_case(keyword))
+    }
+
+    // Consumes a keyword if it is next
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        let found = self.is_keyword(keyword);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), anyhow::Error> {
+        if self.eat_keyword(keyword) {
+            Ok(())
+        } else {
+            Err(self.error())
+        }
+    }
+
+    fn is_symbol(&self, symbol: &str) -> bool {
+        matches!(self.peek(), Some(Token::Symbol(s)) if *s == symbol)
+    }
+
+    // Consumes a symbol if it is next
+    fn eat_symbol(&mut self, symbol: &str) -> bool {
+        let found = self.is_symbol(symbol);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Result<(), anyhow::Error> {
+        if self.eat_symbol(symbol) {
+            Ok(())
+        } else {
+            Err(self.error())
+        }
+    }
+
+    // Parses a quoted identifier, or a bare word that isn't reserved
+    fn identifier(&mut self) -> Result<String, anyhow::Error> {
+        match self.peek() {
+            Some(Token::QuotedIdentifier(name)) => {
+                let name = name.clone();
+                self.pos += 1;
+                Ok(name)
+            }
+            Some(Token::Word(word)) if !is_reserved(word) => {
+                let word = word.clone();
+                self.pos += 1;
+                Ok(word)
+            }
+            _ => Err(self.error()),
+        }
+    }
+
+    fn statement(&mut self) -> Result<Statement, anyhow::Error> {
+        let statement = Statement::Select(self.select()?);
+        // A single trailing semicolon is allowed
+        self.eat_symbol(";");
+        match self.peek() {
+            Some(_) => Err(self.error()),
+            None => Ok(statement),
+        }
+    }
+
+    // Parses a SELECT, which may combine several SELECTs with UNION,
+    // INTERSECT or EXCEPT
+    fn select(&mut self) -> Result<Select, anyhow::Error> {
+        let with = if self.eat_keyword("with") {
+            Some(Rc::new(self.ctes()?))
+        } else {
+            None
+        };
+        let mut select = self.select_core()?;
+        select.with = with;
+        while let Some(op) = self.compound_op() {
+            select.compound.push((op, self.select_core()?));
+        }
+        if self.eat_keyword("order") {
+            self.expect_keyword("by")?;
+            select.order_by.push(self.ordering_term()?);
+            while self.eat_symbol(",") {
+                select.order_by.push(self.ordering_term()?);
+            }
+        }
+        if self.eat_keyword("limit") {
+            select.limit = Some(self.expr()?);
+            if self.eat_keyword("offset") {
+                select.offset = Some(self.expr()?);
+            } else if self.eat_symbol(",") {
+                // LIMIT a, b means LIMIT b OFFSET a
+                select.offset = select.limit.replace(self.expr()?);
+            }
+        }
+        if let Some(op) = self.compound_op() {
+            let clause = if select.limit.is_some() {
+                "LIMIT"
+            } else {
+                "ORDER BY"
+            };
+            bail!("{clause} clause should come after {} not before", op.name());
+        }
+        Ok(select)
+    }
+
+    // Parses the tables of a WITH clause, after its keyword
+    fn ctes(&mut self) -> Result<Vec<Cte>, anyhow::Error> {
+        // Any of the tables may read itself, so RECURSIVE changes nothing
+        self.eat_keyword("recursive");
+        let mut ctes: Vec<Cte> = Vec::new();
+        loop {
+            let name = self.identifier()?;
+            if ctes.iter().any(|cte| cte.name.eq_ignore_ascii_case(&name)) {
+                bail!("duplicate WITH table name: {name}");
+            }
+            let mut columns = Vec::new();
+            if self.eat_symbol("(") {
+                columns.push(self.identifier()?);
+                while self.eat_symbol(",") {
+                    columns.push(self.identifier()?);
+                }
+                self.expect_symbol(")")?;
+            }
+            self.expect_keyword("as")?;
+            // Whether the table is materialized is only a hint
+            if self.eat_keyword("not") {
+                self.expect_keyword("materialized")?;
+            } else {
+                self.eat_keyword("materialized");
+            }
+            self.expect_symbol("(")?;
+            let select = self.select()?;
+            self.expect_symbol(")")?;
+            ctes.push(Cte {
+                name,
+                columns,
+                select,
+            });
+            if !self.eat_symbol(",") {
+                return Ok(ctes);
+            }
+        }
+    }
+
+    // Returns true if a SELECT is next, which may start with a WITH clause
+    fn is_select(&self) -> bool {
+        self.is_keyword("select") || self.is_keyword("with")
+    }
+
+    // Parses UNION, UNION ALL, INTERSECT or EXCEPT, if one is next
+    fn compound_op(&mut self) -> Option<CompoundOp> {
+        if self.eat_keyword("union") {
+            if self.eat_keyword("all") {
+                Some(CompoundOp::UnionAll)
+            } else {
+                Some(CompoundOp::Union)
+            }
+        } else if self.eat_keyword("intersect") {
+            Some(CompoundOp::Intersect)
+        } else if self.eat_keyword("except") {
+            Some(CompoundOp::Except)
+        } else {
+            None
+        }
+    }
+
+    // Parses one SELECT of a compound SELECT, up to its HAVING clause
+    fn select_core(&mut self) -> Result<Select, anyhow::Error> {
+        self.expect_keyword("select")?;
+        let distinct = self.eat_keyword("distinct");
+        if !distinct {
+            self.eat_keyword("all");
+        }
+        let mut columns = vec![self.result_column()?];
+        while self.eat_symbol(",") {
+            columns.push(self.result_column()?);
+        }
+        let mut joins = Vec::new();
+        let from = if self.eat_keyword("from") {
+            let from = self.table_ref()?;
+            while let Some(join) = self.join()? {
+                joins.push(join);
+            }
+            Some(from)
+        } else {
+            None
+        };
+        let where_ = if self.eat_keyword("where") {
+            Some(self.expr()?)
+        } else {
+            None
+        };
+        let mut group_by = Vec::new();
+        let mut having = None;
+        if self.eat_keyword("group") {
+            self.expect_keyword("by")?;
+            group_by.push(self.expr()?);
+            while self.eat_symbol(",") {
+                group_by.push(self.expr()?);
+            }
+            if self.eat_keyword("having") {
+                having = Some(self.expr()?);
+            }
+        } else if self.eat_keyword("having") {
+            having = Some(self.expr()?);
+        }
+        let mut windows = Vec::new();
+        if self.eat_keyword("window") {
+            loop {
+                let name = self.identifier()?;
+                self.expect_keyword("as")?;
+                self.expect_symbol("(")?;
+                windows.push((name, self.window()?));
+                self.expect_symbol(")")?;
+                if !self.eat_symbol(",") {
+                    break;
+                }
+            }
+        }
+        Ok(Select {
+            with: None,
+            distinct,
+            columns,
+            from,
+            joins,
+            where_,
+            group_by,
+            having,
+            windows,
+            compound: Vec::new(),
+            order_by: Vec::new(),
+            limit: None,
+            offset: None,
+        })
+    }
+
+    // Parses a table name or a parenthesized subquery, and its optional alias
+    fn table_ref(&mut self) -> Result<TableRef, anyhow::Error> {
+        if self.eat_symbol("(") {
+            let select = Box::new(self.select()?);
+            self.expect_symbol(")")?;
+            let alias = self.table_alias()?;
+            return Ok(TableRef::Subquery { select, alias });
+        }
+        let name = self.identifier()?;
+        let alias = self.table_alias()?;
+        Ok(TableRef::Table { name, alias })
+    }
+
+    fn table_alias(&mut self) -> Result<Option<String>, anyhow::Error> {
+        // RIGHT and FULL aren't reserved, so they would be taken for an alias
+        self.reject_right_join()?;
+        let has_alias = self.eat_keyword("as")
+            || matches!(self.peek(), Some(Token::QuotedIdentifier(_)))
+            || matches!(self.peek(), Some(Token::Word(w)) if !is_reserved(w));
+        if has_alias {
+            Ok(Some(self.identifier()?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn reject_right_join(&self) -> Result<(), anyhow::Error> {
+        let unsupported = ["right", "full"].iter().any(|k| self.is_keyword(k))
+            && (self.is_keyword_at(1, "join") || self.is_keyword_at(1, "outer"));
+        if unsupported {
+            bail!("RIGHT and FULL OUTER JOINs are not supported");
+        }
+        Ok(())
+    }
+
+    // Parses the next join of a FROM clause, if there is one
+    fn join(&mut self) -> Result<Option<Join>, anyhow::Error> {
+        self.reject_right_join()?;
+        if self.eat_symbol(",") {
+            let table = self.table_ref()?;
+            return Ok(Some(Join {
+                kind: JoinKind::Inner,
+                table,
+                constraint: None,
+            }));
+        }
+        let start = self.pos;
+        let natural = self.eat_keyword("natural");
+        let kind = if self.eat_keyword("left") {
+            self.eat_keyword("outer");
+            JoinKind::Left
+        } else {
+            if !self.eat_keyword("inner") {
+                self.eat_keyword("cross");
+            }
+            JoinKind::Inner
+        };
+        if self.pos == start && !self.is_keyword("join") {
+            return Ok(None);
+        }
+        self.expect_keyword("join")?;
+        let table = self.table_ref()?;
+        let constraint = if self.eat_keyword("on") {
+            Some(JoinConstraint::On(self.expr()?))
+        } else if self.eat_keyword("using") {
+            self.expect_symbol("(")?;
+            let mut columns = vec![self.identifier()?];
+            while self.eat_symbol(",") {
+                columns.push(self.identifier()?);
+            }
+            self.expect_symbol(")")?;
+            Some(JoinConstraint::Using(columns))
+        } else {
+            None
+        };
+        match constraint {
+            Some(_) if natural => bail!("a NATURAL join may not have an ON or USING clause"),
+            _ if natural => Ok(Some(Join {
+                kind,
+                table,
+                constraint: Some(JoinConstraint::Natural),
+            })),
+            constraint => Ok(Some(Join {
+                kind,
+                table,
+                constraint,
+            })),
+        }
+    }
+
+    fn ordering_term(&mut self) -> Result<OrderingTerm, anyhow::Error> {
+        let expr = self.expr()?;
+        let descending = if self.eat_keyword("desc") {
+            true
+        } else {
+            self.eat_keyword("asc");
+            false
+        };
+        let nulls = if self.eat_keyword("nulls") {
+            if self.eat_keyword("first") {
+                Some(NullsOrder::First)
+            } else {
+                self.expect_keyword("last")?;
+                Some(NullsOrder::Last)
+            }
+        } else {
+            None
+        };
+        Ok(OrderingTerm {
+            expr,
+            descending,
+            nulls,
+        })
+    }
+
+    fn result_column(&mut self) -> Result<ResultColumn, anyhow::Error> {
+        if self.eat_symbol("*") {
+            return Ok(ResultColumn::Star(None));
+        }
+        let qualified_star = matches!(
+            self.peek(),
+            Some(Token::Word(_) | Token::QuotedIdentifier(_))
+        ) && matches!(self.tokens.get(self.pos + 1), Some(Token::Symbol(".")))
+            && matches!(self.tokens.get(self.pos + 2), Some(Token::Symbol("*")));
+        if qualified_star {
+            let table = self.identifier()?;
+            self.pos += 2;
+            return Ok(ResultColumn::Star(Some(table)));
+        }
+        let expr = self.expr()?;
+        // The alias may be a string, and AS may be left out before an identifier
+        let alias = if self.eat_keyword("as") {
+            match self.peek() {
+                Some(Token::String(s)) => {
+                    let alias = s.clone();
+                    self.pos += 1;
+                    Some(alias)
+                }
+                _ => Some(self.identifier()?),
+            }
+        } else if matches!(self.peek(), Some(Token::QuotedIdentifier(_)))
+            || matches!(self.peek(), Some(Token::Word(w)) if !is_reserved(w))
+        {
+            Some(self.identifier()?)
+        } else {
+            None
+        };
+        Ok(ResultColumn::Expr { expr, alias })
+    }
+
+    // Parses an expression. From loosest to tightest, the levels are OR,
+    // AND, NOT, the equality operators, the relational operators, + and -,
+    // * / and %, and ||.
+    fn expr(&mut self) -> Result<Expr, anyhow::Error> {
+        let mut left = self.and_expr()?;
+        while self.eat_keyword("or") {
+            left = Expr::binary(BinaryOp::Or, left, self.and_expr()?);
+        }
+        Ok(left)
+    }
+
+    fn and_expr(&mut self) -> Result<Expr, anyhow::Error> {
+        let mut left = self.not_expr()?;
+        while self.eat_keyword("and") {
+            left = Expr::binary(BinaryOp::And, left, self.not_expr()?);
+        }
+        Ok(left)
+    }
+
+    fn not_expr(&mut self) -> Result<Expr, anyhow::Error> {
+        if self.eat_keyword("not") {
+            return Ok(Expr::Unary {
+                op: UnaryOp::Not,
+                expr: Box::new(self.not_expr()?),
+            });
+        }
+        self.equality()
+    }
+
+    fn equality(&mut self) -> Result<Expr, anyhow::Error> {
+        let mut left = self.relational()?;
+        loop {
+            // LIKE, GLOB, REGEXP, IN and BETWEEN, each optionally after NOT
+            let negated = self.is_keyword("not");
+            if MEMBERSHIP_KEYWORDS
+                .iter()
+                .any(|k| self.is_keyword_at(negated as usize, k))
+            {
+                self.pos += negated as usize;
+                left = self.membership(left, negated)?;
+                continue;
+            }
+            let op = match self.peek() {
+                Some(Token::Symbol("=" | "==")) => BinaryOp::Eq,
+                Some(Token::Symbol("!=" | "<>")) => BinaryOp::Ne,
+                Some(Token::Word(w)) if w.eq_ignore_ascii_case("is") => {
+                    self.pos += 1;
+                    let op = if self.eat_keyword("not") {
+                        BinaryOp::IsNot
+                    } else {
+                        BinaryOp::Is
+                    };
+                    left = Expr::binary(op, left, self.relational()?);
+                    continue;
+                }
+                // The postfix forms x ISNULL, x NOTNULL and x NOT NULL
+                Some(Token::Word(w)) if w.eq_ignore_ascii_case("isnull") => {
+                    self.pos += 1;
+                    left = Expr::binary(BinaryOp::Is, left, Expr::Literal(Data::Null));
+                    continue;
+                }
+                Some(Token::Word(w)) if w.eq_ignore_ascii_case("notnull") => {
+                    self.pos += 1;
+                    left = Expr::binary(BinaryOp::IsNot, left, Expr::Literal(Data::Null));
+                    continue;
+                }
+                Some(Token::Word(w))
+                    if w.eq_ignore_ascii_case("not") && self.is_keyword_at(1, "null") =>
+                {
+                    self.pos += 2;
+                    left = Expr::binary(BinaryOp::IsNot, left, Expr::Literal(Data::Null));
+                    continue;
+                }
+                _ => return Ok(left),
+            };
+            self.pos += 1;
+            left = Expr::binary(op, left, self.relational()?);
+        }
+    }
+
+    fn relational(&mut self) -> Result<Expr, anyhow::Error> {
+        let mut left = self.additive()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Symbol("<")) => BinaryOp::Lt,
+                Some(Token::Symbol("<=")) => BinaryOp::Le,
+                Some(Token::Symbol(">")) => BinaryOp::Gt,
+                Some(Token::Symbol(">=")) => BinaryOp::Ge,
+                _ => return Ok(left),
+            };
+            self.pos += 1;
+            left = Expr::binary(op, left, self.additive()?);
+        }
+    }
+
+    fn additive(&mut self) -> Result<Expr, anyhow::Error> {
+        let mut left = self.multiplicative()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Symbol("+")) => BinaryOp::Add,
+                Some(Token::Symbol("-")) => BinaryOp::Sub,
+                _ => return Ok(left),
+            };
+            self.pos += 1;
+            left = Expr::binary(op, left, self.multiplicative()?);
+        }
+    }
+
+    fn multiplicative(&mut self) -> Result<Expr, anyhow::Error> {
+        let mut left = self.concat()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Symbol("*")) => BinaryOp::Mul,
+                Some(Token::Symbol("/")) => BinaryOp::Div,
+                Some(Token::Symbol("%")) => BinaryOp::Rem,
+                _ => return Ok(left),
+            };
+            self.pos += 1;
+            left = Expr::binary(op, left, self.concat()?);
+        }
+    }
+
+    fn concat(&mut self) -> Result<Expr, anyhow::Error> {
+        let mut left = self.unary()?;
+        while self.eat_symbol("||") {
+            left = Expr::binary(BinaryOp::Concat, left, self.unary()?);
+        }
+        Ok(left)
+    }
+
+    // Parses the rest of a LIKE, GLOB, REGEXP, IN or BETWEEN expression,
+    // starting with its keyword
+    fn membership(&mut self, expr: Expr, negated: bool) -> Result<Expr, anyhow::Error> {
+        let expr = Box::new(expr);
+        if self.eat_keyword("in") {
+            self.expect_symbol("(")?;
+            if self.is_select() {
+                let select = Box::new(self.select()?);
+                self.expect_symbol(")")?;
+                return Ok(Expr::InSelect {
+                    negated,
+                    expr,
+                    select,
+                });
+            }
+            let mut list = Vec::new();
+            if !self.is_symbol(")") {
+                list.push(self.expr()?);
+                while self.eat_symbol(",") {
+                    list.push(self.expr()?);
+                }
+            }
+            self.expect_symbol(")")?;
+            return Ok(Expr::InList {
+                negated,
+                expr,
+                list,
+            });
+        }
+        if self.eat_keyword("between") {
+            let low = Box::new(self.relational()?);
+            self.expect_keyword("and")?;
+            let high = Box::new(self.relational()?);
+            return Ok(Expr::Between {
+                negated,
+                expr,
+                low,
+                high,
+            });
+        }
+        let op = if self.eat_keyword("like") {
+            LikeOp::Like
+        } else if self.eat_keyword("glob") {
+            LikeOp::Glob
+        } else {
+            self.expect_keyword("regexp")?;
+            LikeOp::Regexp
+        };
+        let pattern = Box::new(self.relational()?);
+        let escape = if op == LikeOp::Like && self.eat_keyword("escape") {
+            Some(Box::new(self.relational()?))
+        } else {
+            None
+        };
+        Ok(Expr::Like {
+            op,
+            negated,
+            expr,
+            pattern,
+            escape,
+        })
+    }
+
+    fn unary(&mut self) -> Result<Expr, anyhow::Error> {
+        // The smallest integer can only be written negated
+        if self.is_symbol("-")
+            && matches!(self.tokens.get(self.pos + 1), Some(Token::Number(n)) if n == "9223372036854775808")
+        {
+            self.pos += 2;
+            return Ok(Expr::Literal(Data::Integer(i64::MIN)));
+        }
+        if self.eat_symbol("-") {
+            return Ok(Expr::Unary {
+                op: UnaryOp::Negate,
+                expr: Box::new(self.unary()?),
+            });
+        }
+        if self.eat_symbol("+") {
+            return Ok(Expr::Unary {
+                op: UnaryOp::Plus,
+                expr: Box::new(self.unary()?),
+            });
+        }
+        self.primary()
+    }
+
+    // Parses a literal, a column reference, a function call, a parenthesized
+    // expression or a subquery
+    fn primary(&mut self) -> Result<Expr, anyhow::Error> {
+        match self.peek() {
+            Some(Token::String(s)) => {
+                let value = Data::Text(s.clone());
+                self.pos += 1;
+                Ok(Expr::Literal(value))
+            }
+            Some(Token::Number(n)) => {
+                let value = parse_number(n)?;
+                self.pos += 1;
+                Ok(Expr::Literal(value))
+            }
+            Some(Token::Blob(b)) => {
+                let value = Data::Blob(b.clone());
+                self.pos += 1;
+                Ok(Expr::Literal(value))
+            }
+            Some(Token::Word(w)) if w.eq_ignore_ascii_case("null") => {
+                self.pos += 1;
+                Ok(Expr::Literal(Data::Null))
+            }
+            Some(Token::Symbol("(")) => {
+                self.pos += 1;
+                let expr = if self.is_select() {
+                    Expr::Subquery(Box::new(self.select()?))
+                } else {
+                    self.expr()?
+                };
+                self.expect_symbol(")")?;
+                Ok(expr)
+            }
+            Some(Token::Word(w)) if w.eq_ignore_ascii_case("exists") => {
+                self.pos += 1;
+                self.expect_symbol("(")?;
+                let select = self.select()?;
+                self.expect_symbol(")")?;
+                Ok(Expr::Exists(Box::new(select)))
+            }
+            Some(Token::Word(w)) if w.eq_ignore_ascii_case("case") => {
+                self.pos += 1;
+                self.case()
+            }
+            Some(Token::Word(w)) if w.eq_ignore_ascii_case("cast") => {
+                self.pos += 1;
+                self.expect_symbol("(")?;
+                let expr = Box::new(self.expr()?);
+                self.expect_keyword("as")?;
+                let type_name = self.type_name()?;
+                self.expect_symbol(")")?;
+                Ok(Expr::Cast { expr, type_name })
+            }
+            Some(Token::Word(_)) | Some(Token::QuotedIdentifier(_)) => {
+                let name = self.identifier()?;
+                if self.eat_symbol("(") {
+                    return self.function_call(name);
+                }
+                if self.eat_symbol(".") {
+                    let column = self.identifier()?;
+                    return Ok(Expr::Column(ColumnRef {
+                        table: Some(name),
+                        name: column,
+                    }));
+                }
+                Ok(Expr::Column(ColumnRef { table: None, name }))
+            }
+            _ => Err(self.error()),
+        }
+    }
+
+    // Parses a CASE expression, after the CASE keyword
+    fn case(&mut self) -> Result<Expr, anyhow::Error> {
+        let operand = if self.is_keyword("when") {
+            None
+        } else {
+            Some(Box::new(self.expr()?))
+        };
+        let mut whens = Vec::new();
+        self.expect_keyword("when")?;
+        loop {
+            let when = self.expr()?;
+            self.expect_keyword("then")?;
+            whens.push((when, self.expr()?));
+            if !self.eat_keyword("when") {
+                break;
+            }
+        }
+        let otherwise = if self.eat_keyword("else") {
+            Some(Box::new(self.expr()?))
+        } else {
+            None
+        };
+        self.expect_keyword("end")?;
+        Ok(Expr::Case {
+            operand,
+            whens,
+            otherwise,
+        })
+    }
+
+    // Parses a type name, like INTEGER, VARCHAR(10) or DOUBLE PRECISION. It
+    // may be empty. The type is kept as written, with its words separated by
+    // single spaces.
+    fn type_name(&mut self) -> Result<String, anyhow::Error> {
+        let mut words = Vec::new();
+        loop {
+            match self.peek() {
+                Some(Token::String(s)) => {
+                    words.push(s.clone());
+                    self.pos += 1;
+                }
+                Some(Token::QuotedIdentifier(_)) => words.push(self.identifier()?),
+                Some(Token::Word(w))
+                    if !is_reserved(w)
+                        && !COLUMN_CONSTRAINT_KEYWORDS
+                            .iter()
+                            .any(|k| k.eq_ignore_ascii_case(w)) =>
+                {
+                    words.push(self.identifier()?)
+                }
+                _ => break,
+            }
+        }
+        let mut type_name = words.join(" ");
+        if !words.is_empty() && self.eat_symbol("(") {
+            let mut sizes = vec![self.signed_number()?];
+            if self.eat_symbol(",") {
+                sizes.push(self.signed_number()?);
+            }
+            self.expect_symbol(")")?;
+            type_name += &format!("({})", sizes.join(","));
+        }
+        Ok(type_name)
+    }
+
+    // Parses a number with an optional sign, returning it as written
+    fn signed_number(&mut self) -> Result<String, anyhow::Error> {
+        let sign = if self.eat_symbol("-") {
+            "-"
+        } else {
+            self.eat_symbol("+");
+            ""
+        };
+        match self.peek() {
+            Some(Token::Number(n)) => {
+                let number = format!("{sign}{n}");
+                self.pos += 1;
+                Ok(number)
+            }
+            _ => Err(self.error()),
+        }
+    }
+
+    // Parses the arguments of a function call, after the opening parenthesis
+    fn function_call(&mut self, name: String) -> Result<Expr, anyhow::Error> {
+        let distinct = self.eat_keyword("distinct");
+        if !distinct {
+            self.eat_keyword("all");
+        }
+        let args = if !distinct && self.eat_symbol("*") {
+            FunctionArgs::Star
+        } else if self.is_symbol(")") {
+            FunctionArgs::List(Vec::new())
+        } else {
+            let mut args = vec![self.expr()?];
+            while self.eat_symbol(",") {
+                args.push(self.expr()?);
+            }
+            FunctionArgs::List(args)
+        };
+        self.expect_symbol(")")?;
+        if self.eat_keyword("over") {
+            if distinct {
+                bail!("DISTINCT is not supported for window functions");
+            }
+            let window = Box::new(if self.eat_symbol("(") {
+                let window = self.window()?;
+                self.expect_symbol(")")?;
+                window
+            } else {
+                Window {
+                    base: Some(self.identifier()?),
+                    ..Window::default()
+                }
+            });
+            return Ok(Expr::Window { name, args, window });
+        }
+        Ok(Expr::Function {
+            name,
+            distinct,
+            args,
+        })
+    }
+
+    // Parses a window definition, inside its parentheses
+    fn window(&mut self) -> Result<Window, anyhow::Error> {
+        let mut window = Window::default();
+        let is_base = match self.peek() {
+            Some(Token::QuotedIdentifier(_)) => true,
+            Some(Token::Word(w)) => {
+                !is_reserved(w)
+                    && !["partition", "rows", "range", "groups"]
+                        .iter()
+                        .any(|k| w.eq_ignore_ascii_case(k))
+            }
+            _ => false,
+        };
+        if is_base {
+            window.base = Some(self.identifier()?);
+        }
+        if self.eat_keyword("partition") {
+            self.expect_keyword("by")?;
+            window.partition_by.push(self.expr()?);
+            while self.eat_symbol(",") {
+                window.partition_by.push(self.expr()?);
+            }
+        }
+        if self.eat_keyword("order") {
+            self.expect_keyword("by")?;
+            window.order_by.push(self.ordering_term()?);
+            while self.eat_symbol(",") {
+                window.order_by.push(self.ordering_term()?);
+            }
+        }
+        let units = if self.eat_keyword("rows") {
+            FrameUnits::Rows
+        } else if self.eat_keyword("range") {
+            FrameUnits::Range
+        } else if self.eat_keyword("groups") {
+            FrameUnits::Groups
+        } else {
+            return Ok(window);
+        };
+        // A frame with one bound ends at the current row
+        let (start, end) = if self.eat_keyword("between") {
+            let start = self.frame_bound(true)?;
+            self.expect_keyword("and")?;
+            (start, self.frame_bound(false)?)
+        } else {
+            (self.frame_bound(true)?, FrameBound::CurrentRow)
+        };
+        // The frame can't end before it starts
+        let rank = |bound: &FrameBound| match bound {
+            FrameBound::UnboundedPreceding | FrameBound::Preceding(_) => 0,
+            FrameBound::CurrentRow => 1,
+            FrameBound::Following(_) | FrameBound::UnboundedFollowing => 2,
+        };
+        if matches!(start, FrameBound::Following(_)) && rank(&end) < 2
+            || matches!(start, FrameBound::CurrentRow) && rank(&end) < 1
+        {
+            bail!("unsupported frame specification");
+        }
+        window.frame = Some(Frame { units, start, end });
+        Ok(window)
+    }
+
+    // Parses a CREATE TABLE statement. The primary key is found once the
+    // whole table is known, since it may be declared after its columns.
+    fn create_table(&mut self) -> Result<CreateTable, anyhow::Error> {
+        self.expect_keyword("create")?;
+        if !self.eat_keyword("temp") {
+            self.eat_keyword("temporary");
+        }
+        // Virtual tables are read by their modules, which aren't built in
+        if self.eat_keyword("virtual") {
+            self.expect_keyword("table")?;
+            self.qualified_name()?;
+            self.expect_keyword("using")?;
+            bail!("no such module: {}", self.name()?);
+        }
+        self.expect_keyword("table")?;
+        if self.eat_keyword("if") {
+            self.expect_keyword("not")?;
+            self.expect_keyword("exists")?;
+        }
+        let name = self.qualified_name()?;
+        self.expect_symbol("(")?;
+        let mut columns = vec![self.column_def()?];
+        let mut constraints = Vec::new();
+        while self.eat_symbol(",") {
+            if TABLE_CONSTRAINT_KEYWORDS.iter().any(|k| self.is_keyword(k)) {
+                // Table constraints come last, and may be separated by
+                // commas or by nothing at all
+                constraints.push(self.table_constraint()?);
+                while self.eat_symbol(",")
+                    || TABLE_CONSTRAINT_KEYWORDS.iter().any(|k| self.is_keyword(k))
+                {
+                    constraints.push(self.table_constraint()?);
+                }
+                break;
+            }
+            columns.push(self.column_def()?);
+        }
+        self.expect_symbol(")")?;
+        let (mut without_rowid, mut strict) = (false, false);
+        if self.peek().is_some() && !self.is_symbol(";") {
+            loop {
+                if self.eat_keyword("without") {
+                    self.expect_keyword("rowid")?;
+                    without_rowid = true;
+                } else {
+                    self.expect_keyword("strict")?;
+                    strict = true;
+                }
+                if !self.eat_symbol(",") {
+                    break;
+                }
+            }
+        }
+        self.eat_symbol(";");
+        if self.peek().is_some() {
+            return Err(self.error());
+        }
+        for column in &mut columns {
+            // ANY columns of a STRICT table keep values as they are
+            column.affinity = if strict && column.declared_type.eq_ignore_ascii_case("any") {
+                Affinity::Blob
+            } else {
+                Affinity::from_declared_type(&column.declared_type)
+            };
+        }
+        // A primary key on a single INTEGER column makes the column another
+        // name for the rowid, unless it is declared PRIMARY KEY DESC
+        let mut keys = Vec::new();
+        for (i, column) in columns.iter().enumerate() {
+            for constraint in &column.constraints {
+                if let ColumnConstraint::PrimaryKey { descending, .. } = constraint {
+                    keys.push((Some(i), !descending));
+                }
+            }
+        }
+        for constraint in &constraints {
+            if let TableConstraint::PrimaryKey(names) = constraint {
+                let column = match names.as_slice() {
+                    [name] => columns
+                        .iter()
+                        .position(|c| c.name.eq_ignore_ascii_case(name)),
+                    _ => None,
+                };
+                keys.push((column, true));
+            }
+        }
+        if keys.len() > 1 {
+            bail!("table \"{name}\" has more than one primary key");
+        }
+        if let Some((Some(i), true)) = keys.pop() {
+            let column = &mut columns[i];
+            column.ipk = !without_rowid && column.declared_type.eq_ignore_ascii_case("integer");
+        }
+        Ok(CreateTable {
+            name,
+            columns,
+            constraints,
+            without_rowid,
+            strict,
+        })
+    }
+
+    fn create_index(&mut self) -> Result<CreateIndex, anyhow::Error> {
+        self.expect_keyword("create")?;
+        let unique = self.eat_keyword("unique");
+        self.expect_keyword("index")?;
+        if self.eat_keyword("if") {
+            self.expect_keyword("not")?;
+            self.expect_keyword("exists")?;
+        }
+        let name = self.qualified_name()?;
+        self.expect_keyword("on")?;
+        let table = self.name()?;
+        self.expect_symbol("(")?;
+        let mut columns = Vec::new();
+        loop {
+            let expr = self.expr()?;
+            let collation = if self.eat_keyword("collate") {
+                Some(self.name()?)
+            } else {
+                None
+            };
+            let descending = self.sort_order();
+            columns.push(IndexedColumn {
+                expr,
+                collation,
+                descending,
+            });
+            if !self.eat_symbol(",") {
+                break;
+            }
+        }
+        self.expect_symbol(")")?;
+        let where_ = if self.eat_keyword("where") {
+            Some(self.expr()?)
+        } else {
+            None
+        };
+        self.eat_symbol(";");
+        if self.peek().is_some() {
+            return Err(self.error());
+        }
+        Ok(CreateIndex {
+            name,
+            table,
+            unique,
+            columns,
+            where_,
+        })
+    }
+
+    // Parses the name of a table, column or collation in a CREATE TABLE
+    // statement. SQLite has checked the statements stored in a database, so
+    // any word is taken as a name, including the keywords SQLite allows as
+    // names, like KEY or DESC. Names may also be written as strings.
+    fn name(&mut self) -> Result<String, anyhow::Error> {
+        match self.peek() {
+            Some(Token::Word(name) | Token::QuotedIdentifier(name) | Token::String(name)) => {
+                let name = name.clone();
+                self.pos += 1;
+                Ok(name)
+            }
+            _ => Err(self.error()),
+        }
+    }
+
+    // Parses a table name, which may be qualified by the name of its schema
+    fn qualified_name(&mut self) -> Result<String, anyhow::Error> {
+        let name = self.name()?;
+        if self.eat_symbol(".") {
+            return self.name();
+        }
+        Ok(name)
+    }
+
+    // Parses a parenthesized list of column names. The columns of keys may
+    // have a collation and a sort order, which are left out.
+    fn column_names(&mut self) -> Result<Vec<String>, anyhow::Error> {
+        self.expect_symbol("(")?;
+        let mut names = Vec::new();
+        loop {
+            names.push(self.name()?);
+            if self.eat_keyword("collate") {
+                self.name()?;
+            }
+            self.sort_order();
+            if !self.eat_symbol(",") {
+                break;
+            }
+        }
+        self.expect_symbol(")")?;
+        Ok(names)
+    }
+
+    // Consumes an optional ASC or DESC, returning true for DESC
+    fn sort_order(&mut self) -> bool {
+        if self.eat_keyword("desc") {
+            return true;
+        }
+        self.eat_keyword("asc");
+        false
+    }
+
+    fn column_def(&mut self) -> Result<ColumnDef, anyhow::Error> {
+        let name = self.name()?;
+        let declared_type = self.type_name()?;
+        let mut constraints = Vec::new();
+        loop {
+            // A constraint may be named, and the name may stand alone
+            if self.eat_keyword("constraint") {
+                self.name()?;
+            }
+            let constraint = if self.eat_keyword("primary") {
+                self.expect_keyword("key")?;
+                let descending = self.sort_order();
+                self.conflict_clause()?;
+                ColumnConstraint::PrimaryKey {
+                    descending,
+                    autoincrement: self.eat_keyword("autoincrement"),
+                }
+            } else if self.eat_keyword("not") {
+                self.expect_keyword("null")?;
+                self.conflict_clause()?;
+                ColumnConstraint::NotNull
+            } else if self.eat_keyword("null") {
+                // NULL allows NULLs, which every column does anyway
+                self.conflict_clause()?;
+                continue;
+            } else if self.eat_keyword("unique") {
+                self.conflict_clause()?;
+                ColumnConstraint::Unique
+            } else if self.eat_keyword("check") {
+                ColumnConstraint::Check(self.parenthesized_expr()?)
+            } else if self.eat_keyword("default") {
+                ColumnConstraint::Default(self.default_value()?)
+            } else if self.eat_keyword("collate") {
+                ColumnConstraint::Collate(self.name()?)
+            } else if self.eat_keyword("references") {
+                ColumnConstraint::References(self.foreign_key()?)
+            } else if self.is_keyword("generated") || self.is_keyword("as") {
+                if self.eat_keyword("generated") {
+                    self.expect_keyword("always")?;
+                }
+                self.expect_keyword("as")?;
+                let expr = self.parenthesized_expr()?;
+                let stored = self.eat_keyword("stored");
+                if !stored {
+                    self.eat_keyword("virtual");
+                }
+                ColumnConstraint::Generated { expr, stored }
+            } else {
+                break;
+            };
+            constraints.push(constraint);
+        }
+        Ok(ColumnDef {
+            name,
+            declared_type,
+            // Set once the table is known to be STRICT or not
+            affinity: Affinity::Blob,
+            constraints,
+            ipk: false,
+        })
+    }
+
+    fn parenthesized_expr(&mut self) -> Result<Expr, anyhow::Error> {
+        self.expect_symbol("(")?;
+        let expr = self.expr()?;
+        self.expect_symbol(")")?;
+        Ok(expr)
+    }
+
+    // Parses the value of a DEFAULT constraint: a literal, a signed number,
+    // a parenthesized expression, or a name, which stands for its own text
+    fn default_value(&mut self) -> Result<Expr, anyhow::Error> {
+        if self.is_symbol("-") || self.is_symbol("+") {
+            return Ok(Expr::Literal(parse_number(&self.signed_number()?)?));
+        }
+        match self.peek() {
+            Some(Token::Symbol("(")) => self.parenthesized_expr(),
+            Some(Token::Word(w))
+                if w.eq_ignore_ascii_case("true") || w.eq_ignore_ascii_case("false") =>
+            {
+                let value = w.eq_ignore_ascii_case("true") as i64;
+                self.pos += 1;
+                Ok(Expr::Literal(Data::Integer(value)))
+            }
+            // The time the row is written, which only matters when writing
+            Some(Token::Word(w))
+                if ["current_time", "current_date", "current_timestamp"]
+                    .iter()
+                    .any(|k| k.eq_ignore_ascii_case(w)) =>
+            {
+                let name = w.to_ascii_lowercase();
+                self.pos += 1;
+                Ok(Expr::Function {
+                    name,
+                    distinct: false,
+                    args: FunctionArgs::List(Vec::new()),
+                })
+            }
+            Some(Token::Word(w)) if !w.eq_ignore_ascii_case("null") => {
+                Ok(Expr::Literal(Data::Text(self.name()?)))
+            }
+            Some(Token::QuotedIdentifier(_)) => Ok(Expr::Literal(Data::Text(self.name()?))),
+            _ => self.primary(),
+        }
+    }
+
+    // Parses an optional ON CONFLICT clause, which only matters when writing
+    fn conflict_clause(&mut self) -> Result<(), anyhow::Error> {
+        if self.eat_keyword("on") {
+            self.expect_keyword("conflict")?;
+            let resolutions = ["rollback", "abort", "fail", "ignore", "replace"];
+            if !resolutions.iter().any(|r| self.eat_keyword(r)) {
+                return Err(self.error());
+            }
+        }
+        Ok(())
+    }
+
+    // Parses the table and columns of a foreign key, after REFERENCES. Its
+    // actions and deferral only matter when writing, so they are left out.
+    fn foreign_key(&mut self) -> Result<ForeignKey, anyhow::Error> {
+        let table = self.name()?;
+        let columns = if self.is_symbol("(") {
+            self.column_names()?
+        } else {
+            Vec::new()
+        };
+        loop {
+            if self.eat_keyword("on") {
+                if !self.eat_keyword("delete") {
+                    self.expect_keyword("update")?;
+                }
+                if self.eat_keyword("set") {
+                    if !self.eat_keyword("null") {
+                        self.expect_keyword("default")?;
+                    }
+                } else if self.eat_keyword("no") {
+                    self.expect_keyword("action")?;
+                } else if !self.eat_keyword("cascade") {
+                    self.expect_keyword("restrict")?;
+                }
+            } else if self.eat_keyword("match") {
+                self.name()?;
+            } else if self.is_keyword("deferrable")
+                || (self.is_keyword("not") && self.is_keyword_at(1, "deferrable"))
+            {
+                self.eat_keyword("not");
+                self.pos += 1;
+                if self.eat_keyword("initially") && !self.eat_keyword("deferred") {
+                    self.expect_keyword("immediate")?;
+                }
+            } else {
+                break;
+            }
+        }
+        Ok(ForeignKey { table, columns })
+    }
+
+    fn table_constraint(&mut self) -> Result<TableConstraint, anyhow::Error> {
+        if self.eat_keyword("constraint") {
+            self.name()?;
+        }
+        let constraint = if self.eat_keyword("primary") {
+            self.expect_keyword("key")?;
+            let names = self.column_names()?;
+            self.eat_keyword("autoincrement");
+            TableConstraint::PrimaryKey(names)
+        } else if self.eat_keyword("unique") {
+            TableConstraint::Unique(self.column_names()?)
+        } else if self.eat_keyword("check") {
+            return Ok(TableConstraint::Check(self.parenthesized_expr()?));
+        } else {
+            self.expect_keyword("foreign")?;
+            self.expect_keyword("key")?;
+            let columns = self.column_names()?;
+            self.expect_keyword("references")?;
+            let references = self.foreign_key()?;
+            return Ok(TableConstraint::ForeignKey {
+                columns,
+                references,
+            });
+        };
+        self.conflict_clause()?;
+        Ok(constraint)
+    }
+
+    // Parses one end of a frame. A frame can't start at UNBOUNDED FOLLOWING,
+    // or end at UNBOUNDED PRECEDING.
+    fn frame_bound(&mut self, start: bool) -> Result<FrameBound, anyhow::Error> {
+        if self.eat_keyword("unbounded") {
+            if start {
+                self.expect_keyword("preceding")?;
+                return Ok(FrameBound::UnboundedPreceding);
+            }
+            self.expect_keyword("following")?;
+            return Ok(FrameBound::UnboundedFollowing);
+        }
+        if self.eat_keyword("current") {
+            self.expect_keyword("row")?;
+            return Ok(FrameBound::CurrentRow);
+        }
+        let offset = self.expr()?;
+        if self.eat_keyword("preceding") {
+            Ok(FrameBound::Preceding(offset))
+        } else {
+            self.expect_keyword("following")?;
+            Ok(FrameBound::Following(offset))
+        }
+    }
+}
+
+// Converts a numeric literal to a value. Decimal integers too large for 64
+// bits become reals, while hexadecimal literals must fit in 64 bits.
+fn parse_number(n: &str) -> Result<Data, anyhow::Error> {
+    // Signed numbers, like the DEFAULT of a column, are parsed as a whole
+    if let Some(unsigned) = n.strip_prefix('-') {
+        if unsigned == "9223372036854775808" {
+            return Ok(Data::Integer(i64::MIN));
+        }
+        return Ok(match parse_number(unsigned)? {
+            Data::Integer(i) => Data::Integer(i.wrapping_neg()),
+            Data::Float(x) => Data::Float(-x),
+            value => value,
+        });
+    }
+    if let Some(hex) = n.strip_prefix("0x").or_else(|| n.strip_prefix("0X")) {
+        return u64::from_str_radix(hex, 16)
+            .map(|i| Data::Integer(i as i64))
+            .map_err(|_| anyhow!("hex literal too big: {n}"));
+    }
+    if let Ok(i) = n.parse::<i64>() {
+        return Ok(Data::Integer(i));
+    }
+    n.parse::<f64>()
+        .map(Data::Float)
+        .map_err(|_| anyhow!("malformed number: {n}"))
+}
+
+// Parses one SQL statement
+pub fn parse_statement(sql: &str) -> Result<Statement, anyhow::Error> {
+    let mut parser = Parser {
+        tokens: tokenize(sql)?,
+        pos: 0,
+    };
+    parser.statement()
+}
+
+// Parses the CREATE TABLE statement of a table in the schema table
+pub fn parse_create_table(sql: &str) -> Result<CreateTable, anyhow::Error> {
+    let mut parser = Parser {
+        tokens: tokenize(sql)?,
+        pos: 0,
+    };
+    parser.create_table()
+}
+
+// Parses the CREATE INDEX statement of an index in the schema table
+pub fn parse_create_index(sql: &str) -> Result<CreateIndex, anyhow::Error> {
+    let mut parser = Parser {
+        tokens: tokenize(sql)?,
+        pos: 0,
+    };
+    parser.create_index()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(expr: Expr) -> ResultColumn {
+        ResultColumn::Expr { expr, alias: None }
+    }
+
+    fn column(name: &str) -> Expr {
+        Expr::Column(ColumnRef {
+            table: None,
+            name: name.to_string(),
+        })
+    }
+
+    fn select(sql: &str) -> Select {
+        match parse_statement(sql).unwrap() {
+            Statement::Select(select) => select,
+        }
+    }
+
+    #[test]
+    fn test_select() {
+        let select =
+            select("SELECT id, name, eye_color FROM superheroes WHERE eye_color = 'Pink Eyes'");
+        assert_eq!(
+            select,
+            Select {
+                with: None,
+                distinct: false,
+                columns: vec![
+                    result(column("id")),
+                    result(column("name")),
+                    result(column("eye_color")),
+                ],
+                from: Some(TableRef::Table {
+                    name: "superheroes".to_string(),
+                    alias: None,
+                }),
+                joins: vec![],
+                where_: Some(Expr::Binary {
+                    op: BinaryOp::Eq,
+                    left: Box::new(column("eye_color")),
+                    right: Box::new(Expr::Literal(Data::Text("Pink Eyes".to_string()))),
+                }),
+                group_by: vec![],
+                having: None,
+                windows: vec![],
+                compound: vec![],
+                order_by: vec![],
+                limit: None,
+                offset: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_count_and_quoting() {
+        let select = select("select COUNT(*) from \"my table\" where [t].\"a b\" = 'it''s';");
+        assert_eq!(
+            select.columns,
+            vec![result(Expr::Function {
+                name: "COUNT".to_string(),
+                distinct: false,
+                args: FunctionArgs::Star,
+            })]
+        );
+        assert_eq!(
+            select.from,
+            Some(TableRef::Table {
+                name: "my table".to_string(),
+                alias: None,
+            })
+        );
+        assert_eq!(
+            select.where_,
+            Some(Expr::Binary {
+                op: BinaryOp::Eq,
+                left: Box::new(Expr::Column(ColumnRef {
+                    table: Some("t".to_string()),
+                    name: "a b".to_string(),
+                })),
+                right: Box::new(Expr::Literal(Data::Text("it's".to_string()))),
+            })
+        );
+    }
+
+    #[test]
+    fn test_boolean_precedence() {
+        let text = |s: &str| Expr::Literal(Data::Text(s.to_string()));
+        let select =
+            select("select a from t where not a = 'x' or b <= 'y' and (c <> 'z' or d > 'w')");
+        assert_eq!(
+            select.where_,
+            Some(Expr::binary(
+                BinaryOp::Or,
+                Expr::Unary {
+                    op: UnaryOp::Not,
+                    expr: Box::new(Expr::binary(BinaryOp::Eq, column("a"), text("x"))),
+                },
+                Expr::binary(
+                    BinaryOp::And,
+                    Expr::binary(BinaryOp::Le, column("b"), text("y")),
+                    Expr::binary(
+                        BinaryOp::Or,
+                        Expr::binary(BinaryOp::Ne, column("c"), text("z")),
+                        Expr::binary(BinaryOp::Gt, column("d"), text("w")),
+                    ),
+                ),
+            ))
+        );
+    }
+
+    #[test]
+    fn test_literals() {
+        let select =
+            select("select 1, 2.5, 1e2, 0x10, 99999999999999999999, -3, null, x'AB' from t");
+        let literals = select
+            .columns
+            .into_iter()
+            .map(|column| match column {
+                ResultColumn::Expr { expr, .. } => expr,
+                column => panic!("not an expression: {column:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            literals,
+            vec![
+                Expr::Literal(Data::Integer(1)),
+                Expr::Literal(Data::Float(2.5)),
+                Expr::Literal(Data::Float(100.0)),
+                Expr::Literal(Data::Integer(16)),
+                Expr::Literal(Data::Float(1e20)),
+                Expr::Unary {
+                    op: UnaryOp::Negate,
+                    expr: Box::new(Expr::Literal(Data::Integer(3))),
+                },
+                Expr::Literal(Data::Null),
+                Expr::Literal(Data::Blob(vec![0xab])),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_null_tests() {
+        let null = || Expr::Literal(Data::Null);
+        for (sql, op) in [
+            ("a is null", BinaryOp::Is),
+            ("a isnull", BinaryOp::Is),
+            ("a is not null", BinaryOp::IsNot),
+            ("a notnull", BinaryOp::IsNot),
+            ("a not null", BinaryOp::IsNot),
+        ] {
+            let select = select(&format!("select a from t where {sql}"));
+            assert_eq!(select.where_, Some(Expr::binary(op, column("a"), null())));
+        }
+    }
+
+    #[test]
+    fn test_membership_operators() {
+        let text = |s: &str| Box::new(Expr::Literal(Data::Text(s.to_string())));
+        let select = select(
+            "select a from t where a not like 'x!%' escape '!' and b in ('CA', 'NY') \
+             and c not between 'a' and 'b' or d glob 'x*'",
+        );
+        let like = Expr::Like {
+            op: LikeOp::Like,
+            negated: true,
+            expr: Box::new(column("a")),
+            pattern: text("x!%"),
+            escape: Some(text("!")),
+        };
+        let in_list = Expr::InList {
+            negated: false,
+            expr: Box::new(column("b")),
+            list: vec![*text("CA"), *text("NY")],
+        };
+        let between = Expr::Between {
+            negated: true,
+            expr: Box::new(column("c")),
+            low: text("a"),
+            high: text("b"),
+        };
+        let glob = Expr::Like {
+            op: LikeOp::Glob,
+            negated: false,
+            expr: Box::new(column("d")),
+            pattern: text("x*"),
+            escape: None,
+        };
+        assert_eq!(
+            select.where_,
+            Some(Expr::binary(
+                BinaryOp::Or,
+                Expr::binary(
+                    BinaryOp::And,
+                    Expr::binary(BinaryOp::And, like, in_list),
+                    between
+                ),
+                glob
+            ))
+        );
+    }
+
+    #[test]
+    fn test_order_by_and_limit() {
+        let select = select(
+            "select a from t order by a desc nulls first, b, 2 asc nulls last limit 5 offset 2",
+        );
+        assert_eq!(
+            select.order_by,
+            vec![
+                OrderingTerm {
+                    expr: column("a"),
+                    descending: true,
+                    nulls: Some(NullsOrder::First),
+                },
+                OrderingTerm {
+                    expr: column("b"),
+                    descending: false,
+                    nulls: None,
+                },
+                OrderingTerm {
+                    expr: Expr::Literal(Data::Integer(2)),
+                    descending: false,
+                    nulls: Some(NullsOrder::Last),
+                },
+            ]
+        );
+        assert_eq!(select.limit, Some(Expr::Literal(Data::Integer(5))));
+        assert_eq!(select.offset, Some(Expr::Literal(Data::Integer(2))));
+        let select = self::select("select a from t limit 2, 10");
+        assert_eq!(select.limit, Some(Expr::Literal(Data::Integer(10))));
+        assert_eq!(select.offset, Some(Expr::Literal(Data::Integer(2))));
+    }
+
+    #[test]
+    fn test_projection() {
+        let select = select("select *, t.*, a + 1 AS x, b y, c as 'z' from t");
+        assert_eq!(
+            select.columns,
+            vec![
+                ResultColumn::Star(None),
+                ResultColumn::Star(Some("t".to_string())),
+                ResultColumn::Expr {
+                    expr: Expr::binary(BinaryOp::Add, column("a"), Expr::Literal(Data::Integer(1))),
+                    alias: Some("x".to_string()),
+                },
+                ResultColumn::Expr {
+                    expr: column("b"),
+                    alias: Some("y".to_string()),
+                },
+                ResultColumn::Expr {
+                    expr: column("c"),
+                    alias: Some("z".to_string()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_arithmetic_precedence() {
+        let int = |i: i64| Expr::Literal(Data::Integer(i));
+        let select = select("select a from t where a - 2 * b || c < +d % 3 + -e");
+        assert_eq!(
+            select.where_,
+            Some(Expr::binary(
+                BinaryOp::Lt,
+                Expr::binary(
+                    BinaryOp::Sub,
+                    column("a"),
+                    Expr::binary(
+                        BinaryOp::Mul,
+                        int(2),
+                        Expr::binary(BinaryOp::Concat, column("b"), column("c")),
+                    ),
+                ),
+                Expr::binary(
+                    BinaryOp::Add,
+                    Expr::binary(
+                        BinaryOp::Rem,
+                        Expr::Unary {
+                            op: UnaryOp::Plus,
+                            expr: Box::new(column("d")),
+                        },
+                        int(3),
+                    ),
+                    Expr::Unary {
+                        op: UnaryOp::Negate,
+                        expr: Box::new(column("e")),
+                    },
+                ),
+            ))
+        );
+    }
+
+    #[test]
+    fn test_joins() {
+        let select = select(
+            "select * from a x, b as y join c using (id, k) left outer join d on d.id = x.id \
+             natural join e cross join f",
+        );
+        let table = |name: &str, alias: Option<&str>| TableRef::Table {
+            name: name.to_string(),
+            alias: alias.map(str::to_string),
+        };
+        assert_eq!(select.from, Some(table("a", Some("x"))));
+        let on = Expr::binary(
+            BinaryOp::Eq,
+            Expr::Column(ColumnRef {
+                table: Some("d".to_string()),
+                name: "id".to_string(),
+            }),
+            Expr::Column(ColumnRef {
+                table: Some("x".to_string()),
+                name: "id".to_string(),
+            }),
+        );
+        assert_eq!(
+            select.joins,
+            vec![
+                Join {
+                    kind: JoinKind::Inner,
+                    table: table("b", Some("y")),
+                    constraint: None,
+                },
+                Join {
+                    kind: JoinKind::Inner,
+                    table: table("c", None),
+                    constraint: Some(JoinConstraint::Using(vec![
+                        "id".to_string(),
+                        "k".to_string()
+                    ])),
+                },
+                Join {
+                    kind: JoinKind::Left,
+                    table: table("d", None),
+                    constraint: Some(JoinConstraint::On(on)),
+                },
+                Join {
+                    kind: JoinKind::Inner,
+                    table: table("e", None),
+                    constraint: Some(JoinConstraint::Natural),
+                },
+                Join {
+                    kind: JoinKind::Inner,
+                    table: table("f", None),
+                    constraint: None,
+                },
+            ]
+        );
+        for sql in [
+            "select * from a right join b on 1",
+            "select * from a x full outer join b on 1",
+            "select * from a natural join b using (id)",
+        ] {
+            assert!(parse_statement(sql).is_err(), "{sql}");
+        }
+    }
+
+    #[test]
+    fn test_group_by() {
+        let select = select("select a, count(distinct b) from t group by a, c having sum(b) > 1");
+        assert_eq!(select.group_by, vec![column("a"), column("c")]);
+        assert_eq!(
+            select.columns[1],
+            result(Expr::Function {
+                name: "count".to_string(),
+                distinct: true,
+                args: FunctionArgs::List(vec![column("b")]),
+            })
+        );
+        assert_eq!(
+            select.having,
+            Some(Expr::binary(
+                BinaryOp::Gt,
+                Expr::Function {
+                    name: "sum".to_string(),
+                    distinct: false,
+                    args: FunctionArgs::List(vec![column("b")]),
+                },
+                Expr::Literal(Data::Integer(1))
+            ))
+        );
+    }
+
+    #[test]
+    fn test_subqueries() {
+        let outer = select(
+            "select (select max(b) from u), a from (select a from t) s \
+             where a not in (select a from v) and not exists (select 1)",
+        );
+        let inner = |sql| Box::new(select(sql));
+        assert_eq!(
+            outer.columns[0],
+            result(Expr::Subquery(inner("select max(b) from u")))
+        );
+        assert_eq!(
+            outer.from,
+            Some(TableRef::Subquery {
+                select: inner("select a from t"),
+                alias: Some("s".to_string()),
+            })
+        );
+        assert_eq!(
+            outer.where_,
+            Some(Expr::binary(
+                BinaryOp::And,
+                Expr::InSelect {
+                    negated: true,
+                    expr: Box::new(column("a")),
+                    select: inner("select a from v"),
+                },
+                Expr::Unary {
+                    op: UnaryOp::Not,
+                    expr: Box::new(Expr::Exists(inner("select 1"))),
+                }
+            ))
+        );
+        // A parenthesized expression is still just the expression
+        assert_eq!(select("select (a)").columns[0], result(column("a")));
+        assert!(parse_statement("select exists select 1").is_err());
+    }
+
+    #[test]
+    fn test_compound_select() {
+        let parsed =
+            select("select distinct a from t union all select b from u order by 1 limit 2");
+        assert!(parsed.distinct);
+        assert_eq!(parsed.compound.len(), 1);
+        assert_eq!(parsed.compound[0].0, CompoundOp::UnionAll);
+        assert_eq!(parsed.compound[0].1, select("select b from u"));
+        assert_eq!(parsed.order_by.len(), 1);
+        assert!(parsed.limit.is_some());
+        assert_eq!(
+            parse_statement("select a from t limit 1 except select b from u")
+                .unwrap_err()
+                .to_string(),
+            "LIMIT clause should come after EXCEPT not before"
+        );
+    }
+
+    #[test]
+    fn test_with_clause() {
+        let parsed = select(
+            "with recursive t(x, y) as (select 1, 2), u as materialized (select * from t) select * from u",
+        );
+        let with = parsed.with.unwrap();
+        assert_eq!(with.len(), 2);
+        assert_eq!(with[0].name, "t");
+        assert_eq!(with[0].columns, vec!["x".to_string(), "y".to_string()]);
+        assert_eq!(with[0].select, select("select 1, 2"));
+        assert!(with[1].columns.is_empty());
+        // A subquery can have a WITH clause of its own
+        let parsed = select("select (with t as (select 1) select * from t)");
+        let ResultColumn::Expr {
+            expr: Expr::Subquery(subquery),
+            ..
+        } = &parsed.columns[0]
+        else {
+            panic!("expected a subquery");
+        };
+        assert!(subquery.with.is_some());
+        assert_eq!(
+            parse_statement("with t as (select 1), T as (select 2) select * from t")
+                .unwrap_err()
+                .to_string(),
+            "duplicate WITH table name: T"
+        );
+    }
+
+    #[test]
+    fn test_window() {
+        let parsed = select(
+            "select sum(a) over (w order by b rows between 2 preceding and current row) from t window w as (partition by c)",
+        );
+        let ResultColumn::Expr {
+            expr: Expr::Window { name, window, .. },
+            ..
+        } = &parsed.columns[0]
+        else {
+            panic!("expected a window function call");
+        };
+        assert_eq!(name, "sum");
+        assert_eq!(window.base.as_deref(), Some("w"));
+        assert_eq!(window.order_by.len(), 1);
+        assert_eq!(
+            window.frame,
+            Some(Frame {
+                units: FrameUnits::Rows,
+                start: FrameBound::Preceding(Expr::Literal(Data::Integer(2))),
+                end: FrameBound::CurrentRow,
+            })
+        );
+        assert_eq!(parsed.windows.len(), 1);
+        assert_eq!(parsed.windows[0].1.partition_by, vec![column("c")]);
+        let err = |sql| parse_statement(sql).unwrap_err().to_string();
+        assert_eq!(
+            err("select count(distinct a) over () from t"),
+            "DISTINCT is not supported for window functions"
+        );
+        assert_eq!(
+            err("select sum(a) over (rows 1 following) from t"),
+            "unsupported frame specification"
+        );
+    }
+
+    #[test]
+    fn test_case_and_cast() {
+        let parsed =
+            select("select case a when 1 then 'one' else b end, cast(a as varchar (10)) from t");
+        let expr = |i: usize| match &parsed.columns[i] {
+            ResultColumn::Expr { expr, .. } => expr.clone(),
+            _ => panic!("expected an expression"),
+        };
+        assert_eq!(
+            expr(0),
+            Expr::Case {
+                operand: Some(Box::new(column("a"))),
+                whens: vec![(
+                    Expr::Literal(Data::Integer(1)),
+                    Expr::Literal(Data::Text("one".to_string()))
+                )],
+                otherwise: Some(Box::new(column("b"))),
+            }
+        );
+        assert_eq!(
+            expr(1),
+            Expr::Cast {
+                expr: Box::new(column("a")),
+                type_name: "varchar(10)".to_string(),
+            }
+        );
+        let parsed = select("select cast(a as double precision), cast(a as int(-5, +2)) from t");
+        assert!(matches!(&parsed.columns[0],
+            ResultColumn::Expr { expr: Expr::Cast { type_name, .. }, .. } if type_name == "double precision"));
+        assert!(matches!(&parsed.columns[1],
+            ResultColumn::Expr { expr: Expr::Cast { type_name, .. }, .. } if type_name == "int(-5,2)"));
+        let err = |sql| parse_statement(sql).unwrap_err().to_string();
+        assert_eq!(err("select case a end"), "near \"end\": syntax error");
+        assert_eq!(err("select case when a then b"), "incomplete input");
+        assert_eq!(
+            err("select cast(a integer)"),
+            "near \"integer\": syntax error"
+        );
+        assert_eq!(err("select cast(a as int(x))"), "near \"x\": syntax error");
+    }
+
+    #[test]
+    fn test_create_table() {
+        let table =
+            parse_create_table("create table foo (id integer primary key, name text)").unwrap();
+        assert_eq!(table.name, "foo");
+        assert_eq!(
+            table.columns,
+            vec![
+                ColumnDef {
+                    name: "id".to_string(),
+                    declared_type: "integer".to_string(),
+                    affinity: Affinity::Integer,
+                    constraints: vec![ColumnConstraint::PrimaryKey {
+                        descending: false,
+                        autoincrement: false,
+                    }],
+                    ipk: true,
+                },
+                ColumnDef {
+                    name: "name".to_string(),
+                    declared_type: "text".to_string(),
+                    affinity: Affinity::Text,
+                    constraints: vec![],
+                    ipk: false,
+                }
+            ]
+        );
+        let table = parse_create_table(
+            "CREATE TABLE IF NOT EXISTS main.\"t t\"([a b] VARCHAR(255) NOT NULL DEFAULT 'x' COLLATE NOCASE, \
+             `c` int CONSTRAINT c_fk REFERENCES u(id) ON DELETE SET NULL NOT DEFERRABLE CHECK (c > 0), \
+             d DEFAULT -5 UNIQUE ON CONFLICT REPLACE, e AS (c * 2) STORED, \
+             PRIMARY KEY (c DESC), FOREIGN KEY (d) REFERENCES u CHECK (d < 10)) STRICT, WITHOUT ROWID",
+        )
+        .unwrap();
+        assert_eq!(table.name, "t t");
+        let names = table
+            .columns
+            .iter()
+            .map(|c| c.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["a b", "c", "d", "e"]);
+        assert_eq!(table.columns[0].declared_type, "VARCHAR(255)");
+        assert_eq!(
+            table.columns[0].constraints,
+            vec![
+                ColumnConstraint::NotNull,
+                ColumnConstraint::Default(Expr::Literal(Data::Text("x".to_string()))),
+                ColumnConstraint::Collate("NOCASE".to_string()),
+            ]
+        );
+        assert_eq!(
+            table.columns[1].constraints[0],
+            ColumnConstraint::References(ForeignKey {
+                table: "u".to_string(),
+                columns: vec!["id".to_string()],
+            })
+        );
+        assert_eq!(
+            table.columns[2].constraints,
+            vec![
+                ColumnConstraint::Default(Expr::Literal(Data::Integer(-5))),
+                ColumnConstraint::Unique,
+            ]
+        );
+        assert!(matches!(
+            table.columns[3].constraints[0],
+            ColumnConstraint::Generated { stored: true, .. }
+        ));
+        assert_eq!(table.constraints.len(), 3);
+        assert_eq!(
+            table.constraints[0],
+            TableConstraint::PrimaryKey(vec!["c".to_string()])
+        );
+        // A WITHOUT ROWID table has no rowid to alias
+        assert!(table.without_rowid && table.strict);
+        assert!(table.columns.iter().all(|c| !c.ipk));
+    }
+
+    #[test]
+    fn test_create_index() {
+        let index = parse_create_index(
+            "CREATE UNIQUE INDEX IF NOT EXISTS main.\"i(1\" ON \"t+u\" (a COLLATE nocase DESC, b + 1) WHERE b = 'keep'",
+        )
+        .unwrap();
+        assert_eq!(index.name, "i(1");
+        assert_eq!(index.table, "t+u");
+        assert!(index.unique);
+        assert_eq!(
+            index.columns,
+            vec![
+                IndexedColumn {
+                    expr: column("a"),
+                    collation: Some("nocase".to_string()),
+                    descending: true,
+                },
+                IndexedColumn {
+                    expr: Expr::binary(BinaryOp::Add, column("b"), Expr::Literal(Data::Integer(1))),
+                    collation: None,
+                    descending: false,
+                },
+            ]
+        );
+        assert_eq!(
+            index.where_,
+            Some(Expr::binary(
+                BinaryOp::Eq,
+                column("b"),
+                Expr::Literal(Data::Text("keep".to_string()))
+            ))
+        );
+        assert!(parse_create_index("create index i on t(a) x").is_err());
+    }
+
+    #[test]
+    fn test_rowid_alias() {
+        let ipk = |sql| {
+            let table = parse_create_table(sql).unwrap();
+            table.columns.iter().position(|c| c.ipk)
+        };
+        assert_eq!(
+            ipk("create table t(a, b INTEGER, primary key(b desc))"),
+            Some(1)
+        );
+        assert_eq!(
+            ipk("create table t(a \"integer\" primary key asc)"),
+            Some(0)
+        );
+        assert_eq!(ipk("create table t(a integer primary key desc)"), None);
+        assert_eq!(ipk("create table t(a int primary key)"), None);
+        assert_eq!(ipk("create table t(a integer(10) primary key)"), None);
+        assert_eq!(ipk("create table t(a integer, b, primary key(a, b))"), None);
+        let err = |sql| parse_create_table(sql).unwrap_err().to_string();
+        assert_eq!(
+            err("create table t(a primary key, b, primary key(b))"),
+            "table \"t\" has more than one primary key"
+        );
+        assert_eq!(
+            err("create virtual table t using fts5(a)"),
+            "no such module: fts5"
+        );
+        assert_eq!(
+            err("create table t(a) rowid"),
+            "near \"rowid\": syntax error"
+        );
+    }
+
+    #[test]
+    fn test_syntax_errors() {
+        let err = |sql| parse_statement(sql).unwrap_err().to_string();
+        assert_eq!(err("select from t"), "near \"from\": syntax error");
+        assert_eq!(err("select a from"), "incomplete input");
+        assert_eq!(err("select a from t t2 t3"), "near \"t3\": syntax error");
+    }
+}
@@ -0,0 +1,255 @@
+use crate::cache::{CacheStats, PageCache};
+
+use anyhow::bail;
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Deref;
+use std::rc::Rc;
+
+// How the pages of a database file are read
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Backend {
+    // Read pages with regular file reads, keeping recently used pages in a cache
+    #[default]
+    File,
+    // Map the whole file into memory, and hand out slices of the mapping
+    Mmap,
+}
+
+// The bytes of a single page. Cached pages are shared with the page cache,
+// and mapped pages borrow from the mapping, so neither is copied.
+#[derive(Debug, Clone)]
+pub enum PageBytes {
+    Cached(Rc<[u8]>),
+    Mapped {
+        map: Rc<Mmap>,
+        offset: usize,
+        len: usize,
+    },
+}
+
+impl Deref for PageBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            PageBytes::Cached(page) => page,
+            PageBytes::Mapped { map, offset, len } => &map[*offset..*offset + *len],
+        }
+    }
+}
+
+impl From<Vec<u8>> for PageBytes {
+    fn from(page: Vec<u8>) -> Self {
+        PageBytes::Cached(Rc::from(page))
+    }
+}
+
+// Where the pages of an open database come from
+pub enum Storage {
+    File {
+        file: RefCell<File>,
+        cache: RefCell<PageCache>,
+    },
+    // The mapping is replaced with a larger one when a page past its end is
+    // requested and the file has grown. Pages handed out earlier keep the old
+    // mapping alive.
+    Mapped {
+        file: File,
+        map: RefCell<Rc<Mmap>>,
+    },
+}
+
+impl Storage {
+    pub fn open(file: File, backend: Backend, cache_pages: usize) -> Result<Self, anyhow::Error> {
+        match backend {
+            Backend::File => Ok(Storage::File {
+                file: RefCell::new(file),
+                cache: RefCell::new(PageCache::new(cache_pages)),
+            }),
+            Backend::Mmap => {
+                let map = Mmap::map(&file)?;
+                Ok(Storage::Mapped {
+                    file,
+                    map: RefCell::new(Rc::new(map)),
+                })
+            }
+        }
+    }
+
+    // Read a page given its number, which starts at 1
+    pub fn read_page(
+        &self,
+        page_number: u64,
+        page_size: usize,
+    ) -> Result<PageBytes, anyhow::Error> {
+        if page_number == 0 {
+            bail!("Invalid page number 0");
+        }
+        let offset = (page_number - 1) as usize * page_size;
+        match self {
+            Storage::File { file, cache } => {
+                if let Some(page) = cache.borrow_mut().get(page_number) {
+                    return Ok(PageBytes::Cached(page));
+                }
+                let mut file = file.borrow_mut();
+                file.seek(SeekFrom::Start(offset as u64))?;
+                let mut buf = vec![0; page_size];
+                file.read_exact(&mut buf)?;
+                let page: Rc<[u8]> = Rc::from(buf);
+                cache.borrow_mut().insert(page_number, page.clone());
+                Ok(PageBytes::Cached(page))
+            }
+            Storage::Mapped { file, map } => {
+                if map.borrow().len() < offset + page_size {
+                    let file_size = file.metadata()?.len() as usize;
+                    if file_size < offset + page_size {
+                        bail!("Page {page_number} is past the end of the file");
+                    }
+                    *map.borrow_mut() = Rc::new(Mmap::map(file)?);
+                }
+                Ok(PageBytes::Mapped {
+                    map: map.borrow().clone(),
+                    offset,
+                    len: page_size,
+                })
+            }
+        }
+    }
+
+    // Mapped storage has no cache, so it never records hits or misses
+    pub fn cache_stats(&self) -> CacheStats {
+        match self {
+            Storage::File { cache, .. } => cache.borrow().stats(),
+            Storage::Mapped { .. } => CacheStats::default(),
+        }
+    }
+}
+
+// A read-only memory mapping of a whole file. If another process truncates
+// the file while it is mapped, reading the missing pages raises SIGBUS, as
+// with any file mapping.
+#[derive(Debug)]
+pub struct Mmap {
+    ptr: *const u8,
+    len: usize,
+}
+
+#[cfg(all(unix, target_pointer_width = "64"))]
+mod sys {
+    use std::os::raw::{c_int, c_void};
+
+    pub const PROT_READ: c_int = 1;
+    pub const MAP_SHARED: c_int = 1;
+    pub const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+
+    extern "C" {
+        pub fn mmap(
+            addr: *mut c_void,
+            len: usize,
+            prot: c_int,
+            flags: c_int,
+            fd: c_int,
+            offset: i64,
+        ) -> *mut c_void;
+        pub fn munmap(addr: *mut c_void, len: usize) -> c_int;
+    }
+}
+
+impl Mmap {
+    #[cfg(all(unix, target_pointer_width = "64"))]
+    pub fn map(file: &File) -> Result<Self, anyhow::Error> {
+        use std::os::fd::AsRawFd;
+
+        let len = file.metadata()?.len() as usize;
+        if len == 0 {
+            bail!("Cannot map an empty file");
+        }
+        // SAFETY: we map a file we hold open, read-only, and only unmap it in drop
+        let ptr = unsafe {
+            sys::mmap(
+                std::ptr::null_mut(),
+                len,
+                sys::PROT_READ,
+                sys::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == sys::MAP_FAILED {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(Self {
+            ptr: ptr as *const u8,
+            len,
+        })
+    }
+
+    #[cfg(not(all(unix, target_pointer_width = "64")))]
+    pub fn map(_file: &File) -> Result<Self, anyhow::Error> {
+        bail!("Memory-mapped databases are not supported on this platform")
+    }
+}
+
+impl Deref for Mmap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: the mapping is valid for len bytes until it is dropped
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        #[cfg(all(unix, target_pointer_width = "64"))]
+        // SAFETY: ptr and len describe a mapping created by Mmap::map
+        unsafe {
+            sys::munmap(self.ptr as *mut _, self.len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn temp_file(name: &str, contents: &[u8]) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("{name}-{}", std::process::id()));
+        File::create(&path).unwrap().write_all(contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_backends_read_the_same_pages() {
+        let contents = (0..64u8).collect::<Vec<_>>();
+        let path = temp_file("storage-backends", &contents);
+        for backend in [Backend::File, Backend::Mmap] {
+            let storage = Storage::open(File::open(&path).unwrap(), backend, 4).unwrap();
+            assert_eq!(&storage.read_page(1, 16).unwrap()[..], &contents[..16]);
+            assert_eq!(&storage.read_page(4, 16).unwrap()[..], &contents[48..]);
+            assert!(storage.read_page(5, 16).is_err());
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_mapping_grows_with_file() {
+        let path = temp_file("storage-grow", &[1; 16]);
+        let storage = Storage::open(File::open(&path).unwrap(), Backend::Mmap, 0).unwrap();
+        let first = storage.read_page(1, 16).unwrap();
+        assert!(storage.read_page(2, 16).is_err());
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&[2; 16])
+            .unwrap();
+        assert_eq!(&storage.read_page(2, 16).unwrap()[..], &[2; 16]);
+        // Pages from the old mapping are still readable
+        assert_eq!(&first[..], &[1; 16]);
+        std::fs::remove_file(path).unwrap();
+    }
+}
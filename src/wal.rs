@@ -0,0 +1,226 @@
+use crate::cache::PageCache;
+use crate::storage::PageBytes;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::rc::Rc;
+
+const WAL_HEADER_SIZE: u64 = 32;
+const WAL_FRAME_HEADER_SIZE: u64 = 24;
+// The low bit of the magic number gives the byte order of the checksums
+const WAL_MAGIC_LITTLE_ENDIAN: u32 = 0x377f0682;
+const WAL_MAGIC_BIG_ENDIAN: u32 = 0x377f0683;
+const WAL_FORMAT_VERSION: u32 = 3007000;
+
+// The checksum used by the WAL header and frames. It runs over the data as
+// pairs of 32-bit words, continuing from the previous checksum.
+fn checksum(data: &[u8], big_endian: bool, (mut s0, mut s1): (u32, u32)) -> (u32, u32) {
+    let word = |b: &[u8]| {
+        let b = [b[0], b[1], b[2], b[3]];
+        if big_endian {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        }
+    };
+    for pair in data.chunks_exact(8) {
+        s0 = s0.wrapping_add(word(&pair[..4])).wrapping_add(s1);
+        s1 = s1.wrapping_add(word(&pair[4..])).wrapping_add(s0);
+    }
+    (s0, s1)
+}
+
+fn be_u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
+// Read exactly buf.len() bytes. Returns false if the file ends first.
+fn read_full(file: &mut File, buf: &mut [u8]) -> Result<bool, anyhow::Error> {
+    match file.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// The write-ahead log of a database in WAL mode. Committed changes are
+// appended to the log as frames, each holding a new version of one page,
+// until they are checkpointed back into the database file.
+pub struct Wal {
+    file: RefCell<File>,
+    page_size: usize,
+    // For each page in the log, the offset of its data in the latest frame
+    // that holds it and belongs to a committed transaction
+    frames: HashMap<u64, u64>,
+    cache: RefCell<PageCache>,
+}
+
+impl Wal {
+    // Open the log of the database at the given path. Returns None if there
+    // is no log, or if it has no valid header, in which case it is empty as
+    // far as readers are concerned.
+    pub fn open(
+        db_path: &str,
+        page_size: usize,
+        cache_pages: usize,
+    ) -> Result<Option<Self>, anyhow::Error> {
+        let mut file = match File::open(format!("{db_path}-wal")) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut header = [0; WAL_HEADER_SIZE as usize];
+        if !read_full(&mut file, &mut header)? {
+            return Ok(None);
+        }
+        let big_endian = match be_u32_at(&header, 0) {
+            WAL_MAGIC_LITTLE_ENDIAN => false,
+            WAL_MAGIC_BIG_ENDIAN => true,
+            _ => return Ok(None),
+        };
+        let mut sum = checksum(&header[..24], big_endian, (0, 0));
+        if be_u32_at(&header, 4) != WAL_FORMAT_VERSION
+            || be_u32_at(&header, 8) as usize != page_size
+            || sum != (be_u32_at(&header, 24), be_u32_at(&header, 28))
+        {
+            return Ok(None);
+        }
+        let salt = &header[16..24];
+
+        // Frames are valid as long as their salt matches the header and the
+        // running checksum matches. Only frames up to the last commit frame,
+        // which records the database size, belong to committed transactions.
+        let mut frames = HashMap::new();
+        let mut uncommitted = HashMap::new();
+        let mut frame_header = [0; WAL_FRAME_HEADER_SIZE as usize];
+        let mut page = vec![0; page_size];
+        let mut offset = WAL_HEADER_SIZE;
+        while read_full(&mut file, &mut frame_header)? && read_full(&mut file, &mut page)? {
+            if &frame_header[8..16] != salt {
+                break;
+            }
+            sum = checksum(&frame_header[..8], big_endian, sum);
+            sum = checksum(&page, big_endian, sum);
+            if sum != (be_u32_at(&frame_header, 16), be_u32_at(&frame_header, 20)) {
+                break;
+            }
+            let page_number = be_u32_at(&frame_header, 0) as u64;
+            uncommitted.insert(page_number, offset + WAL_FRAME_HEADER_SIZE);
+            if be_u32_at(&frame_header, 4) != 0 {
+                frames.extend(uncommitted.drain());
+            }
+            offset += WAL_FRAME_HEADER_SIZE + page_size as u64;
+        }
+        Ok(Some(Self {
+            file: RefCell::new(file),
+            page_size,
+            frames,
+            cache: RefCell::new(PageCache::new(cache_pages)),
+        }))
+    }
+
+    // Read the latest committed version of a page from the log. Returns None
+    // if the page isn't in the log, so the database file has its latest version.
+    pub fn read_page(&self, page_number: u64) -> Result<Option<PageBytes>, anyhow::Error> {
+        let Some(offset) = self.frames.get(&page_number) else {
+            return Ok(None);
+        };
+        if let Some(page) = self.cache.borrow_mut().get(page_number) {
+            return Ok(Some(PageBytes::Cached(page)));
+        }
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start(*offset))?;
+        let mut buf = vec![0; self.page_size];
+        file.read_exact(&mut buf)?;
+        let page: Rc<[u8]> = Rc::from(buf);
+        self.cache.borrow_mut().insert(page_number, page.clone());
+        Ok(Some(PageBytes::Cached(page)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const PAGE_SIZE: usize = 512;
+
+    // Build a log with big-endian checksums. Each frame is a page number, the
+    // database size if it is a commit frame, and a byte to fill the page with.
+    fn build_wal(frames: &[(u32, u32, u8)]) -> Vec<u8> {
+        let mut wal = Vec::new();
+        wal.extend_from_slice(&WAL_MAGIC_BIG_ENDIAN.to_be_bytes());
+        wal.extend_from_slice(&WAL_FORMAT_VERSION.to_be_bytes());
+        wal.extend_from_slice(&(PAGE_SIZE as u32).to_be_bytes());
+        wal.extend_from_slice(&0u32.to_be_bytes());
+        wal.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        let mut sum = checksum(&wal, true, (0, 0));
+        wal.extend_from_slice(&sum.0.to_be_bytes());
+        wal.extend_from_slice(&sum.1.to_be_bytes());
+        for (page_number, commit, fill) in frames {
+            let mut frame = Vec::new();
+            frame.extend_from_slice(&page_number.to_be_bytes());
+            frame.extend_from_slice(&commit.to_be_bytes());
+            let page = vec![*fill; PAGE_SIZE];
+            sum = checksum(&frame, true, sum);
+            sum = checksum(&page, true, sum);
+            frame.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+            frame.extend_from_slice(&sum.0.to_be_bytes());
+            frame.extend_from_slice(&sum.1.to_be_bytes());
+            frame.extend(page);
+            wal.extend(frame);
+        }
+        wal
+    }
+
+    fn open_wal(name: &str, contents: &[u8]) -> Option<Wal> {
+        let db_path = std::env::temp_dir().join(format!("{name}-{}.db", std::process::id()));
+        let db_path = db_path.to_str().unwrap();
+        let wal_path = format!("{db_path}-wal");
+        File::create(&wal_path)
+            .unwrap()
+            .write_all(contents)
+            .unwrap();
+        let wal = Wal::open(db_path, PAGE_SIZE, 16).unwrap();
+        std::fs::remove_file(wal_path).unwrap();
+        wal
+    }
+
+    fn fill_of(wal: &Wal, page_number: u64) -> Option<u8> {
+        wal.read_page(page_number).unwrap().map(|page| page[0])
+    }
+
+    #[test]
+    fn test_latest_committed_frames() {
+        // Page 2 is written twice, and the last transaction never committed
+        let wal = build_wal(&[(1, 0, 1), (2, 2, 2), (2, 2, 3), (1, 0, 4)]);
+        let wal = open_wal("wal-committed", &wal).unwrap();
+        assert_eq!(fill_of(&wal, 1), Some(1));
+        assert_eq!(fill_of(&wal, 2), Some(3));
+        assert_eq!(fill_of(&wal, 3), None);
+    }
+
+    #[test]
+    fn test_stops_at_bad_checksum() {
+        let mut wal = build_wal(&[(1, 1, 1), (1, 1, 2)]);
+        // Corrupt the page data of the second frame
+        let last = wal.len() - 1;
+        wal[last] ^= 0xff;
+        let wal = open_wal("wal-checksum", &wal).unwrap();
+        assert_eq!(fill_of(&wal, 1), Some(1));
+    }
+
+    #[test]
+    fn test_invalid_header() {
+        let mut wal = build_wal(&[(1, 1, 1)]);
+        wal[12] ^= 0xff;
+        assert!(open_wal("wal-header", &wal).is_none());
+    }
+}
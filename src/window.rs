@@ -0,0 +1,528 @@
+use crate::aggregate::{self, Accumulator, Key};
+use crate::ast::{
+    Expr, Frame, FrameBound, FrameUnits, FunctionArgs, OrderingTerm, ResultColumn, Select, Window,
+};
+use crate::executor::{compare_keys, eval, own_order_by, Context, Env, Scope, SourceRow};
+use crate::functions::{self, integer_of, real_of};
+use crate::parser::{Affinity, Data};
+
+use anyhow::bail;
+use std::cmp::Ordering;
+use std::ops::{Range, RangeInclusive};
+
+// The numbers of arguments a function that can only be called with OVER
+// accepts, or None if there is no such function
+fn arity(name: &str) -> Option<RangeInclusive<usize>> {
+    Some(match name {
+        "cume_dist" | "dense_rank" | "percent_rank" | "rank" | "row_number" => 0..=0,
+        "first_value" | "last_value" | "ntile" => 1..=1,
+        "nth_value" => 2..=2,
+        "lag" | "lead" => 1..=3,
+        _ => return None,
+    })
+}
+
+// Returns true if a function can only be called with OVER
+pub fn is_window_function(name: &str) -> bool {
+    arity(&name.to_ascii_lowercase()).is_some()
+}
+
+// Checks that a function called with OVER is a window function or an
+// aggregate, and accepts its arguments
+pub fn check(name: &str, args: &FunctionArgs) -> Result<(), anyhow::Error> {
+    let argc = match args {
+        FunctionArgs::Star => 0,
+        FunctionArgs::List(args) => args.len(),
+    };
+    match arity(&name.to_ascii_lowercase()) {
+        Some(arity) if arity.contains(&argc) => Ok(()),
+        Some(_) => bail!("wrong number of arguments to function {name}()"),
+        None if aggregate::is_aggregate(name, args) => Ok(()),
+        None => {
+            functions::check(name, args)?;
+            bail!("{name}() may not be used as a window function")
+        }
+    }
+}
+
+// Collects the distinct window function calls in an expression, without
+// looking inside them
+pub fn collect_windows<'a>(expr: &'a Expr, res: &mut Vec<&'a Expr>) {
+    match expr {
+        Expr::Window { .. } => {
+            if !res.contains(&expr) {
+                res.push(expr);
+            }
+        }
+        expr => {
+            for child in expr.children() {
+                collect_windows(child, res);
+            }
+        }
+    }
+}
+
+// Collects the window function calls in the result columns and ORDER BY of
+// a SELECT, which are the only places they can be used
+pub fn window_calls(select: &Select) -> Vec<&Expr> {
+    let mut calls = Vec::new();
+    for column in &select.columns {
+        if let ResultColumn::Expr { expr, .. } = column {
+            collect_windows(expr, &mut calls);
+        }
+    }
+    for term in own_order_by(select) {
+        collect_windows(&term.expr, &mut calls);
+    }
+    calls
+}
+
+// The clauses of a window, each taken from the window or from the named
+// window it's based on
+pub struct Clauses<'a> {
+    partition_by: &'a [Expr],
+    order_by: &'a [OrderingTerm],
+    frame: Option<&'a Frame>,
+}
+
+// Finds the clauses of a window, given the windows of the WINDOW clause
+// before it. A window can add an ORDER BY or a frame to the window it's
+// based on, but can't replace them.
+fn base_clauses<'a>(
+    window: &'a Window,
+    windows: &'a [(String, Window)],
+) -> Result<Clauses<'a>, anyhow::Error> {
+    let Some(name) = &window.base else {
+        return Ok(Clauses {
+            partition_by: &window.partition_by,
+            order_by: &window.order_by,
+            frame: window.frame.as_ref(),
+        });
+    };
+    // A later window of the same name replaces an earlier one
+    let Some(i) = windows
+        .iter()
+        .rposition(|(n, _)| n.eq_ignore_ascii_case(name))
+    else {
+        bail!("no such window: {name}");
+    };
+    let base = base_clauses(&windows[i].1, &windows[..i])?;
+    if !window.partition_by.is_empty() {
+        bail!("cannot override PARTITION clause of window: {name}");
+    }
+    if !window.order_by.is_empty() && !base.order_by.is_empty() {
+        bail!("cannot override ORDER BY clause of window: {name}");
+    }
+    if window.frame.is_some() && base.frame.is_some() {
+        bail!("cannot override frame specification of window: {name}");
+    }
+    Ok(Clauses {
+        partition_by: base.partition_by,
+        order_by: if window.order_by.is_empty() {
+            base.order_by
+        } else {
+            &window.order_by
+        },
+        frame: window.frame.as_ref().or(base.frame),
+    })
+}
+
+// Finds the clauses of the window of a window function call in a SELECT
+pub fn clauses<'a>(window: &'a Window, select: &'a Select) -> Result<Clauses<'a>, anyhow::Error> {
+    let clauses = base_clauses(window, &select.windows)?;
+    // An offset in a RANGE frame is measured with the ORDER BY value
+    if let Some(frame) = clauses.frame {
+        let has_offset = [&frame.start, &frame.end]
+            .iter()
+            .any(|bound| matches!(bound, FrameBound::Preceding(_) | FrameBound::Following(_)));
+        if frame.units == FrameUnits::Range && has_offset && clauses.order_by.len() != 1 {
+            bail!("RANGE with offset PRECEDING/FOLLOWING requires one ORDER BY expression");
+        }
+    }
+    Ok(clauses)
+}
+
+// One end of a frame, with its offset evaluated. Offsets before the current
+// row are negative.
+#[derive(Clone, Copy)]
+enum Bound {
+    Unbounded,
+    CurrentRow,
+    Offset(f64),
+}
+
+// Evaluates one end of a frame. The offsets of ROWS and GROUPS frames count
+// rows or groups, so they must be integers.
+fn bound(
+    bound: &FrameBound,
+    units: FrameUnits,
+    end: &str,
+    context: &Context,
+) -> Result<Bound, anyhow::Error> {
+    let (offset, sign) = match bound {
+        FrameBound::UnboundedPreceding | FrameBound::UnboundedFollowing => {
+            return Ok(Bound::Unbounded)
+        }
+        FrameBound::CurrentRow => return Ok(Bound::CurrentRow),
+        FrameBound::Preceding(offset) => (offset, -1.0),
+        FrameBound::Following(offset) => (offset, 1.0),
+    };
+    let scope = Scope::default();
+    let value = eval(offset, &Env::new(&scope, &[]).with_context(context))?;
+    let offset = match (units, value) {
+        (FrameUnits::Range, value) => match value.apply_affinity(Affinity::Numeric) {
+            Data::Integer(n) if n >= 0 => n as f64,
+            Data::Float(x) if x >= 0.0 => x,
+            _ => bail!("frame {end} offset must be a non-negative number"),
+        },
+        (_, value) => match value.apply_affinity(Affinity::Integer) {
+            Data::Integer(n) if n >= 0 => n as f64,
+            _ => bail!("frame {end} offset must be a non-negative integer"),
+        },
+    };
+    Ok(Bound::Offset(sign * offset))
+}
+
+// The rows of one partition of a window, in the window's order
+struct Partition<'p> {
+    // The ORDER BY values and the arguments of each row
+    order: &'p [Vec<Data>],
+    args: &'p [Vec<Data>],
+    // The group of rows with equal ORDER BY values, or peers, that each row
+    // belongs to, and the first row of each group, followed by the number of
+    // rows
+    peers: Vec<usize>,
+    starts: Vec<usize>,
+}
+
+impl<'p> Partition<'p> {
+    fn new(order_by: &[OrderingTerm], order: &'p [Vec<Data>], args: &'p [Vec<Data>]) -> Self {
+        let mut peers = Vec::new();
+        let mut starts = Vec::new();
+        for (i, values) in order.iter().enumerate() {
+            let is_peer = i > 0 && compare_keys(order_by, &order[i - 1], values) == Ordering::Equal;
+            if !is_peer {
+                starts.push(i);
+            }
+            peers.push(starts.len() - 1);
+        }
+        starts.push(order.len());
+        Self {
+            order,
+            args,
+            peers,
+            starts,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    // The position a frame starts at, or ends before, for the `i`th row
+    fn position(
+        &self,
+        units: FrameUnits,
+        bound: Bound,
+        i: usize,
+        end: bool,
+        descending: bool,
+    ) -> usize {
+        // A frame ends after its last row
+        let (peer, after) = (self.peers[i], end as usize);
+        let clamp = |position: f64, max: usize| position.clamp(0.0, max as f64) as usize;
+        match (units, bound) {
+            (_, Bound::Unbounded) if end => self.len(),
+            (_, Bound::Unbounded) => 0,
+            (FrameUnits::Rows, Bound::CurrentRow) => i + after,
+            (FrameUnits::Rows, Bound::Offset(k)) => clamp(i as f64 + k + after as f64, self.len()),
+            (FrameUnits::Range | FrameUnits::Groups, Bound::CurrentRow) => {
+                self.starts[peer + after]
+            }
+            (FrameUnits::Groups, Bound::Offset(k)) => {
+                let groups = self.starts.len() - 1;
+                self.starts[clamp(peer as f64 + k + after as f64, groups)]
+            }
+            (FrameUnits::Range, Bound::Offset(k)) => {
+                // Rows with NULL values are only in range of each other
+                if self.order[i][0] == Data::Null {
+                    return self.starts[peer + after];
+                }
+                let value = real_of(&self.order[i][0]);
+                let first = self.order.iter().position(|v| v[0] != Data::Null);
+                let last = self.order.iter().rposition(|v| v[0] != Data::Null);
+                let (first, last) = (first.unwrap_or(0), last.map_or(0, |last| last + 1));
+                let distance = |v: &Vec<Data>| {
+                    if descending {
+                        value - real_of(&v[0])
+                    } else {
+                        real_of(&v[0]) - value
+                    }
+                };
+                let before = |v: &Vec<Data>| {
+                    if end {
+                        distance(v) <= k
+                    } else {
+                        distance(v) < k
+                    }
+                };
+                first + self.order[first..last].partition_point(before)
+            }
+        }
+    }
+}
+
+// Returns the result of a window function for the `i`th row of a partition,
+// given the rows of its frame
+fn compute(
+    name: &str,
+    partition: &Partition,
+    i: usize,
+    frame: Range<usize>,
+) -> Result<Data, anyhow::Error> {
+    let n = partition.len();
+    let peer = partition.peers[i];
+    let args = &partition.args[i];
+    let value_at = |row: usize| partition.args[row][0].clone();
+    Ok(match name {
+        "row_number" => Data::Integer(i as i64 + 1),
+        "rank" => Data::Integer(partition.starts[peer] as i64 + 1),
+        "dense_rank" => Data::Integer(peer as i64 + 1),
+        "percent_rank" if n > 1 => Data::Float(partition.starts[peer] as f64 / (n - 1) as f64),
+        "percent_rank" => Data::Float(0.0),
+        "cume_dist" => Data::Float(partition.starts[peer + 1] as f64 / n as f64),
+        // The rows are split into buckets of nearly equal size, with the
+        // larger buckets first
+        "ntile" => {
+            let buckets = match args[0] {
+                Data::Null => 0,
+                ref value => integer_of(value),
+            };
+            if buckets <= 0 {
+                bail!("argument of ntile must be a positive integer");
+            }
+            let buckets = buckets as usize;
+            let size = n / buckets;
+            if size == 0 {
+                return Ok(Data::Integer(i as i64 + 1));
+            }
+            let large = n - buckets * size;
+            let in_large = large * (size + 1);
+            let bucket = if i < in_large {
+                i / (size + 1)
+            } else {
+                (i - in_large) / size + large
+            };
+            Data::Integer(bucket as i64 + 1)
+        }
+        // The offset counts rows back for lag and forward for lead. Without
+        // a row there, the result is the default.
+        "lag" | "lead" => {
+            let default = args.get(2).cloned().unwrap_or(Data::Null);
+            let offset = match args.get(1) {
+                None => 1,
+                Some(Data::Null) => return Ok(default),
+                Some(Data::Float(x)) if x.fract() != 0.0 => return Ok(default),
+                Some(value) => integer_of(value),
+            };
+            let offset = if name == "lag" { -offset } else { offset };
+            match (i as i64).checked_add(offset) {
+                Some(row) if (0..n as i64).contains(&row) => value_at(row as usize),
+                _ => default,
+            }
+        }
+        "first_value" if frame.is_empty() => Data::Null,
+        "first_value" => value_at(frame.start),
+        "last_value" if frame.is_empty() => Data::Null,
+        "last_value" => value_at(frame.end - 1),
+        "nth_value" => match args[1].clone().apply_affinity(Affinity::Integer) {
+            Data::Integer(nth) if nth > 0 => match frame.start.checked_add(nth as usize - 1) {
+                Some(row) if row < frame.end => value_at(row),
+                _ => Data::Null,
+            },
+            _ => bail!("second argument to nth_value must be a positive integer"),
+        },
+        _ => unreachable!("not a window function: {name}"),
+    })
+}
+
+// Computes the window function calls of a SELECT over its rows, which have
+// been filtered and grouped, adding the values of the calls to the values
+// each row's expressions are evaluated with. Each window sorts the rows by
+// its PARTITION BY and ORDER BY values. The first window is computed last,
+// so the rows end up in its order.
+pub fn apply<'a>(
+    select: &'a Select,
+    scope: &Scope,
+    context: &Context,
+    rows: &mut Vec<SourceRow<'a>>,
+) -> Result<(), anyhow::Error> {
+    for call in window_calls(select).into_iter().rev() {
+        let Expr::Window {
+            name,
+            args: call_args,
+            window,
+        } = call
+        else {
+            unreachable!("not a window function call");
+        };
+        let clauses = clauses(window, select)?;
+        let args = match call_args {
+            FunctionArgs::Star => &[][..],
+            FunctionArgs::List(args) => args,
+        };
+        let mut sorted = Vec::new();
+        for row in rows.drain(..) {
+            let env = Env {
+                scope,
+                rows: &row.0,
+                aggregates: &row.1,
+                context: Some(context),
+            };
+            let eval_all = |exprs: &mut dyn Iterator<Item = &Expr>| {
+                exprs
+                    .map(|expr| eval(expr, &env))
+                    .collect::<Result<Vec<_>, _>>()
+            };
+            let partition = Key(eval_all(&mut clauses.partition_by.iter())?);
+            let order = eval_all(&mut clauses.order_by.iter().map(|term| &term.expr))?;
+            let args = eval_all(&mut args.iter())?;
+            sorted.push((partition, order, args, row));
+        }
+        sorted.sort_by(|a, b| {
+            a.0.cmp(&b.0)
+                .then_with(|| compare_keys(clauses.order_by, &a.1, &b.1))
+        });
+
+        // Without a frame, a window ends with the peers of the current row
+        let default_frame = Frame {
+            units: FrameUnits::Range,
+            start: FrameBound::UnboundedPreceding,
+            end: FrameBound::CurrentRow,
+        };
+        let frame = clauses.frame.unwrap_or(&default_frame);
+        let start = bound(&frame.start, frame.units, "starting", context)?;
+        let end = bound(&frame.end, frame.units, "ending", context)?;
+        let descending = clauses.order_by.first().is_some_and(|term| term.descending);
+        let lower = name.to_ascii_lowercase();
+        let is_aggregate = aggregate::is_aggregate(name, call_args);
+
+        let (order, args): (Vec<_>, Vec<_>) = sorted
+            .iter()
+            .map(|(_, order, args, _)| (order.clone(), args.clone()))
+            .unzip();
+        let mut values = Vec::new();
+        let mut first = 0;
+        while first < sorted.len() {
+            let last = first
+                + sorted[first..]
+                    .iter()
+                    .take_while(|row| row.0 == sorted[first].0)
+                    .count();
+            let partition =
+                Partition::new(clauses.order_by, &order[first..last], &args[first..last]);
+            // An aggregate is carried from one row to the next while its
+            // frame only grows at the end
+            let mut running: Option<(Accumulator, Range<usize>)> = None;
+            for i in 0..partition.len() {
+                let frame_start = partition.position(frame.units, start, i, false, descending);
+                let frame_end = partition.position(frame.units, end, i, true, descending);
+                let frame = frame_start..frame_end.max(frame_start);
+                if !is_aggregate {
+                    values.push(compute(&lower, &partition, i, frame)?);
+                    continue;
+                }
+                let reusable = matches!(&running,
+                    Some((_, done)) if done.start == frame.start && done.end <= frame.end);
+                if !reusable {
+                    running = Some((Accumulator::new(name, false), frame.start..frame.start));
+                }
+                let Some((accumulator, done)) = running.as_mut() else {
+                    unreachable!();
+                };
+                for row in done.end..frame.end {
+                    accumulator.step(&partition.args[row])?;
+                }
+                done.end = frame.end;
+                values.push(accumulator.finish());
+            }
+            first = last;
+        }
+        *rows = sorted
+            .into_iter()
+            .zip(values)
+            .map(|((_, _, _, mut row), value)| {
+                row.1.push((call, value));
+                row
+            })
+            .collect();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn integers(values: &[i64]) -> Vec<Vec<Data>> {
+        values.iter().map(|&n| vec![Data::Integer(n)]).collect()
+    }
+
+    #[test]
+    fn test_ranking() {
+        let order = integers(&[1, 2, 2, 3]);
+        let args = vec![Vec::new(); 4];
+        let order_by = [OrderingTerm {
+            expr: Expr::Literal(Data::Null),
+            descending: false,
+            nulls: None,
+        }];
+        let partition = Partition::new(&order_by, &order, &args);
+        let results = |name: &str| {
+            (0..4)
+                .map(|i| compute(name, &partition, i, 0..4).unwrap())
+                .collect::<Vec<_>>()
+        };
+        let integers =
+            |values: &[i64]| values.iter().map(|&n| Data::Integer(n)).collect::<Vec<_>>();
+        assert_eq!(results("row_number"), integers(&[1, 2, 3, 4]));
+        assert_eq!(results("rank"), integers(&[1, 2, 2, 4]));
+        assert_eq!(results("dense_rank"), integers(&[1, 2, 2, 3]));
+        assert_eq!(
+            results("cume_dist"),
+            [0.25, 0.75, 0.75, 1.0].map(Data::Float).to_vec()
+        );
+    }
+
+    #[test]
+    fn test_frames() {
+        let order = integers(&[1, 2, 2, 5]);
+        let args = vec![Vec::new(); 4];
+        let order_by = [OrderingTerm {
+            expr: Expr::Literal(Data::Null),
+            descending: false,
+            nulls: None,
+        }];
+        let partition = Partition::new(&order_by, &order, &args);
+        let frame = |units, start, end, i| {
+            partition.position(units, start, i, false, false)
+                ..partition.position(units, end, i, true, false)
+        };
+        let (preceding, following) = (Bound::Offset(-1.0), Bound::Offset(1.0));
+        assert_eq!(frame(FrameUnits::Rows, preceding, following, 0), 0..2);
+        assert_eq!(
+            frame(FrameUnits::Rows, preceding, Bound::CurrentRow, 3),
+            2..4
+        );
+        assert_eq!(
+            frame(FrameUnits::Groups, preceding, Bound::CurrentRow, 3),
+            1..4
+        );
+        assert_eq!(
+            frame(FrameUnits::Range, Bound::Unbounded, Bound::CurrentRow, 1),
+            0..3
+        );
+        assert_eq!(frame(FrameUnits::Range, preceding, following, 1), 0..3);
+        assert_eq!(frame(FrameUnits::Range, preceding, following, 3), 3..4);
+    }
+}